@@ -0,0 +1,18 @@
+#!/bin/sh
+#Builds `examples/disabled.rs` with the `enabled` feature turned off and makes sure that
+#none of the runtime state (core, thread info, shared memory) got linked into it.
+set -e
+
+cd "$(dirname "$0")/.."
+cargo build --release --no-default-features --example disabled
+
+BIN=target/release/examples/disabled
+SYMBOLS=$(nm -C "$BIN" | grep -E 'temporal_lens::(core|context|signal|downsample|THREAD_INFO|GENERIC_ZONE|DEFAULT_FRAMES|shmem::SharedMemory)|shared_memory::' || true)
+
+if [ -n "$SYMBOLS" ]; then
+    echo "Runtime state found in a disabled build:"
+    echo "$SYMBOLS"
+    exit 1
+fi
+
+echo "Disabled build is free of runtime state"
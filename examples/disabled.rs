@@ -0,0 +1,27 @@
+//Instrumented program used by `ci/check-disabled.sh`: built with `--no-default-features`,
+//none of the profiler's runtime state should make it into the binary.
+
+use temporal_lens::{profile_scope, frame_delimiter};
+
+fn work(i: u32) -> u32 {
+    profile_scope!("work");
+    temporal_lens::plot!("work_plot", f64::from(i));
+    temporal_lens::tl_log!("work {}", i);
+
+    i.wrapping_mul(31)
+}
+
+fn main() {
+    let mut acc = 0;
+
+    for i in 0..std::env::args().count() as u32 + 10 {
+        let zone = temporal_lens::start_zone_profiling!("main_loop", color: red);
+        acc ^= work(i);
+        zone.end();
+
+        temporal_lens::frame_plot!("acc", f64::from(acc));
+        frame_delimiter!();
+    }
+
+    println!("{}", acc);
+}
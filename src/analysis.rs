@@ -0,0 +1,605 @@
+///Server-side helpers turning the raw entries retrieved from the shared memory
+///into something more useful.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::shmem::{ZoneData, FrameData, HeapData, PlotData, PlotMetaData, LogEntryHeader, LogLevel, Color, SharedString, Time, Duration};
+
+///Entries that can be looked up by time: zones and frames by their end time (which is the order
+///in which clients send them), plots and heap events by their only time
+pub trait Timed {
+    fn time_key(&self) -> Time;
+}
+
+impl Timed for ZoneData {
+    #[inline]
+    fn time_key(&self) -> Time { self.end }
+}
+
+impl Timed for FrameData {
+    #[inline]
+    fn time_key(&self) -> Time { self.end }
+}
+
+impl Timed for PlotData {
+    #[inline]
+    fn time_key(&self) -> Time { self.time }
+}
+
+impl Timed for HeapData {
+    #[inline]
+    fn time_key(&self) -> Time { self.time }
+}
+
+///Sorts entries for `in_range()`. What one client thread sends is already in order, but
+///entries of several threads (or several retrieves) interleave, so call this after merging.
+pub fn sort_by_time<T: Timed>(entries: &mut [T]) {
+    entries.sort_by_key(|x| x.time_key());
+}
+
+//Index of the first entry of `sorted` whose key is at least `t`
+fn lower_bound<T: Timed>(sorted: &[T], t: Time) -> usize {
+    sorted.binary_search_by(|x| if x.time_key() < t { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater }).unwrap_err()
+}
+
+///Entries of `sorted` whose time (see `Timed`) is in `[start, end]`, found by binary search.
+///`sorted` must be ordered by that time (see `sort_by_time()`), otherwise the result is
+///meaningless. Note that zones and frames are found by end time: one that started before
+///`start` but ends in the range is included, one that ends after `end` isn't.
+pub fn in_range<T: Timed>(sorted: &[T], start: Time, end: Time) -> &[T] {
+    if end < start {
+        return &sorted[0..0];
+    }
+
+    let first = lower_bound(sorted, start);
+    let last = first + lower_bound(&sorted[first..], end.saturating_add(1));
+
+    &sorted[first..last]
+}
+
+///Zones ending between `start` and `end`, see `in_range()`
+pub fn zones_in_range(sorted: &[ZoneData], start: Time, end: Time) -> &[ZoneData] {
+    in_range(sorted, start, end)
+}
+
+///Frames ending between `start` and `end`, see `in_range()`
+pub fn frames_in_range(sorted: &[FrameData], start: Time, end: Time) -> &[FrameData] {
+    in_range(sorted, start, end)
+}
+
+///Plot values between `start` and `end`, see `in_range()`
+pub fn plots_in_range(sorted: &[PlotData], start: Time, end: Time) -> &[PlotData] {
+    in_range(sorted, start, end)
+}
+
+pub struct CallNode {
+    pub uid: usize,               //Same as `ZoneData::uid`
+    pub index: usize,             //Index of the zone in the slice passed to `build_call_tree`
+    pub start: Time,              //Time when the zone started
+    pub end: Time,                //Time when the zone ended
+    pub duration: Duration,       //Same as `ZoneData::duration`
+    pub depth: u32,               //Call stack depth, as reported by the client
+    pub recursion: u32,           //How many nested zones with the same `uid` were merged into this one (see `collapse_recursion`)
+    pub children: Vec<CallNode>   //Zones nested inside this one, ordered by start time
+}
+
+pub struct ThreadTree {
+    pub thread: usize,            //Thread ID (`ZoneData::thread`)
+    pub roots: Vec<CallNode>      //Top-level zones of this thread, ordered by start time
+}
+
+impl CallNode {
+    fn contains(&self, start: Time, end: Time) -> bool {
+        start >= self.start && end <= self.end
+    }
+}
+
+fn attach(stack: &mut [CallNode], roots: &mut Vec<CallNode>, node: CallNode) {
+    match stack.last_mut() {
+        Some(parent) => parent.children.push(node),
+        None         => roots.push(node)
+    }
+}
+
+//Indices of the zones of each thread, parents sorted before their children: they start
+//before (or at the same time as) them, and have a lower depth
+fn zones_per_thread(zones: &[ZoneData]) -> BTreeMap<usize, Vec<usize>> {
+    let mut ret: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+
+    for (i, zone) in zones.iter().enumerate() {
+        ret.entry(zone.thread).or_default().push(i);
+    }
+
+    for indices in ret.values_mut() {
+        indices.sort_by_key(|&i| {
+            let z = &zones[i];
+            (z.start, z.depth, std::cmp::Reverse(z.end))
+        });
+    }
+
+    ret
+}
+
+///Rebuilds the call tree of each thread from the `depth` field of the zones.
+///
+///A zone is nested under the closest previous zone of the same thread that has
+///a lower depth and fully contains it. This means that if a parent zone was
+///dropped (e.g. because the buffer was full), its children are attached to the
+///next available ancestor (or become roots) instead of being lost. Recursion
+///is not special-cased: nested zones sharing the same `uid` are just nested,
+///use `collapse_recursion` to merge them.
+pub fn build_call_tree(zones: &[ZoneData]) -> Vec<ThreadTree> {
+    zones_per_thread(zones).into_iter().map(|(thread, indices)| {
+        let mut roots = Vec::new();
+        let mut stack: Vec<CallNode> = Vec::new();
+
+        for i in indices {
+            let zone = &zones[i];
+            let start = zone.start;
+
+            while let Some(top) = stack.last() {
+                if top.depth < zone.depth && top.contains(start, zone.end) {
+                    break;
+                }
+
+                let done = stack.pop().unwrap();
+                attach(&mut stack, &mut roots, done);
+            }
+
+            stack.push(CallNode {
+                uid: zone.uid,
+                index: i,
+                start,
+                end: zone.end,
+                duration: zone.duration,
+                depth: zone.depth,
+                recursion: 0,
+                children: Vec::new()
+            });
+        }
+
+        while let Some(done) = stack.pop() {
+            attach(&mut stack, &mut roots, done);
+        }
+
+        ThreadTree { thread, roots }
+    }).collect()
+}
+
+fn collapse_node(node: &mut CallNode) {
+    let children = std::mem::take(&mut node.children);
+
+    for mut child in children {
+        collapse_node(&mut child);
+
+        if child.uid == node.uid {
+            //The grandchildren are inside `child`, so they stay ordered by start time
+            node.recursion = node.recursion.max(child.recursion + 1);
+            node.children.append(&mut child.children);
+        } else {
+            node.children.push(child);
+        }
+    }
+}
+
+///Merges zones nested directly inside a zone with the same `uid` into it, so that recursive
+///functions show up as a single node instead of a tower of identical ones. Their children are
+///moved up to the outermost zone, and `recursion` records how many levels were merged. The
+///outermost zone's duration is kept, which already includes the nested calls; `depth` is left
+///as reported by the client, so it no longer matches the position in the tree.
+pub fn collapse_recursion(trees: &mut [ThreadTree]) {
+    for tree in trees {
+        for root in &mut tree.roots {
+            collapse_node(root);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnomalyKind {
+    Overlap,   //The zone started inside `parent` but ended after it
+    NotDeeper, //The zone is contained in `parent` but its depth isn't greater
+    DepthJump  //The zone is contained in `parent` but is more than one level deeper
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DepthAnomaly {
+    pub thread: usize,    //Thread ID (`ZoneData::thread`)
+    pub index: usize,     //Index of the offending zone in the slice passed to `validate_depths`
+    pub parent: usize,    //Index of the innermost zone that was still running when it started
+    pub kind: AnomalyKind
+}
+
+///Checks that the `depth` transitions of each thread are well-formed: zones must be fully
+///contained in the zones running when they started, and exactly one level deeper than the
+///innermost one. Anomalies usually mean that a `Zone` was leaked or that depths got out of
+///sync, but keep in mind that `DepthJump` is also what dropped entries (full buffer,
+///`profile_scope_min!`) look like. Zones with no running parent are never reported, since
+///their parent might just not have ended yet.
+pub fn validate_depths(zones: &[ZoneData]) -> Vec<DepthAnomaly> {
+    let mut ret = Vec::new();
+
+    for (thread, indices) in zones_per_thread(zones) {
+        let mut stack: Vec<usize> = Vec::new();
+
+        for i in indices {
+            let zone = &zones[i];
+            let start = zone.start;
+
+            while let Some(&top) = stack.last() {
+                if zones[top].end > start {
+                    break;
+                }
+
+                stack.pop();
+            }
+
+            if let Some(&parent) = stack.last() {
+                let p = &zones[parent];
+                let kind = if zone.end > p.end {
+                    Some(AnomalyKind::Overlap)
+                } else if zone.depth <= p.depth {
+                    Some(AnomalyKind::NotDeeper)
+                } else if zone.depth > p.depth + 1 {
+                    Some(AnomalyKind::DepthJump)
+                } else {
+                    None
+                };
+
+                if let Some(kind) = kind {
+                    ret.push(DepthAnomaly { thread, index: i, parent, kind });
+                }
+            }
+
+            stack.push(i);
+        }
+    }
+
+    ret
+}
+
+#[derive(Copy, Clone)]
+pub struct ZoneStats {
+    pub count: u64,          //How many times the zone was recorded
+    pub total: Duration,     //Sum of all the durations
+    pub self_time: Duration, //Sum of all the durations, minus the time spent in child zones
+    pub min: Duration,       //Shortest duration
+    pub max: Duration        //Longest duration
+}
+
+impl ZoneStats {
+    #[inline]
+    pub fn avg(&self) -> Duration {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+fn accumulate_stats(node: &CallNode, stats: &mut HashMap<usize, ZoneStats>) {
+    let children_time: Duration = node.children.iter().map(|c| c.duration).sum();
+    let entry = stats.entry(node.uid).or_insert(ZoneStats {
+        count: 0,
+        total: 0,
+        self_time: 0,
+        min: Duration::MAX,
+        max: 0
+    });
+
+    entry.count += 1;
+    entry.total += node.duration;
+    entry.self_time += node.duration.saturating_sub(children_time);
+    entry.min = entry.min.min(node.duration);
+    entry.max = entry.max.max(node.duration);
+
+    for child in &node.children {
+        accumulate_stats(child, stats);
+    }
+}
+
+///Computes statistics for each zone `uid`. Self-time relies on `build_call_tree`.
+///Note that for recursive zones, `total` counts nested calls several times
+///whereas `self_time` doesn't.
+pub fn aggregate_by_uid(zones: &[ZoneData]) -> HashMap<usize, ZoneStats> {
+    let mut ret = HashMap::new();
+
+    for tree in build_call_tree(zones) {
+        for root in &tree.roots {
+            accumulate_stats(root, &mut ret);
+        }
+    }
+
+    ret
+}
+
+pub enum SpikeThreshold {
+    Absolute(Duration), //Frames longer than this are spikes
+    MedianFactor(f64)   //Frames longer than `median * factor` are spikes
+}
+
+pub struct FrameStats {
+    pub count: usize,   //How many frames were considered
+    pub p50: Duration,  //Median frame time
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    pub spikes: Vec<u64> //Numbers of the frames above the threshold, in the same order as the input
+}
+
+//Nearest-rank percentile, `sorted` must not be empty
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * (sorted.len() as f64)).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+impl FrameStats {
+    ///Computes frame time statistics. Frames of different sets should not be mixed, so filter
+    ///`frames` by `FrameData::set` first. The first frame of a set (number 0) is ignored since its
+    ///start defaults to the program's start time. Returns `None` if there's no frame left.
+    pub fn new(frames: &[FrameData], threshold: SpikeThreshold) -> Option<FrameStats> {
+        let mut sorted: Vec<Duration> = frames.iter().filter(|f| f.number > 0).map(|f| f.duration).collect();
+
+        if sorted.is_empty() {
+            return None;
+        }
+
+        sorted.sort_unstable();
+
+        let p50 = percentile(&sorted, 50.0);
+        let threshold = match threshold {
+            SpikeThreshold::Absolute(x)     => x,
+            SpikeThreshold::MedianFactor(x) => ((p50 as f64) * x) as Duration
+        };
+
+        Some(FrameStats {
+            count: sorted.len(),
+            p50,
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            max: *sorted.last().unwrap(),
+            spikes: frames.iter().filter(|f| f.number > 0 && f.duration > threshold).map(|f| f.number).collect()
+        })
+    }
+}
+
+pub struct HeapSeries {
+    pub live_bytes: Vec<(Time, i64)>,      //Bytes allocated minus bytes freed, after each event
+    pub allocs_per_sec: Vec<(Time, f64)>,  //Allocation rate over each window, at the end of the window
+    pub allocated_bytes: Vec<(Time, u64)>, //Bytes allocated during each window, at the end of the window
+    pub freed_bytes: Vec<(Time, u64)>      //Bytes freed during each window, at the end of the window
+}
+
+///Derives a "live bytes" plot and an "allocations per second" plot from heap events, which
+///must be ordered by time. Live bytes are relative to the first event (what was allocated
+///before is unknown), so they can go negative if older memory gets freed. Allocated and freed
+///bytes are kept apart, which shows churn even while `live_bytes` stays flat. Consecutive
+///windows without any event are merged into a single zero point.
+pub fn heap_series(heap: &[HeapData], window: Duration) -> HeapSeries {
+    let window = window.max(1);
+    let window_secs = (window as f64) / 1e9;
+
+    let mut ret = HeapSeries {
+        live_bytes: Vec::with_capacity(heap.len()),
+        allocs_per_sec: Vec::new(),
+        allocated_bytes: Vec::new(),
+        freed_bytes: Vec::new()
+    };
+
+    let mut live: i64 = 0;
+    let mut window_end = match heap.first() {
+        Some(x) => x.time + window,
+        None    => return ret
+    };
+    let mut count = 0;
+    let mut allocated = 0;
+    let mut freed = 0;
+
+    for entry in heap {
+        if entry.is_free {
+            live -= entry.size as i64;
+        } else {
+            live += entry.size as i64;
+        }
+
+        ret.live_bytes.push((entry.time, live));
+
+        if entry.time >= window_end {
+            ret.allocs_per_sec.push((window_end, (count as f64) / window_secs));
+            ret.allocated_bytes.push((window_end, allocated));
+            ret.freed_bytes.push((window_end, freed));
+
+            //A single point at the end of a gap, however many empty windows it spans
+            let skipped = (entry.time - window_end) / window;
+            if skipped > 0 {
+                window_end += skipped * window;
+                ret.allocs_per_sec.push((window_end, 0.0));
+                ret.allocated_bytes.push((window_end, 0));
+                ret.freed_bytes.push((window_end, 0));
+            }
+
+            window_end += window;
+            count = 0;
+            allocated = 0;
+            freed = 0;
+        }
+
+        if entry.is_free {
+            freed += entry.size as u64;
+        } else {
+            count += 1;
+            allocated += entry.size as u64;
+        }
+    }
+
+    ret.allocs_per_sec.push((window_end, (count as f64) / window_secs));
+    ret.allocated_bytes.push((window_end, allocated));
+    ret.freed_bytes.push((window_end, freed));
+    ret
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PlotBucket {
+    pub start: Time,     //Start of the bucket: a multiple of the bucket duration
+    pub count: u64,      //Number of samples in the bucket (never 0; empty buckets are skipped)
+    pub min: f64,
+    pub min_time: Time,  //When `min` was reached, so that spikes are drawn where they happened
+    pub max: f64,
+    pub max_time: Time,
+    pub avg: f64,
+    pub last: f64,       //Value of the last sample, which is what the plot shows until the next one
+    pub last_time: Time
+}
+
+///Reduces the samples of one plot (filter them by name first) to a bucket per `bucket`
+///nanoseconds, so that long captures can be drawn at any zoom level. Buckets start at
+///multiples of `bucket`, so buckets of different series line up. Keeping the extremes and
+///their times means spikes survive, which averages alone would flatten. Samples must be
+///ordered by time (see `sort_by_time()`).
+pub fn downsample_plot(samples: &[PlotData], bucket: Time) -> Vec<PlotBucket> {
+    let bucket = bucket.max(1);
+    let mut ret: Vec<PlotBucket> = Vec::new();
+    let mut sum = 0.0;
+
+    for s in samples {
+        let start = s.time - s.time % bucket;
+
+        match ret.last_mut() {
+            Some(b) if b.start == start => {
+                b.count += 1;
+                sum += s.value;
+
+                if s.value < b.min {
+                    b.min = s.value;
+                    b.min_time = s.time;
+                }
+
+                if s.value > b.max {
+                    b.max = s.value;
+                    b.max_time = s.time;
+                }
+
+                b.avg = sum / (b.count as f64);
+                b.last = s.value;
+                b.last_time = s.time;
+            },
+
+            _ => {
+                sum = s.value;
+                ret.push(PlotBucket {
+                    start,
+                    count: 1,
+                    min: s.value,
+                    min_time: s.time,
+                    max: s.value,
+                    max_time: s.time,
+                    avg: s.value,
+                    last: s.value,
+                    last_time: s.time
+                });
+            }
+        }
+    }
+
+    ret
+}
+
+///Contents of `SharedString`s by key, learned from the entries that carry them. Keys should
+///mean one string for the whole session, but if new contents arrive for a known key (e.g. a
+///client reused the address of a freed string as a key), they replace the old ones, so that
+///the entries that follow get the right name.
+#[derive(Default)]
+pub struct NameCache {
+    names: HashMap<usize, String>
+}
+
+impl NameCache {
+    pub fn new() -> NameCache {
+        NameCache::default()
+    }
+
+    ///Learns the contents of `string`, if it has some. Returns true if its key was already
+    ///known with other contents, which means the client broke the key rules.
+    pub fn update(&mut self, string: &SharedString) -> bool {
+        let contents = match string.make_str() {
+            Some(x) => x,
+            None    => return false
+        };
+
+        match self.names.get_mut(&string.get_key()) {
+            Some(name) if name == contents => false,
+
+            Some(name) => {
+                *name = contents.to_string();
+                true
+            },
+
+            None => {
+                self.names.insert(string.get_key(), contents.to_string());
+                false
+            }
+        }
+    }
+
+    ///Contents of `string`: its own if it has some, or else the last ones seen for its key
+    pub fn name_of<'a>(&'a self, string: &'a SharedString) -> Option<&'a str> {
+        string.make_str().or_else(|| self.names.get(&string.get_key()).map(|x| x.as_str()))
+    }
+
+    ///Same as `update()` followed by `name_of()`
+    pub fn resolve<'a>(&'a mut self, string: &'a SharedString) -> Option<&'a str> {
+        self.update(string);
+        self.name_of(string)
+    }
+}
+
+///Keeps one color per plot series. Clients send a color with every sample, which may vary; the
+///first one seen for each series wins, until a `PlotMetaData` record (see
+///`temporal_lens::set_plot_color()`) sets another one. Series are identified by name, like
+///the server does, so keep one of these for the whole session and feed it every retrieve.
+#[derive(Default)]
+pub struct PlotColors {
+    names: NameCache,
+    colors: HashMap<String, Color> //Color of each series, by name
+}
+
+impl PlotColors {
+    pub fn new() -> PlotColors {
+        PlotColors::default()
+    }
+
+    ///Sets the color of each sample of `samples` to the color of its series. `metas`, retrieved
+    ///along with the samples, take effect from their time on; both must be ordered by time.
+    ///Samples whose name was never received are left as they are.
+    pub fn apply(&mut self, samples: &mut [PlotData], metas: &[PlotMetaData]) {
+        let mut next_meta = 0;
+
+        for s in samples.iter_mut() {
+            while next_meta < metas.len() && metas[next_meta].time <= s.time {
+                self.set_color(&metas[next_meta]);
+                next_meta += 1;
+            }
+
+            if let Some(name) = self.names.resolve(&s.name) {
+                s.color = *self.colors.entry(name.to_string()).or_insert(s.color);
+            }
+        }
+
+        for meta in &metas[next_meta..] {
+            self.set_color(meta);
+        }
+    }
+
+    fn set_color(&mut self, meta: &PlotMetaData) {
+        if let Some(name) = self.names.resolve(&meta.name) {
+            self.colors.insert(name.to_string(), meta.color);
+        }
+    }
+
+    ///Color of the series `name`, if any sample of it was seen
+    pub fn color_of(&self, name: &str) -> Option<Color> {
+        self.colors.get(name).copied()
+    }
+}
+
+///Log messages (as returned by `SharedMemoryData::retrieve_logs()`) of level `min` or more severe
+pub fn filter_logs(logs: &[(LogEntryHeader, String)], min: LogLevel) -> impl Iterator<Item = &(LogEntryHeader, String)> {
+    logs.iter().filter(move |(header, _)| header.level() >= min)
+}
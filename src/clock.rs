@@ -0,0 +1,151 @@
+///Time sources used to timestamp zones and frames. `InstantClock` is used by
+///default; enabling the `tsc` feature switches to `TscClock` on x86 targets.
+///
+///CAVEATS of `TscClock`: the time stamp counter is only reliable on CPUs with an
+///*invariant* TSC (constant rate regardless of frequency scaling and sleep states,
+///check for the `constant_tsc` and `nonstop_tsc` flags in `/proc/cpuinfo`). Even so,
+///counters of different cores (or sockets) might not be perfectly synchronized,
+///meaning that a thread migrating to another core in the middle of a zone could
+///report a slightly wrong duration (durations are saturated at 0 so they never get
+///negative). The tick rate is calibrated once against `Instant` during the first
+///10 milliseconds, so expect a small drift over very long sessions.
+
+use std::time::Instant;
+
+pub trait Clock {
+    type Timestamp: Copy + Send + Sync + 'static;
+
+    fn now() -> Self::Timestamp;
+
+    ///Nanoseconds elapsed between `earlier` and `later`, or 0 if `later` is actually earlier
+    fn nanos_between(earlier: Self::Timestamp, later: Self::Timestamp) -> u64;
+}
+
+pub struct InstantClock;
+
+impl Clock for InstantClock {
+    type Timestamp = Instant;
+
+    #[inline]
+    fn now() -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn nanos_between(earlier: Instant, later: Instant) -> u64 {
+        later.saturating_duration_since(earlier).as_nanos() as u64
+    }
+}
+
+#[cfg(all(feature = "tsc", any(target_arch = "x86", target_arch = "x86_64")))]
+mod tsc {
+    use std::sync::Once;
+    use std::time::{Instant, Duration};
+
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::_rdtsc;
+
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::_rdtsc;
+
+    static mut NANOS_PER_TICK: f64 = 0.0;
+    static CALIBRATION: Once = Once::new();
+
+    pub struct TscClock;
+
+    impl TscClock {
+        ///Measures the TSC rate against `Instant`. This is called automatically, but takes
+        ///10ms so you might want to call it yourself before the time critical stuff begins.
+        pub fn calibrate() {
+            CALIBRATION.call_once(|| {
+                let start_instant = Instant::now();
+                let start_ticks = unsafe { _rdtsc() };
+
+                while start_instant.elapsed() < Duration::from_millis(10) {
+                    std::thread::yield_now();
+                }
+
+                let ticks = unsafe { _rdtsc() }.saturating_sub(start_ticks);
+                let nanos = start_instant.elapsed().as_nanos() as f64;
+
+                unsafe {
+                    NANOS_PER_TICK = if ticks > 0 { nanos / (ticks as f64) } else { 1.0 };
+                }
+            });
+        }
+    }
+
+    impl super::Clock for TscClock {
+        type Timestamp = u64;
+
+        #[inline]
+        fn now() -> u64 {
+            unsafe { _rdtsc() }
+        }
+
+        #[inline]
+        fn nanos_between(earlier: u64, later: u64) -> u64 {
+            Self::calibrate();
+            (later.saturating_sub(earlier) as f64 * unsafe { NANOS_PER_TICK }) as u64
+        }
+    }
+}
+
+#[cfg(all(feature = "tsc", any(target_arch = "x86", target_arch = "x86_64")))]
+pub use tsc::TscClock;
+
+//The TSC is x86 only; other targets silently keep using `Instant`
+#[cfg(all(feature = "tsc", any(target_arch = "x86", target_arch = "x86_64")))]
+pub type SelectedClock = TscClock;
+
+#[cfg(not(all(feature = "tsc", any(target_arch = "x86", target_arch = "x86_64"))))]
+pub type SelectedClock = InstantClock;
+
+pub type Timestamp = <SelectedClock as Clock>::Timestamp;
+
+#[inline]
+pub fn now() -> Timestamp {
+    SelectedClock::now()
+}
+
+#[inline]
+pub fn nanos_between(earlier: Timestamp, later: Timestamp) -> u64 {
+    SelectedClock::nanos_between(earlier, later)
+}
+
+///CPU time consumed by the current thread so far, in nanoseconds. Unlike the clocks above,
+///this is a syscall, hence the `cpu-time` feature. Returns 0 if it can't be measured.
+#[cfg(feature = "cpu-time")]
+pub fn thread_cpu_time() -> u64 {
+    #[cfg(unix)]
+    unsafe {
+        let mut ts: libc::timespec = std::mem::zeroed();
+
+        if libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) == 0 {
+            (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64)
+        } else {
+            0
+        }
+    }
+
+    #[cfg(windows)]
+    unsafe {
+        use winapi::shared::minwindef::FILETIME;
+        use winapi::um::processthreadsapi::{GetCurrentThread, GetThreadTimes};
+
+        let mut times: [FILETIME; 4] = std::mem::zeroed();
+        let [creation, exit, kernel, user] = &mut times;
+
+        if GetThreadTimes(GetCurrentThread(), creation, exit, kernel, user) != 0 {
+            let ticks = |t: &FILETIME| ((t.dwHighDateTime as u64) << 32) | (t.dwLowDateTime as u64);
+            (ticks(kernel) + ticks(user)) * 100 //100 nanoseconds ticks
+        } else {
+            0
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        0
+    }
+}
@@ -0,0 +1,187 @@
+///Profiling contexts that don't go through the global shared memory, for instance to profile
+///a plugin separately from its host, or to inject a loopback segment in tests. The global
+///functions and macros remain the default; the `_to!` macros target a `Context` instead.
+///
+///Zones sent to a context are pushed as soon as they end instead of being batched, and
+///aggregate zones (see `ZoneInfo::aggregated()`) are sent as regular zones.
+
+use crate::{shmem, clock, Color, LogLevel, FrameInfo, PendingZone, ZoneText};
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+pub struct Context {
+    mem: shmem::SharedMemory,
+    start_time: clock::Timestamp,
+    generation: AtomicU32,   //Same as `core::GENERATION`, but for this context
+    origin: AtomicU64,       //Same as `core::TIMELINE_ORIGIN`, but for this context
+    clear_lock: Mutex<()>,
+    names_sent: Mutex<HashSet<usize>> //`ZoneInfo`s whose name this context's segment has; the global `NameSent` only tells about the global one
+}
+
+//A zone with the name decision of a context, see `Context::push_zone()`
+struct ContextZone<'a> {
+    zone: &'a PendingZone,
+    copy_name: bool
+}
+
+impl shmem::WriteInto<shmem::ZoneData> for ContextZone<'_> {
+    fn write_into(&self, target: &mut shmem::ZoneData) {
+        self.zone.write_named(target, self.copy_name);
+    }
+}
+
+//Payloads have their own locks; the rest is atomics
+unsafe impl Sync for Context {}
+
+impl Context {
+    ///Opens the shared memory created by the server, like the global context does
+    pub fn open() -> Option<Context> {
+        shmem::SharedMemory::open().ok().map(Context::with_shared_memory)
+    }
+
+    ///Uses a shared memory opened (or created) by the caller
+    pub fn with_shared_memory(mem: shmem::SharedMemory) -> Context {
+        let generation = mem.clear_generation.load(Ordering::Relaxed);
+
+        Context {
+            mem,
+            start_time: clock::now(),
+            generation: AtomicU32::new(generation),
+            origin: AtomicU64::new(0),
+            clear_lock: Mutex::new(()),
+            names_sent: Mutex::new(HashSet::new())
+        }
+    }
+
+    //A raw pointer rather than a `&mut`, which would alias those of the other threads: callers
+    //only dereference it for the call that needs it. That's sound as far as the shared memory
+    //goes, since payloads and the log ring take their own locks and the rest is atomics.
+    pub(crate) fn data(&self) -> *mut shmem::SharedMemoryData {
+        let mem = self.mem.as_ptr();
+
+        if unsafe { (*mem).clear_generation.load(Ordering::Relaxed) } != self.generation.load(Ordering::Relaxed) {
+            self.handle_clear(unsafe { &mut *mem });
+        }
+
+        mem
+    }
+
+    #[cold]
+    fn handle_clear(&self, mem: &mut shmem::SharedMemoryData) {
+        let _guard = self.clear_lock.lock().unwrap();
+        let requested = mem.clear_generation.load(Ordering::Relaxed);
+
+        if requested != self.generation.load(Ordering::Relaxed) {
+            self.origin.store(clock::nanos_between(self.start_time, clock::now()), Ordering::Relaxed);
+            mem.clear_all();
+
+            self.generation.store(requested, Ordering::Relaxed);
+
+            //Release: same as `core::handle_clear()`
+            mem.cleared_generation.store(requested, Ordering::Release);
+        }
+    }
+
+    ///Same as `reset_timeline()`, for this context
+    pub fn reset_timeline(&self) {
+        let mem = unsafe { &mut *self.data() };
+        let _guard = self.clear_lock.lock().unwrap();
+
+        self.origin.store(clock::nanos_between(self.start_time, clock::now()), Ordering::Relaxed);
+        mem.clear_all();
+        mem.timeline_resets.fetch_add(1, Ordering::Release);
+    }
+
+    ///Same as `core::timeline_time()`, but relative to this context's timeline
+    pub(crate) fn timeline_time(&self, t: clock::Timestamp) -> shmem::Time {
+        clock::nanos_between(self.start_time, t).saturating_sub(self.origin.load(Ordering::Relaxed))
+    }
+
+    //Copies the name until a zone carrying it made it into this context's segment. Threads
+    //racing on the first instance may both copy it, which is harmless.
+    pub(crate) fn push_zone(&self, zone: &PendingZone) -> bool {
+        let key = zone.info as *const crate::ZoneInfo as usize;
+        let copy_name = !self.names_sent.lock().unwrap_or_else(|e| e.into_inner()).contains(&key);
+        let ok = unsafe { (*self.data()).zone_data.push(&ContextZone { zone, copy_name }) };
+
+        if ok && copy_name {
+            self.names_sent.lock().unwrap_or_else(|e| e.into_inner()).insert(key);
+        }
+
+        ok
+    }
+
+    pub(crate) fn push_zone_text(&self, text: &ZoneText) -> bool {
+        unsafe { (*self.data()).zone_text_data.push(text) }
+    }
+
+    ///Same as `send_log()`, for this context
+    pub fn send_log(&self, msg: &str, color: Color) -> bool {
+        self.send_log_level(msg, LogLevel::Info, color)
+    }
+
+    ///Same as `send_log_level()`, for this context
+    pub fn send_log_level(&self, msg: &str, level: LogLevel, color: Color) -> bool {
+        if !crate::is_enabled() {
+            return false;
+        }
+
+        let time = self.timeline_time(clock::now());
+        unsafe { (*self.data()).push_log(time, level, color, msg) }
+    }
+
+    ///Same as `send_log_kv()`, for this context
+    pub fn send_log_kv(&self, msg: &str, fields: &[(&str, &str)], color: Color) -> bool {
+        self.send_log_kv_level(msg, fields, LogLevel::Info, color)
+    }
+
+    ///Same as `send_log_kv_level()`, for this context
+    pub fn send_log_kv_level(&self, msg: &str, fields: &[(&str, &str)], level: LogLevel, color: Color) -> bool {
+        if !crate::is_enabled() {
+            return false;
+        }
+
+        let time = self.timeline_time(clock::now());
+        unsafe { (*self.data()).push_log_kv(time, level, color, msg, fields) }
+    }
+
+    fn push_frame_info(&self, num: u64, set: Option<(&'static str, bool)>, start: Option<clock::Timestamp>, end: clock::Timestamp) -> bool {
+        if !crate::is_enabled() {
+            return false;
+        }
+
+        let mem = unsafe { &mut *self.data() };
+        let entry = FrameInfo {
+            number: num,
+            start: self.timeline_time(start.unwrap_or(self.start_time)),
+            end: self.timeline_time(end),
+            duration: clock::nanos_between(start.unwrap_or(self.start_time), end),
+            gpu_present: 0,
+            set
+        };
+
+        mem.client_heartbeat();
+
+        let ok = mem.frame_data.push(&entry);
+
+        if !ok {
+            mem.dropped_frames.fetch_add(1, Ordering::Relaxed);
+        }
+
+        mem.notify_data();
+
+        ok
+    }
+
+    ///Same as `send_frame_info()`, for this context
+    pub fn send_frame_info(&self, num: u64, start: Option<clock::Timestamp>, end: clock::Timestamp) {
+        self.push_frame_info(num, None, start, end);
+    }
+
+    ///Same as `send_named_frame_info()`, for this context
+    pub fn send_named_frame_info(&self, name: &'static str, copy_name: bool, num: u64, start: Option<clock::Timestamp>, end: clock::Timestamp) -> bool {
+        self.push_frame_info(num, Some((name, copy_name)), start, end)
+    }
+}
@@ -1,7 +1,9 @@
 use crate::shmem;
+use crate::clock;
 
 use std::sync::Mutex;
 use std::sync::Once;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
 use std::mem::MaybeUninit;
 use std::time::Instant;
 
@@ -10,13 +12,158 @@ struct Core
     mem: MaybeUninit<shmem::SharedMemory>,
     ready: bool,
     last_check: Mutex<Option<Instant>>,
-    start_time: Instant
+    start_time: clock::Timestamp
 }
 
 static mut CORE: MaybeUninit<Core> = MaybeUninit::uninit();
 static CORE_INITIALIZER: Once = Once::new();
 
-pub unsafe fn get_shmem_data_and_start_time() -> (Option<&'static mut shmem::SharedMemoryData>, Instant) {
+//Last clear generation handled, and timeline origin (nanoseconds after `Core::start_time`) it resulted in
+//Bumped on each clear and on each `reset_timeline()`, see `generation()`
+static TIMELINE_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+//All of them are Relaxed: they are only written under `Core::last_check`, and a thread reading a
+//stale value for a moment just timestamps a zone against the previous timeline
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+static TIMELINE_ORIGIN: AtomicU64 = AtomicU64::new(0);
+
+//Bumped in the child each time the process forks, see `after_fork_child()`
+static PROCESS_EPOCH: AtomicU32 = AtomicU32::new(0);
+
+//Bumped each time `try_open()` connects to a server, see `connection_epoch()`
+static CONNECTION_EPOCH: AtomicU32 = AtomicU32::new(1);
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static ENABLED_FROM_ENV: Once = Once::new();
+
+///Returns whether profiling is enabled. The initial value comes from the `TEMPORAL_LENS_ENABLE`
+///environment variable (`0`, `false`, `off` or `no` disable profiling) and defaults to enabled.
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED_FROM_ENV.call_once(|| {
+        if let Ok(value) = std::env::var("TEMPORAL_LENS_ENABLE") {
+            let enabled = !matches!(value.trim().to_ascii_lowercase().as_str(), "0" | "false" | "off" | "no");
+            ENABLED.store(enabled, Ordering::Relaxed);
+        }
+    });
+
+    ENABLED.load(Ordering::Relaxed)
+}
+
+///Same as `is_enabled()` but never reads the environment, which can be required in contexts where
+///allocating is not possible. Returns true if the environment hasn't been read yet.
+#[inline]
+pub fn is_enabled_no_env() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+//0 means `OverloadPolicy::DropNewest`, anything else is the `OverloadPolicy::Sample` rate
+static OVERLOAD_SAMPLING: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_overload_policy(policy: crate::OverloadPolicy) {
+    let rate = match policy {
+        crate::OverloadPolicy::DropNewest => 0,
+        crate::OverloadPolicy::Sample(n)  => n.max(1)
+    };
+
+    OVERLOAD_SAMPLING.store(rate, Ordering::Relaxed);
+}
+
+pub fn overload_policy() -> crate::OverloadPolicy {
+    match OVERLOAD_SAMPLING.load(Ordering::Relaxed) {
+        0 => crate::OverloadPolicy::DropNewest,
+        n => crate::OverloadPolicy::Sample(n)
+    }
+}
+
+static MAX_DEPTH: AtomicU32 = AtomicU32::new(u32::MAX);
+static MAX_DEPTH_FROM_ENV: Once = Once::new();
+
+///Zones at this depth or deeper are counted (so that depths stay right) but not sent, which
+///bounds what a runaway recursion can push. The server sees how many were skipped in
+///`SharedMemoryData::depth_capped`. The initial value comes from `TEMPORAL_LENS_MAX_DEPTH`
+///and defaults to no limit.
+pub fn set_max_depth(max: u32) {
+    MAX_DEPTH_FROM_ENV.call_once(|| {});
+    MAX_DEPTH.store(max, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn max_depth() -> u32 {
+    MAX_DEPTH_FROM_ENV.call_once(|| {
+        if let Some(max) = std::env::var("TEMPORAL_LENS_MAX_DEPTH").ok().and_then(|x| x.trim().parse().ok()) {
+            MAX_DEPTH.store(max, Ordering::Relaxed);
+        }
+    });
+
+    MAX_DEPTH.load(Ordering::Relaxed)
+}
+
+static MAX_RECURSION: AtomicU32 = AtomicU32::new(0);
+
+///Only sends the `max` outermost zones of a call site that are nested in each other, so that
+///deeply recursive functions don't fill the buffer with identical zones. The zones that are sent still
+///report their actual depth. 0 (the default) disables the limit, which also avoids the cost of
+///keeping track of the call sites of the zones in flight. Takes effect for zones started after
+///the call.
+pub fn set_max_recursion(max: u32) {
+    MAX_RECURSION.store(max, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn max_recursion() -> u32 {
+    MAX_RECURSION.load(Ordering::Relaxed)
+}
+
+static AUTO_COLORS: AtomicBool = AtomicBool::new(false);
+
+///Gives zones declared without a color one derived from their name (see `Color::from_name()`)
+///instead of orange, so that each function gets a distinct color that stays the same across
+///runs. Each call site picks its color the first time it's reached, so call this early.
+pub fn set_auto_colors(enabled: bool) {
+    AUTO_COLORS.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn auto_colors() -> bool {
+    AUTO_COLORS.load(Ordering::Relaxed)
+}
+
+///Enables or disables profiling at runtime. This overrides `TEMPORAL_LENS_ENABLE`.
+pub fn set_enabled(enabled: bool) {
+    ENABLED_FROM_ENV.call_once(|| {});
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+//`ConnectionState` as a number, see `connection_state()`
+const DISCONNECTED: u8 = 0;
+const CONNECTING: u8 = 1;
+const CONNECTED: u8 = 2;
+const LOST: u8 = 3;
+
+static CONNECTION: AtomicU8 = AtomicU8::new(DISCONNECTED);
+
+///Returns the state of the connection to the server. `Lost` is only noticed once per frame (or
+///by the heartbeat thread, see `start_heartbeat()`).
+pub fn connection_state() -> crate::ConnectionState {
+    match CONNECTION.load(Ordering::Relaxed) {
+        CONNECTING => crate::ConnectionState::Connecting,
+        CONNECTED  => crate::ConnectionState::Connected,
+        LOST       => crate::ConnectionState::Lost,
+        _          => crate::ConnectionState::Disconnected
+    }
+}
+
+#[inline]
+pub fn is_connected() -> bool {
+    CONNECTION.load(Ordering::Relaxed) == CONNECTED
+}
+
+//How long to wait before trying to open the shared memory again, and whether to try again at all
+static RETRY_INTERVAL_MS: AtomicU64 = AtomicU64::new(10_000);
+static RETRY: AtomicBool = AtomicBool::new(true);
+
+unsafe fn init_core() {
     //Initialize core
     //---------------
     //What concerns me is that `Once` relies on an atomic boolean, which issues
@@ -32,15 +179,94 @@ pub unsafe fn get_shmem_data_and_start_time() -> (Option<&'static mut shmem::Sha
             mem: MaybeUninit::uninit(),
             ready: false,
             last_check: Mutex::new(None),
-            start_time: Instant::now()
+            start_time: clock::now()
         });
+
+        #[cfg(feature = "track-heap")]
+        crate::heap_tracker::init_from_env();
+
+        #[cfg(all(unix, feature = "fork-safe"))]
+        libc::pthread_atfork(None, None, Some(after_fork_child));
+    });
+}
+
+//Opens the shared memory right away. `last_check` is the guard of `Core::last_check`.
+unsafe fn try_open(mem: &mut MaybeUninit<shmem::SharedMemory>, ready: &mut bool, last_check: &mut Option<Instant>) -> Result<&'static mut shmem::SharedMemoryData, shmem::SharedMemoryOpenError> {
+    let previous = CONNECTION.swap(CONNECTING, Ordering::Relaxed);
+
+    //What a crashed server leaves behind isn't worth connecting to
+    let result = shmem::SharedMemory::open().and_then(|opened| {
+        if opened.is_server_alive() { Ok(opened) } else { Err(shmem::SharedMemoryOpenError::ServerNotRunning) }
     });
 
+    match result {
+        Ok(opened) => {
+            //Clears requested before we got there are none of our business
+            GENERATION.store(opened.clear_generation.load(Ordering::Relaxed), Ordering::Relaxed);
+
+            //A new server knows none of the names sent so far
+            CONNECTION_EPOCH.fetch_add(1, Ordering::Relaxed);
+
+            //If this replaces a lost connection, the old mapping is leaked: threads might still be writing to it
+            let ret = &mut *(mem.write(opened) as *mut shmem::SharedMemory);
+            std::ptr::write_volatile(ready, true);
+            CONNECTION.store(CONNECTED, Ordering::Relaxed);
+
+            Ok(ret)
+        },
+
+        Err(err) => {
+            *last_check = Some(Instant::now());
+            CONNECTION.store(if previous == LOST { LOST } else { DISCONNECTED }, Ordering::Relaxed);
+            Err(err)
+        }
+    }
+}
+
+//Second (since UNIX epoch) at which the server was last seen alive by `check_connection()`
+static LAST_ALIVE_CHECK: AtomicU64 = AtomicU64::new(0);
+
+///Checks that the server behind `mem` is still there, at most once per second as it may cost a
+///syscall (see `SharedMemoryData::is_server_alive()`). If it's not, the connection is marked as
+///`Lost` and the client tries to connect to a new server every `retry_interval`. Returns false
+///if the connection was lost, in which case `mem` shouldn't be written to anymore.
+pub fn check_connection(mem: &shmem::SharedMemoryData) -> bool {
+    if mem.magic == shmem::MAGIC {
+        let now = shmem::unix_time();
+
+        if LAST_ALIVE_CHECK.load(Ordering::Relaxed) == now || mem.is_server_alive() {
+            LAST_ALIVE_CHECK.store(now, Ordering::Relaxed);
+            return true;
+        }
+    }
+
+    unsafe {
+        let core = CORE.get_mut();
+        let mut last_check = core.last_check.lock().unwrap();
+
+        if std::ptr::read_volatile(&core.ready) {
+            std::ptr::write_volatile(&mut core.ready, false);
+            *last_check = Some(Instant::now());
+            CONNECTION.store(LOST, Ordering::Relaxed);
+        }
+    }
+
+    false
+}
+
+pub unsafe fn get_shmem_data_and_start_time() -> (Option<&'static mut shmem::SharedMemoryData>, clock::Timestamp) {
+    init_core();
     let core = CORE.get_mut();
 
     if std::ptr::read_volatile(&core.ready) {
         //Shared mem is already open
-        (Some(&mut *core.mem.get_mut()), core.start_time)
+        let mem = &mut *core.mem.get_mut();
+
+        if mem.clear_generation.load(Ordering::Relaxed) != GENERATION.load(Ordering::Relaxed) {
+            handle_clear(mem, &core.last_check, core.start_time);
+        }
+
+        (Some(mem), core.start_time)
     } else {
         //Shared mem might not be open just yet, lock mutex & check again...
         //Here we assume that the mutex issues a memory barrier, which it surely does
@@ -51,24 +277,14 @@ pub unsafe fn get_shmem_data_and_start_time() -> (Option<&'static mut shmem::Sha
             (Some(&mut *core.mem.get_mut()), core.start_time)
         } else {
             //Indeed, it's not open
-            let now = Instant::now();
-            let should_init = last_check.map(|x| now.saturating_duration_since(x).as_secs() >= 10).unwrap_or(true);
-            
+            let should_init = match *last_check {
+                Some(x) => RETRY.load(Ordering::Relaxed) && Instant::now().saturating_duration_since(x).as_millis() as u64 >= RETRY_INTERVAL_MS.load(Ordering::Relaxed),
+                None    => true
+            };
+
             if should_init {
-                //Try to initialize again
-                let mem_result = shmem::SharedMemory::open();
-
-                if let Ok(mem) = mem_result {
-                    let ret = core.mem.write(mem);
-                    std::ptr::write_volatile(&mut core.ready, true);
-                    
-                    //Success!!
-                    (Some(ret), core.start_time)
-                } else {
-                    //Init failure; TODO: report this error!!
-                    *last_check = Some(now);
-                    (None, core.start_time)
-                }
+                //Try to initialize again; use `preinit_with()` to get the error
+                (try_open(&mut core.mem, &mut core.ready, &mut last_check).ok(), core.start_time)
             } else {
                 //Not yet time for another try
                 (None, core.start_time)
@@ -77,7 +293,137 @@ pub unsafe fn get_shmem_data_and_start_time() -> (Option<&'static mut shmem::Sha
     }
 }
 
-pub unsafe fn get_shmem_data_and_start_time_ro() -> Option<(&'static mut shmem::SharedMemoryData, Instant)> {
+///Applies the connection settings of `opts`, then opens the shared memory if it's not open
+///yet, returning why it failed if it did
+pub fn connect(opts: &crate::PreinitOptions) -> Result<(), shmem::SharedMemoryOpenError> {
+    match (&opts.path, &opts.instance) {
+        (Some(path), _)    => shmem::SharedMemory::set_path(Some(path.clone())),
+        (None, Some(name)) => shmem::SharedMemory::set_path(Some(shmem::SharedMemory::get_path_for(Some(name)))),
+        (None, None)       => ()
+    }
+
+    if let Some(interval) = opts.retry_interval {
+        RETRY_INTERVAL_MS.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    if let Some(fail_fast) = opts.fail_fast {
+        RETRY.store(!fail_fast, Ordering::Relaxed);
+    }
+
+    unsafe {
+        init_core();
+        let core = CORE.get_mut();
+        let mut last_check = core.last_check.lock().unwrap();
+
+        if std::ptr::read_volatile(&core.ready) {
+            Ok(())
+        } else {
+            try_open(&mut core.mem, &mut core.ready, &mut last_check).map(|_| ())
+        }
+    }
+}
+
+//The server asked for a clear: restart the timeline from now, then empty the buffers so
+//that anything computed relative to the old origin is gone. Threads keep their pending
+//zones around, but `ThreadInfo::flush` discards them if `generation()` changed meanwhile.
+#[cold]
+fn handle_clear(mem: &mut shmem::SharedMemoryData, lock: &Mutex<Option<Instant>>, start_time: clock::Timestamp) {
+    let _guard = lock.lock().unwrap();
+    let requested = mem.clear_generation.load(Ordering::Relaxed);
+
+    if requested != GENERATION.load(Ordering::Relaxed) {
+        TIMELINE_ORIGIN.store(clock::nanos_between(start_time, clock::now()), Ordering::Relaxed);
+        TIMELINE_GENERATION.fetch_add(1, Ordering::Relaxed);
+        mem.clear_all();
+
+        GENERATION.store(requested, Ordering::Relaxed);
+
+        //Release: the server must see empty buffers once it sees the clear as done
+        mem.cleared_generation.store(requested, Ordering::Release);
+    }
+}
+
+///Returns the timeline generation the client is currently in. It changes each time the timeline
+///origin moves (clears and `reset_timeline()`), after which older times are meaningless.
+#[inline]
+pub fn generation() -> u32 {
+    TIMELINE_GENERATION.load(Ordering::Relaxed)
+}
+
+///Restarts the timeline from now, so that subsequent times are relative to this moment (e.g. to
+///ignore the warmup of an app). Just like when the server asks for a clear, everything still
+///buffered is dropped and zones in flight saturate to 0. The server is told through
+///`SharedMemoryData::timeline_resets`.
+pub fn reset_timeline() {
+    unsafe {
+        let (opt_mem, start_time) = get_shmem_data_and_start_time();
+        let _guard = CORE.get_ref().last_check.lock().unwrap();
+
+        TIMELINE_ORIGIN.store(clock::nanos_between(start_time, clock::now()), Ordering::Relaxed);
+        TIMELINE_GENERATION.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(mem) = opt_mem {
+            mem.clear_all();
+
+            //Release: the server must see empty buffers once it sees the reset
+            mem.timeline_resets.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+///Converts a timestamp to a `Time` relative to the current timeline origin. Timestamps
+///taken before the origin (e.g. in-flight zones during a clear) saturate to 0.
+#[inline]
+pub fn timeline_time(start_time: clock::Timestamp, t: clock::Timestamp) -> shmem::Time {
+    clock::nanos_between(start_time, t).saturating_sub(TIMELINE_ORIGIN.load(Ordering::Relaxed))
+}
+
+//The child inherits the parent's mapping and `Core`, including a mutex that might be
+//held by a thread that doesn't exist anymore. Forget about them (the mapping is leaked)
+//so that the child opens its own view of the shared memory on the next push.
+//Note that `Mutex::new()` allocates, which glibc allows in the child of a fork.
+#[cfg(all(unix, feature = "fork-safe"))]
+extern "C" fn after_fork_child() {
+    unsafe {
+        let core = CORE.get_mut();
+
+        std::ptr::write(&mut core.last_check, Mutex::new(None));
+        std::ptr::write_volatile(&mut core.ready, false);
+    }
+
+    CONNECTION.store(DISCONNECTED, Ordering::Relaxed);
+
+    PROCESS_EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+///Returns how many times this process went through a `fork()`, always 0 on Windows
+///or without the `fork-safe` feature. Thread infos created before are stale.
+#[inline]
+pub fn process_epoch() -> u32 {
+    PROCESS_EPOCH.load(Ordering::Relaxed)
+}
+
+///Starts at 1 and goes up each time the client connects to a server. Names (of zones, plots,
+///frame sets and threads) sent during an older epoch went to a server that's gone, see
+///`NameSent`. Never 0, which `NameSent` uses for names that were never sent.
+#[inline]
+pub fn connection_epoch() -> u32 {
+    CONNECTION_EPOCH.load(Ordering::Relaxed)
+}
+
+///Turns a `ThreadId` into the key sent to the server. With `fork-safe`, the process ID
+///is mixed in so that threads of a forked child don't collide with the parent's.
+#[inline]
+pub fn thread_key(id: std::thread::ThreadId) -> u64 {
+    let id = id.as_u64().get();
+
+    #[cfg(all(unix, feature = "fork-safe"))]
+    let id = id | ((std::process::id() as u64) << 32);
+
+    id
+}
+
+pub unsafe fn get_shmem_data_and_start_time_ro() -> Option<(&'static mut shmem::SharedMemoryData, clock::Timestamp)> {
     if !CORE_INITIALIZER.is_completed() {
         return None;
     }
@@ -0,0 +1,399 @@
+///Stand-ins used when the `enabled` feature is turned off. They keep the public API
+///intact so that instrumented code still compiles, but expand to nothing at all:
+///no thread-local, no `Once` and no shared memory access ends up in the binary.
+
+use crate::{clock, ZoneInfo, PlotInfo, OverloadPolicy, ConnectionState, PreinitOptions, SharedMemoryOpenError, Color, LogLevel};
+
+pub struct Zone;
+
+impl Zone {
+    #[inline(always)]
+    pub fn new(_info: &'static ZoneInfo) -> Self {
+        Zone
+    }
+
+    #[inline(always)]
+    pub fn new_to(_target: &'static Context, _info: &'static ZoneInfo) -> Self {
+        Zone
+    }
+
+    #[inline(always)]
+    pub fn text(&self, _text: &str) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn end(self) {
+    }
+}
+
+pub struct PlotDownsampler;
+
+impl PlotDownsampler {
+    #[inline(always)]
+    pub fn new(_name: &'static str, _color: Color, _bucket: std::time::Duration) -> Self {
+        PlotDownsampler
+    }
+
+    #[inline(always)]
+    pub fn push(&mut self, _value: f64) {
+    }
+
+    #[inline(always)]
+    pub fn push_at(&mut self, _value: f64, _time: u64) {
+    }
+
+    #[inline(always)]
+    pub fn flush(&mut self) -> bool {
+        false
+    }
+}
+
+pub struct FrameMarker;
+
+impl FrameMarker {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        FrameMarker
+    }
+
+    #[inline(always)]
+    pub fn mark(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn mark_gpu(&self, _gpu_present: u64) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn mark_named(&self, _name: &'static str) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn mark_to(&self, _target: &Context) {
+    }
+}
+
+impl Default for FrameMarker {
+    fn default() -> Self {
+        FrameMarker
+    }
+}
+
+pub struct Context;
+
+impl Context {
+    #[inline(always)]
+    pub fn open() -> Option<Context> {
+        Some(Context)
+    }
+
+    #[inline(always)]
+    pub fn send_log(&self, _msg: &str, _color: Color) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn send_log_level(&self, _msg: &str, _level: LogLevel, _color: Color) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn send_log_kv(&self, _msg: &str, _fields: &[(&str, &str)], _color: Color) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn send_log_kv_level(&self, _msg: &str, _fields: &[(&str, &str)], _level: LogLevel, _color: Color) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn reset_timeline(&self) {
+    }
+
+    #[inline(always)]
+    pub fn send_frame_info(&self, _num: u64, _start: Option<clock::Timestamp>, _end: clock::Timestamp) {
+    }
+
+    #[inline(always)]
+    pub fn send_named_frame_info(&self, _name: &'static str, _copy_name: bool, _num: u64, _start: Option<clock::Timestamp>, _end: clock::Timestamp) -> bool {
+        false
+    }
+}
+
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn set_enabled(_enabled: bool) {
+}
+
+#[inline(always)]
+pub fn set_overload_policy(_policy: OverloadPolicy) {
+}
+
+#[inline(always)]
+pub fn overload_policy() -> OverloadPolicy {
+    OverloadPolicy::DropNewest
+}
+
+#[inline(always)]
+pub fn reset_timeline() {
+}
+
+#[inline(always)]
+pub fn connection_state() -> ConnectionState {
+    ConnectionState::Disconnected
+}
+
+#[inline(always)]
+pub fn is_connected() -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn set_max_depth(_max: u32) {
+}
+
+#[inline(always)]
+pub fn set_max_recursion(_max: u32) {
+}
+
+#[inline(always)]
+pub fn set_auto_colors(_enabled: bool) {
+}
+
+#[inline(always)]
+pub fn flush_thread() {
+}
+
+#[inline(always)]
+pub fn shutdown() {
+}
+
+#[inline(always)]
+pub fn register_thread(_name: &str) {
+}
+
+#[cfg(feature = "rayon")]
+#[inline(always)]
+pub fn name_rayon_workers<S>(builder: rayon::ThreadPoolBuilder<S>) -> rayon::ThreadPoolBuilder<S> {
+    builder
+}
+
+#[inline(always)]
+pub fn send_log(_msg: &str, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn log_message(_color: Color, _msg: &str) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn send_log_level(_msg: &str, _level: LogLevel, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn send_log_kv(_msg: &str, _fields: &[(&str, &str)], _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn send_log_kv_level(_msg: &str, _fields: &[(&str, &str)], _level: LogLevel, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn timeline_time(_t: clock::Timestamp) -> u64 {
+    0
+}
+
+#[inline(always)]
+pub fn timeline_now() -> u64 {
+    0
+}
+
+#[inline(always)]
+pub fn submit_plot_at(_name: &'static str, _value: f64, _color: Color, _time: u64) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn send_plot(_info: &'static PlotInfo, _value: f64) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn plot_value(_name: &'static str, _value: f64, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn set_plot_color(_name: &'static str, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn submit_zone_at(_name: &'static str, _color: Color, _track: &'static str, _start: u64, _end: u64, _depth: u32) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn install_panic_hook() {
+}
+
+pub const SIGNAL_QUEUE_SIZE: usize = 0;
+
+#[inline(always)]
+pub fn signal_plot(_name: &'static str, _value: f64, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn signal_message(_msg: &'static str, _color: Color) -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn flush_signal_queue() -> usize {
+    0
+}
+
+#[inline(always)]
+pub fn start_heartbeat(_interval: std::time::Duration) {
+}
+
+#[inline(always)]
+pub fn preinit() {
+}
+
+#[inline(always)]
+pub fn preinit_with(_opts: PreinitOptions) -> Result<(), SharedMemoryOpenError> {
+    Ok(())
+}
+
+#[inline(always)]
+pub fn frame_plot(_info: &'static PlotInfo, _value: f64) {
+}
+
+#[inline(always)]
+pub fn mark_frame() -> bool {
+    false
+}
+
+#[inline(always)]
+pub fn mark_frame_named(_name: &'static str) -> bool {
+    false
+}
+
+///# Safety
+///None, this only mirrors the enabled version.
+#[inline(always)]
+#[deprecated(note = "use `mark_frame()` or a `FrameMarker` instead")]
+pub unsafe fn send_frame_info(_num: u64, _start: Option<clock::Timestamp>, _end: clock::Timestamp) {
+}
+
+///# Safety
+///None, this only mirrors the enabled version.
+#[inline(always)]
+#[deprecated(note = "use `FrameMarker::mark_gpu()` instead")]
+pub unsafe fn send_frame_info_gpu(_num: u64, _start: Option<clock::Timestamp>, _end: clock::Timestamp, _gpu_present: u64) {
+}
+
+///# Safety
+///None, this only mirrors the enabled version.
+#[inline(always)]
+#[deprecated(note = "use `mark_frame_named()` or a `FrameMarker` instead")]
+pub unsafe fn send_named_frame_info(_name: &'static str, _copy_name: bool, _num: u64, _start: Option<clock::Timestamp>, _end: clock::Timestamp) -> bool {
+    false
+}
+
+#[macro_export]
+macro_rules! start_zone_profiling {
+    ($($args:tt)*) => { $crate::Zone };
+}
+
+#[macro_export]
+macro_rules! profile_scope {
+    ($($args:tt)*) => {};
+}
+
+#[macro_export]
+macro_rules! start_zone_profiling_generic {
+    ($($args:tt)*) => { $crate::Zone };
+}
+
+#[macro_export]
+macro_rules! profile_scope_generic {
+    ($($args:tt)*) => {};
+}
+
+#[macro_export]
+macro_rules! profile_expr {
+    ($name:expr, color: $color:expr, $e:expr) => { $e };
+    ($name:expr, $e:expr) => { $e };
+}
+
+#[macro_export]
+macro_rules! profile_scope_min {
+    ($($args:tt)*) => {};
+}
+
+#[macro_export]
+macro_rules! profile_scope_aggregate {
+    ($($args:tt)*) => {};
+}
+
+#[macro_export]
+macro_rules! frame_delimiter {
+    () => {{}};
+}
+
+#[macro_export]
+macro_rules! frame_delimiter_gpu {
+    ($gpu_present:expr) => {{}};
+}
+
+#[macro_export]
+macro_rules! frame_plot {
+    ($($args:tt)*) => {{}};
+}
+
+#[macro_export]
+macro_rules! plot {
+    ($($args:tt)*) => { false };
+}
+
+#[macro_export]
+macro_rules! tl_log {
+    ($($args:tt)*) => { false };
+}
+
+#[macro_export]
+macro_rules! frame_mark_named {
+    ($name:expr) => {{}};
+}
+
+#[macro_export]
+macro_rules! start_zone_profiling_to {
+    ($($args:tt)*) => { $crate::Zone };
+}
+
+#[macro_export]
+macro_rules! profile_scope_to {
+    ($($args:tt)*) => {};
+}
+
+#[macro_export]
+macro_rules! frame_delimiter_to {
+    ($target:expr) => {{}};
+}
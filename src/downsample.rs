@@ -0,0 +1,151 @@
+///Client-side reduction of dense plot series. Plotting a value per audio sample (or per
+///anything happening thousands of times per frame) overwhelms `plot_data`, which then drops
+///whatever doesn't fit: the server ends up with a random subset of the series, usually
+///missing its peaks. A `PlotDownsampler` instead keeps the minimum, the maximum and the last
+///value of each time bucket, and only sends these, so the server still gets the envelope.
+
+use crate::{core, Color, NameSent};
+use crate::shmem::{self, PlotData, WriteInto};
+
+#[derive(Copy, Clone)]
+struct Sample {
+    time: shmem::Time,
+    value: f64
+}
+
+struct Bucket {
+    end: shmem::Time, //Samples from this time on go into the next bucket
+    min: Sample,
+    max: Sample,
+    last: Sample
+}
+
+struct DownsampledPlot {
+    time: shmem::Time,
+    color: Color,
+    value: f64,
+    name: &'static str,
+    copy_name: bool
+}
+
+impl WriteInto<PlotData> for DownsampledPlot {
+    fn write_into(&self, target: &mut PlotData) {
+        target.time = self.time;
+        target.color = self.color;
+        target.set_value(self.value);
+        target.name.set(self.name, self.copy_name);
+    }
+}
+
+///One per series, e.g. `let mut level = PlotDownsampler::new("audio level", colors::BLUE, Duration::from_millis(1));`
+///then `level.push(x)` for each sample. What's left is sent on `flush()` or when it's dropped.
+pub struct PlotDownsampler {
+    name: &'static str,
+    color: Color,
+    bucket: shmem::Duration, //Duration of each bucket, in nanoseconds
+    name_sent: NameSent,
+    current: Option<Bucket>
+}
+
+impl PlotDownsampler {
+    ///Creates a downsampler for the plot `name`, sending at most three values (min, max and
+    ///last) per `bucket`. The name must fit in `SHARED_STRING_MAX_SIZE` bytes.
+    pub fn new(name: &'static str, color: Color, bucket: std::time::Duration) -> Self {
+        PlotDownsampler {
+            name, color,
+            bucket: (bucket.as_nanos() as shmem::Duration).max(1),
+            name_sent: NameSent::new(),
+            current: None
+        }
+    }
+
+    ///Adds a value measured now
+    pub fn push(&mut self, value: f64) {
+        self.push_at(value, crate::timeline_now());
+    }
+
+    ///Adds a value measured at `time` (see `timeline_time()`). Times should increase; a value
+    ///older than the current bucket is just added to it.
+    pub fn push_at(&mut self, value: f64, time: u64) {
+        let sample = Sample { time, value };
+
+        match &mut self.current {
+            Some(bucket) if time < bucket.end => {
+                if value < bucket.min.value {
+                    bucket.min = sample;
+                }
+
+                if value > bucket.max.value {
+                    bucket.max = sample;
+                }
+
+                bucket.last = sample;
+                return;
+            },
+
+            _ => {}
+        }
+
+        self.flush();
+        self.current = Some(Bucket {
+            end: time.saturating_add(self.bucket),
+            min: sample,
+            max: sample,
+            last: sample
+        });
+    }
+
+    ///Sends the current bucket right away, without waiting for it to end. Returns false if
+    ///some of its values couldn't be sent (or if profiling is disabled).
+    pub fn flush(&mut self) -> bool {
+        let bucket = match self.current.take() {
+            Some(x) => x,
+            None    => return true
+        };
+
+        if !core::is_enabled() {
+            return false;
+        }
+
+        let mem = match unsafe { core::get_shmem_data_and_start_time() } {
+            (Some(mem), _) => mem,
+            _              => return false
+        };
+
+        //In time order, skipping samples that are the same. Min and max may share a time
+        //(e.g. when pushed with the same timestamp), so both the time and the value must match.
+        let mut samples = [bucket.min, bucket.max, bucket.last];
+        samples.sort_by_key(|s| s.time);
+
+        let mut ret = true;
+        let epoch = core::connection_epoch();
+
+        for (i, s) in samples.iter().enumerate() {
+            if samples[..i].iter().any(|x| x.time == s.time && x.value.to_bits() == s.value.to_bits()) {
+                continue;
+            }
+
+            let entry = DownsampledPlot {
+                time: s.time,
+                color: self.color,
+                value: s.value,
+                name: self.name,
+                copy_name: self.name_sent.copy_name()
+            };
+
+            if mem.plot_data.push(&entry) {
+                self.name_sent.set_sent(epoch);
+            } else {
+                ret = false;
+            }
+        }
+
+        ret
+    }
+}
+
+impl Drop for PlotDownsampler {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
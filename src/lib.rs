@@ -3,21 +3,66 @@
 #![feature(maybe_uninit_extra)]
 #![feature(maybe_uninit_ref)]
 #![feature(thread_id_value)]
+#![feature(min_const_generics)]
 
 //Imports
-use std::time::Instant;
-use std::mem::MaybeUninit;
-use std::cell::RefCell;
+#[cfg(feature = "enabled")] use std::cell::RefCell;
+#[cfg(feature = "enabled")] use std::thread_local;
 use std::path::PathBuf;
-use std::thread_local;
 
 use dirs::data_dir;
 
 //Declare modules
 #[cfg(not(feature = "server-mode"))] mod shmem;
 #[cfg(feature = "server-mode")] pub mod shmem;
+#[cfg(feature = "server-mode")] pub mod analysis;
+#[cfg(feature = "server-mode")] pub mod trace;
+#[cfg(feature = "server-mode")] pub mod zone_codec;
+#[cfg(feature = "server-mode")] pub mod remote;
 #[cfg(test)] mod tests;
-mod core;
+#[cfg(feature = "enabled")] mod core;
+#[cfg(feature = "enabled")] mod context;
+#[cfg(feature = "enabled")] mod signal;
+#[cfg(feature = "enabled")] mod downsample;
+#[cfg(feature = "off-cpu")] mod offcpu;
+#[cfg(feature = "metrics-bridge")] mod metrics_bridge;
+#[cfg(not(feature = "enabled"))] mod disabled;
+pub mod clock;
+
+pub use shmem::{Color, LogLevel, SpinConfig, SharedMemoryOpenError, set_spin_config, spin_config, namespace};
+#[cfg(feature = "enabled")] pub use crate::core::{is_enabled, set_enabled, set_overload_policy, overload_policy, reset_timeline, set_auto_colors, set_max_recursion, set_max_depth, connection_state, is_connected};
+#[cfg(feature = "enabled")] pub use context::Context;
+#[cfg(feature = "enabled")] pub use signal::{signal_plot, signal_message, flush_signal_queue, SIGNAL_QUEUE_SIZE};
+#[cfg(feature = "enabled")] pub use downsample::PlotDownsampler;
+#[cfg(feature = "off-cpu")] pub use offcpu::start_off_cpu_sampler;
+#[cfg(feature = "metrics-bridge")] pub use metrics_bridge::MetricsRecorder;
+#[cfg(not(feature = "enabled"))] pub use disabled::*;
+
+///What clients do when the server reports it can't keep up
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OverloadPolicy {
+    DropNewest, //Keep pushing everything; whatever doesn't fit is lost (default)
+    Sample(u32) //Only push one zone out of N while the server is overloaded, so that the timeline remains representative
+}
+
+///State of the connection to the server, see `connection_state()`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConnectionState {
+    Disconnected, //Never connected: the server wasn't found (yet), or profiling is disabled
+    Connecting,   //Opening the shared memory right now
+    Connected,    //Sending data to a running server
+    Lost          //Was connected but the server went away; reconnects once a new one is found
+}
+
+///Options of `preinit_with()`. Options left to None keep their current value, so that
+///calling `preinit()` later on doesn't undo them.
+#[derive(Clone, Debug, Default)]
+pub struct PreinitOptions {
+    pub path: Option<PathBuf>,                       //Path of the shared memory, instead of `SharedMemory::get_path()`. Takes precedence over `instance`
+    pub instance: Option<String>,                    //Instance to connect to instead of `instance_name()`, without touching `TEMPORAL_LENS_INSTANCE`
+    pub retry_interval: Option<std::time::Duration>, //How long to wait before trying again after a failed connection attempt (10 seconds by default)
+    pub fail_fast: Option<bool>                      //Never try again if the connection fails now, so that profiling costs next to nothing for the rest of the run
+}
 
 pub fn get_data_dir() -> PathBuf {
     let mut ret = data_dir().expect("could not find user data directory");
@@ -26,275 +71,2160 @@ pub fn get_data_dir() -> PathBuf {
     ret
 }
 
-pub struct ThreadInfo {
-    id: u64,
-    name: String,
-    name_sent: bool,
-    depth: u32
+///Data directory of a named instance: a subdirectory of `get_data_dir()`, so that several
+///profiled programs running at the same time each get their own shared memory. Characters
+///that don't belong in a file name are replaced by underscores.
+pub fn get_data_dir_for(app: &str) -> PathBuf {
+    let name: String = app.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' }).collect();
+    let mut ret = get_data_dir();
+
+    if name.trim_matches('.').is_empty() {
+        ret.push("_");
+    } else {
+        ret.push(name);
+    }
+
+    ret
+}
+
+///Name of the instance to use, read from `TEMPORAL_LENS_INSTANCE`. None (the default) means the
+///shared memory lives directly in `get_data_dir()`. Both the server and the client must agree on it.
+pub fn instance_name() -> Option<String> {
+    std::env::var("TEMPORAL_LENS_INSTANCE").ok().filter(|x| !x.trim().is_empty())
+}
+
+///Data directory of the current instance (see `instance_name()`)
+pub fn get_instance_data_dir() -> PathBuf {
+    match instance_name() {
+        Some(name) => get_data_dir_for(&name),
+        None       => get_data_dir()
+    }
+}
+
+///How many zones a thread can buffer before they are sent to the server
+#[cfg(feature = "enabled")]
+const ZONE_BATCH_SIZE: usize = 64;
+
+///Pending zones are also sent if the oldest one has been waiting for that long (in nanoseconds)
+#[cfg(feature = "enabled")]
+const ZONE_BATCH_MAX_AGE: shmem::Time = 10_000_000;
+
+#[cfg(feature = "enabled")]
+pub struct ThreadInfo {
+    id: u64,
+    name: String,
+    name_sent: NameSent,        //Whether `name` is in the thread table of the global context
+    named_contexts: Vec<*const Context>, //Contexts whose thread table has `name`, as they don't share the global one
+    depth: u32,
+    pending: Vec<PendingZone>,
+    pending_generation: u32,    //Clear generation the pending zones were recorded in
+    sample_counter: u32,
+    next_instance: u64,         //Instance number of the next zone created by this thread
+    epoch: u32,                 //`core::process_epoch()` when this was created
+    histograms: Vec<PendingHistogram>,
+    open_zones: Vec<*const ZoneInfo>, //Call sites of the zones in flight, only tracked while a recursion limit is set
+    frame_plots: Vec<(&'static PlotInfo, f64)> //Values given to `frame_plot!()` since the last frame, one per plot name
+}
+
+///Instances of an aggregate zone recorded since the last `flush_thread()`
+#[cfg(feature = "enabled")]
+struct PendingHistogram {
+    info: &'static ZoneInfo,
+    generation: u32, //Clear generation the instances were recorded in
+    thread_id: u64,
+    end: shmem::Time,
+    count: u64,
+    total: shmem::Duration,
+    min: shmem::Duration,
+    max: shmem::Duration,
+    buckets: [u32; shmem::HISTOGRAM_BUCKETS]
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::ZoneHistogramData> for PendingHistogram {
+    fn write_into(&self, target: &mut shmem::ZoneHistogramData) {
+        let info = self.info;
+
+        target.uid = info.uid();
+        target.color = info.color();
+        target.end = self.end;
+        target.count = self.count;
+        target.total = self.total;
+        target.min = self.min;
+        target.max = self.max;
+        target.buckets = self.buckets;
+        target.name.set(info.name, info.name_sent.copy_name());
+        target.thread = self.thread_id as usize;
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl ThreadInfo {
+    fn get_or_init(slot: &mut Option<ThreadInfo>) -> &mut ThreadInfo {
+        let epoch = core::process_epoch();
+
+        //After a fork, the child starts over but keeps the depth of the zones still in flight
+        if slot.as_ref().map(|ti| ti.epoch != epoch).unwrap_or(true) {
+            let actual_ti = std::thread::current();
+            let (depth, open_zones) = match slot.take() {
+                //Whatever `old` buffered was recorded by the parent, so dropping it sends nothing
+                Some(mut old) => (old.depth, std::mem::take(&mut old.open_zones)),
+                None          => (0, Vec::new())
+            };
+
+            *slot = Some(ThreadInfo {
+                id: core::thread_key(actual_ti.id()),
+                name: actual_ti.name().unwrap_or("").to_string(),
+                name_sent: NameSent::new(),
+                named_contexts: Vec::new(),
+                depth,
+                pending: Vec::with_capacity(ZONE_BATCH_SIZE),
+                pending_generation: 0,
+                sample_counter: 0,
+                next_instance: 0,
+                histograms: Vec::new(),
+                epoch,
+                open_zones,
+                frame_plots: Vec::new()
+            });
+        }
+
+        slot.as_mut().unwrap()
+    }
+
+    fn should_record(&mut self, mem: &shmem::SharedMemoryData) -> bool {
+        match core::overload_policy() {
+            OverloadPolicy::Sample(n) if mem.is_overloaded() => {
+                self.sample_counter = self.sample_counter.wrapping_add(1);
+                self.sample_counter % n == 0
+            },
+
+            _ => true
+        }
+    }
+
+    fn flush(&mut self, mem: &mut shmem::SharedMemoryData) {
+        if self.pending_generation != core::generation() || self.epoch != core::process_epoch() {
+            //Recorded before a clear (their times are relative to the old origin), or by the parent process
+            self.pending.clear();
+            return;
+        }
+
+        self.send_name(mem);
+
+        let epoch = core::connection_epoch();
+        let sent = mem.zone_data.push_slice(&self.pending);
+
+        for zone in &self.pending[0..sent] {
+            //Name sent; don't need to do it again until the next connection
+            zone.info.name_sent.set_sent(epoch);
+        }
+
+        self.pending.clear();
+    }
+
+    //Writes the name of this thread to the thread table of the global context, once. If the
+    //table is full, the thread stays unnamed rather than scanning it again on every flush.
+    fn send_name(&mut self, mem: &mut shmem::SharedMemoryData) {
+        if self.name_sent.copy_name() {
+            let epoch = core::connection_epoch();
+
+            mem.thread_table.set(self.id as usize, &self.name);
+            self.name_sent.set_sent(epoch);
+        }
+    }
+
+    //Same as `send_name()`, for `target`
+    fn send_name_to(&mut self, target: &'static Context) {
+        let ptr = target as *const Context;
+
+        if !self.named_contexts.contains(&ptr) {
+            unsafe { (*target.data()).thread_table.set(self.id as usize, &self.name); }
+            self.named_contexts.push(ptr);
+        }
+    }
+
+    //Called when a zone ends
+    fn leave(&mut self, tracked: bool) {
+        //Saturate so that broken bookkeeping (e.g. a zone moved to another thread)
+        //doesn't poison the depth of every subsequent zone of this thread. Zones dropped while
+        //unwinding must not panic again, which would abort.
+        debug_assert!(self.depth > 0 || std::thread::panicking(), "temporal-lens: zone depth underflow on thread {} ({:?})", self.id, self.name);
+        self.depth = self.depth.saturating_sub(1);
+
+        if tracked {
+            self.open_zones.pop();
+        }
+    }
+
+    fn record_aggregate(&mut self, info: &'static ZoneInfo, end: shmem::Time, duration: shmem::Duration) {
+        let generation = core::generation();
+        let thread_id = self.id;
+
+        //Aggregate zones are few, a linear search is fine
+        let hist = match self.histograms.iter().position(|h| std::ptr::eq(h.info, info)) {
+            Some(i) => &mut self.histograms[i],
+
+            None => {
+                self.histograms.push(PendingHistogram {
+                    info, generation, thread_id, end,
+                    count: 0,
+                    total: 0,
+                    min: shmem::Duration::MAX,
+                    max: 0,
+                    buckets: [0; shmem::HISTOGRAM_BUCKETS]
+                });
+
+                self.histograms.last_mut().unwrap()
+            }
+        };
+
+        if hist.generation != generation {
+            //Recorded before a clear, start over
+            hist.generation = generation;
+            hist.count = 0;
+            hist.total = 0;
+            hist.min = shmem::Duration::MAX;
+            hist.max = 0;
+            hist.buckets = [0; shmem::HISTOGRAM_BUCKETS];
+        }
+
+        let bucket = &mut hist.buckets[shmem::ZoneHistogramData::bucket_of(duration)];
+
+        *bucket = bucket.saturating_add(1);
+        hist.end = hist.end.max(end);
+        hist.count += 1;
+        hist.total += duration;
+        hist.min = hist.min.min(duration);
+        hist.max = hist.max.max(duration);
+    }
+
+    //Histograms that don't fit are kept, and will be sent with the next flush
+    fn flush_histograms(&mut self, mem: &mut shmem::SharedMemoryData) {
+        let generation = core::generation();
+
+        if self.epoch != core::process_epoch() {
+            self.histograms.clear();
+        }
+
+        self.histograms.retain(|h| h.count > 0 && h.generation == generation);
+        let epoch = core::connection_epoch();
+
+        for hist in &mut self.histograms {
+            if mem.zone_histogram_data.push(hist) {
+                hist.info.name_sent.set_sent(epoch);
+
+                hist.count = 0;
+            }
+        }
+
+        self.histograms.retain(|h| h.count > 0);
+    }
+}
+
+//The thread is exiting (or, after a fork, starting over): send what's left, otherwise the
+//zones of short-lived threads would vanish with them
+#[cfg(feature = "enabled")]
+impl Drop for ThreadInfo {
+    fn drop(&mut self) {
+        if self.pending.is_empty() && self.histograms.is_empty() {
+            return;
+        }
+
+        unsafe {
+            if let Some((mem, _)) = core::get_shmem_data_and_start_time_ro() {
+                self.flush(mem);
+                self.flush_histograms(mem);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "enabled")]
+thread_local! {
+    static THREAD_INFO: RefCell<Option<ThreadInfo>> = RefCell::new(None);
+}
+
+///Keeps track of whether a name (of a zone, plot, frame set or thread) was sent to the server,
+///so that it's only copied into the shared memory once. After a reconnection, the new server
+///knows none of them and they are all sent again: the name was sent if it was during the
+///current `core::connection_epoch()`.
+pub(crate) struct NameSent(std::sync::atomic::AtomicU32); //Connection epoch of the last time it was sent, 0 if never
+
+impl NameSent {
+    pub const fn new() -> Self {
+        NameSent(std::sync::atomic::AtomicU32::new(0))
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl NameSent {
+    ///Whether the name should be copied with the next entry
+    #[inline]
+    pub fn copy_name(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::Relaxed) != core::connection_epoch()
+    }
+
+    ///An entry copying the name was pushed during `epoch`, read before pushing it: should a
+    ///reconnection happen meanwhile, the name is sent again
+    #[inline]
+    pub fn set_sent(&self, epoch: u32) {
+        self.0.store(epoch, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+///A zone declared at the call site. Every thread starting this zone shares it, so what is
+///resolved by the first instance (uid, color, whether the name was sent) is atomic.
+pub struct ZoneInfo {
+    uid: std::sync::atomic::AtomicUsize, //See `ZoneData::uid`, resolved by the first instance. 0 until then
+    color: std::sync::atomic::AtomicU32, //`Color::to_hex()`, may be `Color::AUTO` until the first instance
+    name: &'static str,
+    location: Option<&'static str>,
+    category: Option<&'static str>,
+    min_duration: shmem::Duration, //Instances shorter than this (in nanoseconds) aren't sent
+    aggregate: bool,               //Instances are accumulated into a histogram instead of being sent
+    name_sent: NameSent
+}
+
+impl ZoneInfo {
+    pub const fn new(color: shmem::Color, name: &'static str) -> Self {
+        Self {
+            uid: std::sync::atomic::AtomicUsize::new(0),
+            color: std::sync::atomic::AtomicU32::new(color.to_hex()),
+            name,
+            location: None,
+            category: None,
+            min_duration: 0,
+            aggregate: false,
+            name_sent: NameSent::new()
+        }
+    }
+
+    ///Sets the source location, typically `concat!(file!(), ":", line!())`
+    pub const fn with_location(self, location: &'static str) -> Self {
+        Self {
+            location: Some(location),
+            ..self
+        }
+    }
+
+    ///Groups the zone with others of the same category (e.g. "net", "io"), which the server
+    ///can filter or color on
+    pub const fn with_category(self, category: &'static str) -> Self {
+        Self {
+            category: Some(category),
+            ..self
+        }
+    }
+
+    ///Only sends the instances of this zone that last at least `min`. Shorter ones
+    ///still count for the call stack depth.
+    pub const fn with_min_duration(self, min: std::time::Duration) -> Self {
+        Self {
+            min_duration: min.as_nanos() as shmem::Duration,
+            ..self
+        }
+    }
+
+    ///Instead of sending each instance of this zone, accumulates their durations into a
+    ///per-thread histogram which is sent on `flush_thread()` (i.e. once per frame). Meant
+    ///for very hot zones, whose instances would be dropped anyway.
+    pub const fn aggregated(self) -> Self {
+        Self {
+            aggregate: true,
+            ..self
+        }
+    }
+
+    #[inline]
+    fn uid(&self) -> usize {
+        self.uid.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn color(&self) -> shmem::Color {
+        shmem::Color::from_raw(self.color.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    //Resolved once per call site, see `auto_color()`. Threads racing on it compute the same value.
+    #[cfg(feature = "enabled")]
+    fn resolve_color(&self, fallback: Color) {
+        if self.color() == Color::AUTO {
+            self.color.store(auto_color(self.name, fallback).to_hex(), std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+//What `Color::AUTO` stands for: a color derived from `name` if `set_auto_colors()` is on,
+//`fallback` otherwise
+#[cfg(feature = "enabled")]
+fn auto_color(name: &str, fallback: Color) -> Color {
+    if core::auto_colors() { Color::from_name(name) } else { fallback }
+}
+
+//`ZoneInfo`s of `start_zone_profiling_generic!()`, by call site and type. Leaked, as zones
+//can't outlive them. The global map is shared so that names are sent once for all threads.
+#[cfg(feature = "enabled")]
+type GenericZones = std::collections::HashMap<(usize, &'static str), usize>;
+
+#[cfg(feature = "enabled")]
+static GENERIC_ZONES: std::sync::Mutex<Option<GenericZones>> = std::sync::Mutex::new(None);
+
+#[cfg(feature = "enabled")]
+thread_local! {
+    static GENERIC_ZONE_CACHE: RefCell<GenericZones> = RefCell::new(GenericZones::new());
+}
+
+///`ZoneInfo` of the call site `site` of `start_zone_profiling_generic!()`, for the type `T`
+#[doc(hidden)]
+#[cfg(feature = "enabled")]
+pub fn __tl_generic_zone_info<T: ?Sized>(site: &'static ZoneInfo) -> &'static ZoneInfo {
+    let key = (site as *const ZoneInfo as usize, std::any::type_name::<T>());
+    let cached = GENERIC_ZONE_CACHE.with(|cache| cache.borrow().get(&key).copied());
+
+    let info = match cached {
+        Some(x) => x,
+        None    => {
+            let mut zones = GENERIC_ZONES.lock().unwrap_or_else(|e| e.into_inner());
+            let info = *zones.get_or_insert_with(GenericZones::new).entry(key).or_insert_with(|| {
+                let mut name = format!("{}<{}>", site.name, key.1);
+                let mut len = name.len().min(shmem::SHARED_STRING_MAX_SIZE);
+
+                while !name.is_char_boundary(len) {
+                    len -= 1;
+                }
+
+                name.truncate(len);
+
+                let info = ZoneInfo {
+                    uid: std::sync::atomic::AtomicUsize::new(0),
+                    color: std::sync::atomic::AtomicU32::new(site.color().to_hex()),
+                    name: Box::leak(name.into_boxed_str()),
+                    location: site.location,
+                    category: site.category,
+                    min_duration: site.min_duration,
+                    aggregate: site.aggregate,
+                    name_sent: NameSent::new()
+                };
+
+                Box::leak(Box::new(info)) as *const ZoneInfo as usize
+            });
+
+            let _ = GENERIC_ZONE_CACHE.try_with(|cache| cache.borrow_mut().insert(key, info));
+            info
+        }
+    };
+
+    unsafe { &*(info as *const ZoneInfo) }
+}
+
+///A plot declared at the call site (see `frame_plot!()`)
+pub struct PlotInfo {
+    color: std::sync::atomic::AtomicU32, //Same as `ZoneInfo::color`
+    name: &'static str,
+    name_sent: NameSent
+}
+
+impl PlotInfo {
+    pub const fn new(color: shmem::Color, name: &'static str) -> Self {
+        Self { color: std::sync::atomic::AtomicU32::new(color.to_hex()), name, name_sent: NameSent::new() }
+    }
+
+    #[inline]
+    fn color(&self) -> shmem::Color {
+        shmem::Color::from_raw(self.color.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    //Resolved once per call site, like zones
+    #[cfg(feature = "enabled")]
+    fn resolve_color(&self, fallback: Color) {
+        if self.color() == Color::AUTO {
+            self.color.store(auto_color(self.name, fallback).to_hex(), std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "enabled")]
+struct PendingPlot {
+    info: &'static PlotInfo,
+    time: shmem::Time,
+    value: f64
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::PlotData> for PendingPlot {
+    fn write_into(&self, target: &mut shmem::PlotData) {
+        let info = self.info;
+
+        target.time = self.time;
+        target.color = info.color();
+        target.set_value(self.value);
+        target.name.set(info.name, info.name_sent.copy_name());
+    }
+}
+
+#[cfg(feature = "enabled")]
+struct TimeData {
+    start: shmem::Time,
+    end: shmem::Time,
+    duration: shmem::Duration,
+    cpu_duration: shmem::Duration
+}
+
+///A zone that ended but that is still waiting in the thread's batch. Only built once the zone
+///ended, so `time_data` is always complete: `Zone` itself is never written to the shared memory.
+#[cfg(feature = "enabled")]
+struct PendingZone {
+    info: &'static ZoneInfo,
+    time_data: TimeData,
+    thread_id: u64,
+    depth: u32,
+    instance: u64
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::ZoneData> for PendingZone {
+    fn write_into(&self, target: &mut shmem::ZoneData) {
+        self.write_named(target, self.info.name_sent.copy_name());
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl PendingZone {
+    //Same as `write_into()`, for segments that don't go by the global `NameSent` (see `Context`)
+    fn write_named(&self, target: &mut shmem::ZoneData, copy_name: bool) {
+        let info = self.info;
+
+        target.uid = info.uid();
+        target.color = info.color();
+        target.start = self.time_data.start;
+        target.end = self.time_data.end;
+        target.duration = self.time_data.duration;
+        target.cpu_duration = self.time_data.cpu_duration;
+        target.depth = self.depth;
+        target.instance = self.instance;
+        target.name.set(info.name, copy_name);
+        target.thread = self.thread_id as usize;
+
+        match info.location {
+            Some(location) => target.loc.set_tail(location, copy_name),
+            None           => target.loc.set_special(0, None)
+        }
+
+        match info.category {
+            Some(category) => target.category.set(category, copy_name),
+            None           => target.category.set_special(0, None)
+        }
+    }
+}
+
+#[cfg(feature = "enabled")]
+pub struct Zone {
+    info: &'static ZoneInfo,
+    start: Option<clock::Timestamp>, //None if profiling was disabled when the zone was created
+    thread_id: u64,
+    depth: u32,
+    instance: u64,
+    target: Option<&'static Context>, //None for the global context
+    tracked: bool,                    //Whether the call site was pushed to `ThreadInfo::open_zones`
+    suppressed: bool,                 //Too deep into recursion (see `set_max_recursion()`), not sent
+    capped: bool,                     //Deeper than `set_max_depth()`, not sent but counted in `depth_capped`
+
+    #[cfg(feature = "cpu-time")]
+    cpu_start: u64                    //`clock::thread_cpu_time()` when the zone started
+}
+
+#[cfg(feature = "enabled")]
+struct ZoneText<'a> {
+    thread_id: u64,
+    instance: u64,
+    text: &'a str
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::ZoneTextData> for ZoneText<'_> {
+    fn write_into(&self, target: &mut shmem::ZoneTextData) {
+        target.thread = self.thread_id as usize;
+        target.instance = self.instance;
+        target.text.set_copy(0, self.text);
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl Zone {
+    pub fn new(info: &'static ZoneInfo) -> Self {
+        Self::start(info, None)
+    }
+
+    ///Same as `new()`, but the zone is sent to `target` instead of the global context
+    pub fn new_to(target: &'static Context, info: &'static ZoneInfo) -> Self {
+        Self::start(info, Some(target))
+    }
+
+    fn start(info: &'static ZoneInfo, target: Option<&'static Context>) -> Self {
+        if !core::is_enabled() {
+            return Self {
+                info,
+                start: None,
+                thread_id: 0,
+                depth: 0,
+                instance: 0,
+                target,
+                tracked: false,
+                suppressed: false,
+                capped: false,
+
+                #[cfg(feature = "cpu-time")]
+                cpu_start: 0
+            };
+        }
+
+        info.resolve_color(colors::ORANGE);
+
+        //Stable across runs if the location is known, so that captures can be compared
+        if info.uid() == 0 {
+            let uid = match info.location {
+                Some(location) => shmem::stable_uid(info.name, location),
+                None           => info as *const ZoneInfo as usize
+            };
+
+            info.uid.store(uid, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        let site = info as *const ZoneInfo;
+        let max_recursion = core::max_recursion();
+        let max_depth = core::max_depth();
+
+        let (thread_id, depth, instance, tracked, suppressed) = THREAD_INFO.with(|ti| {
+            let mut borrowed = ti.borrow_mut();
+            let ti = ThreadInfo::get_or_init(&mut borrowed);
+            let depth = ti.depth;
+            let instance = ti.next_instance;
+
+            ti.depth = ti.depth.saturating_add(1);
+            ti.next_instance += 1;
+
+            //Depth keeps counting suppressed zones, so that the zones sent still report their actual depth
+            let (tracked, suppressed) = if max_recursion > 0 {
+                let level = ti.open_zones.iter().filter(|&&x| x == site).count() as u32;
+                ti.open_zones.push(site);
+
+                (true, level >= max_recursion)
+            } else {
+                (false, false)
+            };
+
+            (ti.id, depth, instance, tracked, suppressed)
+        });
+
+        #[cfg(feature = "cpu-time")]
+        let cpu_start = clock::thread_cpu_time();
+
+        let capped = depth >= max_depth;
+        let start = Some(clock::now());
+
+        Self {
+            info, start,
+            thread_id, depth, instance,
+            target, tracked, suppressed, capped,
+
+            #[cfg(feature = "cpu-time")]
+            cpu_start
+        }
+    }
+
+    ///Attaches runtime text (e.g. the file being loaded) to this zone. It is sent right
+    ///away as a `ZoneTextData` that the server matches with the zone using its thread and
+    ///instance number. Texts longer than `SHARED_STRING_MAX_SIZE` bytes are truncated.
+    ///Returns false if the text couldn't be sent.
+    pub fn text(&self, text: &str) -> bool {
+        if self.start.is_none() || !core::is_enabled() {
+            return false;
+        }
+
+        let entry = ZoneText {
+            thread_id: self.thread_id,
+            instance: self.instance,
+            text
+        };
+
+        if let Some(target) = self.target {
+            return target.push_zone_text(&entry);
+        }
+
+        match unsafe { core::get_shmem_data_and_start_time() } {
+            (Some(mem), _) => mem.zone_text_data.push(&entry),
+            _              => false
+        }
+    }
+
+    pub fn end(self) {
+        //Same as drop(zone)
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl Drop for Zone {
+    fn drop(&mut self) {
+        let start = match self.start {
+            Some(x) => x,
+            None    => return //Nothing was done in `Zone::new()`, nothing to undo
+        };
+
+        let end = clock::now();
+
+        #[cfg(feature = "cpu-time")]
+        let cpu_duration = clock::thread_cpu_time().saturating_sub(self.cpu_start);
+
+        #[cfg(not(feature = "cpu-time"))]
+        let cpu_duration = 0;
+
+        if let Some(target) = self.target {
+            let duration = clock::nanos_between(start, end);
+
+            if self.capped {
+                if core::is_enabled() {
+                    unsafe { (*target.data()).depth_capped.fetch_add(1, std::sync::atomic::Ordering::Relaxed); }
+                }
+            } else if core::is_enabled() && !self.suppressed && duration >= self.info.min_duration {
+                THREAD_INFO.with(|ti| ti.borrow_mut().as_mut().unwrap().send_name_to(target));
+                target.push_zone(&PendingZone {
+                    info: self.info,
+                    time_data: TimeData {
+                        start: target.timeline_time(start),
+                        end: target.timeline_time(end),
+                        duration, cpu_duration
+                    },
+                    thread_id: self.thread_id,
+                    depth: self.depth,
+                    instance: self.instance
+                });
+            }
+
+            THREAD_INFO.with(|ti| ti.borrow_mut().as_mut().unwrap().leave(self.tracked));
+            return;
+        }
+
+        unsafe {
+            //TODO: Maybe we can "cache" shmem and start_time in the THREAD_INFO,
+            //which is thread local. This would probably result in faster code.
+            let (opt_mem, start_time) = if core::is_enabled() {
+                core::get_shmem_data_and_start_time()
+            } else {
+                (None, start) //Profiling was disabled in the meantime; still need to fix the depth
+            };
+
+            THREAD_INFO.with(|ti| {
+                let mut borrowed = ti.borrow_mut();
+                let ti = borrowed.as_mut().unwrap();
+
+                let duration = clock::nanos_between(start, end);
+
+                if let (true, Some(mem)) = (self.capped, opt_mem.as_deref()) {
+                    mem.depth_capped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+
+                let opt_mem = opt_mem.filter(|_| !self.capped && !self.suppressed && duration >= self.info.min_duration);
+
+                if self.info.aggregate {
+                    //No need for sampling here, histograms are cheap
+                    if opt_mem.is_some() {
+                        ti.record_aggregate(self.info, core::timeline_time(start_time, end), duration);
+                    }
+                } else if let Some(mem) = opt_mem.filter(|mem| ti.should_record(mem)) {
+                    let time_data = TimeData {
+                        start: core::timeline_time(start_time, start),
+                        end: core::timeline_time(start_time, end),
+                        duration, cpu_duration
+                    };
+
+                    if ti.pending.is_empty() {
+                        ti.pending_generation = core::generation();
+                    }
+
+                    let should_flush = ti.pending.first().map(|x| time_data.end.saturating_sub(x.time_data.end) >= ZONE_BATCH_MAX_AGE).unwrap_or(false);
+
+                    ti.pending.push(PendingZone {
+                        info: self.info,
+                        time_data,
+                        thread_id: self.thread_id,
+                        depth: self.depth,
+                        instance: self.instance
+                    });
+
+                    if should_flush || ti.pending.len() >= ZONE_BATCH_SIZE {
+                        ti.flush(mem);
+                    }
+                }
+
+                ti.leave(self.tracked);
+            });
+        }
+    }
+}
+
+///Sends the zones buffered by the current thread to the server
+#[cfg(feature = "enabled")]
+pub fn flush_thread() {
+    unsafe {
+        if let Some((mem, _)) = core::get_shmem_data_and_start_time_ro() {
+            let _ = THREAD_INFO.try_with(|ti| {
+                if let Some(ti) = ti.borrow_mut().as_mut() {
+                    ti.flush(mem);
+                    ti.flush_histograms(mem);
+                }
+            });
+        }
+    }
+}
+
+///Sends everything the current thread still has to send. Call this before exiting
+///your program, otherwise the last zones might never make it to the server. Other threads
+///don't need it as they do so when they exit, but the main thread may never get the chance.
+#[cfg(feature = "enabled")]
+pub fn shutdown() {
+    flush_thread();
+}
+
+///Names the current thread in the profiler, overriding the name given to `std::thread::Builder`.
+///Can also be called again to rename it. The name goes to the thread table right away if the
+///server is connected, otherwise along with the first zones of this thread.
+#[cfg(feature = "enabled")]
+pub fn register_thread(name: &str) {
+    THREAD_INFO.with(|ti| {
+        let mut borrowed = ti.borrow_mut();
+        let ti = ThreadInfo::get_or_init(&mut borrowed);
+
+        ti.name = name.to_string();
+        ti.name_sent = NameSent::new();
+        ti.named_contexts.clear();
+
+        if core::is_enabled() {
+            if let Some((mem, _)) = unsafe { core::get_shmem_data_and_start_time_ro() } {
+                ti.send_name(mem);
+            }
+        }
+    });
+}
+
+///Makes the workers of a `rayon` pool register themselves as `rayon-worker-N` (see
+///`register_thread()`) as soon as they start, instead of showing up as anonymous threads:
+///
+///`temporal_lens::name_rayon_workers(rayon::ThreadPoolBuilder::new()).build_global()`
+///
+///This replaces any `start_handler` set on `builder` before.
+#[cfg(all(feature = "enabled", feature = "rayon"))]
+pub fn name_rayon_workers<S>(builder: rayon::ThreadPoolBuilder<S>) -> rayon::ThreadPoolBuilder<S> {
+    builder.start_handler(|index| register_thread(&format!("rayon-worker-{}", index)))
+}
+
+///Sends a log message to the server, at the `Info` level. Returns false if it couldn't be sent
+///entirely, for instance because the log buffer is full; what fitted is still sent, flagged as
+///truncated. The message is timed in nanoseconds on the timeline of the global context, like
+///zones; one longer than `shmem::LOG_CHUNK_SIZE` is sent in parts, and concurrent loggers take turns
+///on the lock of the log buffer.
+#[cfg(feature = "enabled")]
+pub fn send_log(msg: &str, color: Color) -> bool {
+    send_log_level(msg, LogLevel::Info, color)
+}
+
+///Same as `send_log()`, with the arguments in the order of `tl_log!()`, which forwards to it
+#[cfg(feature = "enabled")]
+pub fn log_message(color: Color, msg: &str) -> bool {
+    send_log(msg, color)
+}
+
+///Same as `send_log()`, with the given severity, which the server can filter on. With
+///`Color::AUTO`, the message gets the color of its level (see `LogLevel::default_color()`).
+#[cfg(feature = "enabled")]
+pub fn send_log_level(msg: &str, level: LogLevel, color: Color) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    let now = clock::now();
+
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), start_time) => mem.push_log(core::timeline_time(start_time, now), level, color, msg),
+        _                       => false
+    }
+}
+
+///Same as `send_log()`, with key/value fields (e.g. `&[("request_id", id), ("user", name)]`)
+///that the server can show as columns or filter on, see `shmem::LOG_FIELDS`
+#[cfg(feature = "enabled")]
+pub fn send_log_kv(msg: &str, fields: &[(&str, &str)], color: Color) -> bool {
+    send_log_kv_level(msg, fields, LogLevel::Info, color)
+}
+
+///Same as `send_log_kv()`, with the given severity (see `send_log_level()`)
+#[cfg(feature = "enabled")]
+pub fn send_log_kv_level(msg: &str, fields: &[(&str, &str)], level: LogLevel, color: Color) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    let now = clock::now();
+
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), start_time) => mem.push_log_kv(core::timeline_time(start_time, now), level, color, msg, fields),
+        _                       => false
+    }
+}
+
+///Converts a timestamp to the time used by the `*_at()` functions: nanoseconds on the timeline
+///of the global context, i.e. since the program started (or since the last clear/reset)
+#[cfg(feature = "enabled")]
+pub fn timeline_time(t: clock::Timestamp) -> u64 {
+    let (_, start_time) = unsafe { core::get_shmem_data_and_start_time() };
+    core::timeline_time(start_time, t)
+}
+
+///Current time on the timeline, see `timeline_time()`
+#[cfg(feature = "enabled")]
+pub fn timeline_now() -> u64 {
+    timeline_time(clock::now())
+}
+
+///A plot whose name is built at runtime, so it can't be keyed by its address like the others:
+///`key` is used instead, and should be derived from the name (see `shmem::stable_uid()`)
+#[cfg(feature = "enabled")]
+pub(crate) struct DynamicPlot<'a> {
+    pub time: shmem::Time,
+    pub color: Color,
+    pub value: f64,
+    pub key: usize,
+    pub name: &'a str,
+    pub copy_name: bool
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::PlotData> for DynamicPlot<'_> {
+    fn write_into(&self, target: &mut shmem::PlotData) {
+        target.time = self.time;
+        target.color = self.color;
+        target.set_value(self.value);
+
+        if self.copy_name {
+            //The address of a name that may be freed is exactly what can't be a key
+            debug_assert!(self.key != self.name.as_ptr() as usize || self.name.is_empty(), "the key of a dynamic plot can't be the address of its name");
+            target.name.set_copy(self.key, self.name);
+        } else {
+            target.name.set_special(self.key, None);
+        }
+    }
+}
+
+#[cfg(feature = "enabled")]
+struct ImportedPlot {
+    time: shmem::Time,
+    color: Color,
+    value: f64,
+    name: &'static str
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::PlotData> for ImportedPlot {
+    fn write_into(&self, target: &mut shmem::PlotData) {
+        target.time = self.time;
+        target.color = self.color;
+        target.set_value(self.value);
+        target.name.set(self.name, true);
+    }
+}
+
+///Sends a plot value at an explicit `time` (see `timeline_time()`) instead of now, e.g. to
+///import values measured elsewhere. The name is copied each time, and must fit in
+///`SHARED_STRING_MAX_SIZE` bytes. Returns false if the value couldn't be sent.
+#[cfg(feature = "enabled")]
+pub fn submit_plot_at(name: &'static str, value: f64, color: Color, time: u64) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), _) => mem.plot_data.push(&ImportedPlot { time, color, value, name }),
+        _              => false
+    }
+}
+
+//A `PlotMetaData` record, see `set_plot_color()`
+#[cfg(feature = "enabled")]
+struct PlotMeta {
+    time: shmem::Time,
+    color: Color,
+    name: &'static str
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::PlotMetaData> for PlotMeta {
+    fn write_into(&self, target: &mut shmem::PlotMetaData) {
+        target.time = self.time;
+        target.color = self.color;
+        target.name.set(self.name, true);
+    }
+}
+
+///Sets the color of the plot `name`. Servers keep the first color they see for each plot, so
+///that a series never changes colors from one sample to the next (e.g. the heap tracker and a
+///user plot sharing a name); this is how to change it later on. Returns false if the record
+///couldn't be sent.
+#[cfg(feature = "enabled")]
+pub fn set_plot_color(name: &'static str, color: Color) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), _) => mem.plot_meta_data.push(&PlotMeta { time: timeline_now(), color, name }),
+        _              => false
+    }
+}
+
+#[cfg(feature = "enabled")]
+struct ImportedZone {
+    name: &'static str,
+    track: &'static str,
+    color: Color,
+    start: shmem::Time,
+    end: shmem::Time,
+    depth: u32
+}
+
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::ZoneData> for ImportedZone {
+    fn write_into(&self, target: &mut shmem::ZoneData) {
+        target.uid = shmem::stable_uid(self.name, self.track);
+        target.color = self.color;
+        target.start = self.start;
+        target.end = self.end;
+        target.duration = self.end - self.start;
+        target.cpu_duration = 0;
+        target.depth = self.depth;
+        target.instance = 0;
+        target.name.set(self.name, true);
+        target.thread = track_id(self.track);
+        target.loc.set_special(0, None);
+        target.category.set_special(0, None);
+    }
+}
+
+///Sends a zone that ran from `start` to `end` (see `timeline_time()`), measured by other means
+///(GPU queries, external traces...). It shows up in its own `track`, named like a thread, at
+///the given `depth`; zones of the same track should be properly nested. Strings are copied
+///each time and must fit in `SHARED_STRING_MAX_SIZE` bytes. Returns false if the zone couldn't
+///be sent, or if `end` is before `start`.
+#[cfg(feature = "enabled")]
+pub fn submit_zone_at(name: &'static str, color: Color, track: &'static str, start: u64, end: u64, depth: u32) -> bool {
+    if !core::is_enabled() || end < start {
+        return false;
+    }
+
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), _) => mem.thread_table.set(track_id(track), track) && mem.zone_data.push(&ImportedZone { name, track, color, start, end, depth }),
+        _              => false
+    }
+}
+
+//Tracks of imported zones are named like threads, with IDs that can't be mistaken for real ones
+#[cfg(feature = "enabled")]
+fn track_id(track: &str) -> usize {
+    shmem::stable_uid(track, "track")
+}
+
+///`fmt::Write` into a fixed buffer, silently cutting what doesn't fit
+#[cfg(feature = "enabled")]
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize
+}
+
+#[cfg(feature = "enabled")]
+impl std::fmt::Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let mut len = s.len().min(self.buffer.len() - self.len);
+
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[0..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "enabled")]
+fn report_panic(info: &std::panic::PanicInfo) {
+    use std::fmt::Write;
+
+    //Only if the shared memory is already open: taking locks or opening it here could go wrong
+    let (mem, start_time) = match unsafe { core::get_shmem_data_and_start_time_ro() } {
+        Some(x) if core::is_enabled_no_env() => x,
+        _                                    => return
+    };
+
+    let now = clock::now();
+    let mut buffer = [0u8; 512];
+    let mut writer = TruncatingWriter { buffer: &mut buffer, len: 0 };
+    let payload = info.payload();
+    let msg = payload.downcast_ref::<&str>().copied().or_else(|| payload.downcast_ref::<String>().map(|x| x.as_str()));
+
+    let _ = write!(writer, "thread '{}' panicked", std::thread::current().name().unwrap_or("<unnamed>"));
+
+    if let Some(msg) = msg {
+        let _ = write!(writer, " at '{}'", msg);
+    }
+
+    if let Some(loc) = info.location() {
+        let _ = write!(writer, ", {}:{}:{}", loc.file(), loc.line(), loc.column());
+    }
+
+    let len = writer.len;
+
+    //The zones that led to the panic are still pending
+    let _ = THREAD_INFO.try_with(|ti| {
+        if let Ok(mut borrowed) = ti.try_borrow_mut() {
+            if let Some(ti) = borrowed.as_mut() {
+                ti.flush(mem);
+            }
+        }
+    });
+
+    mem.push_log(core::timeline_time(start_time, now), LogLevel::Error, default_colors!(red), unsafe { std::str::from_utf8_unchecked(&buffer[0..len]) });
+}
+
+///Chains onto the current panic hook so that panics also show up as red log messages
+///(with the thread name, payload and location) in the profiler. Messages are cut at 512 bytes.
+#[cfg(feature = "enabled")]
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        report_panic(info);
+        previous(info);
+    }));
+}
+
+///The named colors of the macros. With the `colorblind` feature, they come from the Okabe-Ito
+///palette instead, which stays distinguishable with the common forms of color blindness.
+pub mod colors {
+    use crate::Color;
+
+    #[cfg(not(feature = "colorblind"))] pub const BLUE: Color   = Color::from_hex(0x0061afef);
+    #[cfg(not(feature = "colorblind"))] pub const ORANGE: Color = Color::from_hex(0x00d19a66);
+    #[cfg(not(feature = "colorblind"))] pub const PURPLE: Color = Color::from_hex(0x00c678dd);
+    #[cfg(not(feature = "colorblind"))] pub const GREEN: Color  = Color::from_hex(0x0098c379);
+    #[cfg(not(feature = "colorblind"))] pub const RED: Color    = Color::from_hex(0x00e06c75);
+    #[cfg(not(feature = "colorblind"))] pub const CYAN: Color   = Color::from_hex(0x0056b6c2);
+
+    #[cfg(feature = "colorblind")] pub const BLUE: Color   = Color::from_hex(0x000072b2);
+    #[cfg(feature = "colorblind")] pub const ORANGE: Color = Color::from_hex(0x00e69f00);
+    #[cfg(feature = "colorblind")] pub const PURPLE: Color = Color::from_hex(0x00cc79a7);
+    #[cfg(feature = "colorblind")] pub const GREEN: Color  = Color::from_hex(0x00009e73);
+    #[cfg(feature = "colorblind")] pub const RED: Color    = Color::from_hex(0x00d55e00);
+    #[cfg(feature = "colorblind")] pub const CYAN: Color   = Color::from_hex(0x0056b4e9);
+
+    ///What zones declared without a color get, see `Color::AUTO`
+    pub const AUTO: Color = Color::AUTO;
+}
+
+//The constants are resolved in this crate, so the `colorblind` feature applies to the callers' macros too
+#[macro_export]
+macro_rules! default_colors {
+    (blue)   => { $crate::colors::BLUE };
+    (orange) => { $crate::colors::ORANGE };
+    (purple) => { $crate::colors::PURPLE };
+    (green)  => { $crate::colors::GREEN };
+    (red)    => { $crate::colors::RED };
+    (cyan)   => { $crate::colors::CYAN };
+    (auto)   => { $crate::colors::AUTO };
+
+    //Anything else has to be a `Color` constant in scope, for instance from `palette!`
+    ($other:ident) => { $other };
+}
+
+///`ZoneInfo` of a zone declared at the call site, which the macros complete with their options.
+///The name goes through a `const` so that it can be any constant `&'static str` expression.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __tl_zone_info {
+    ($color:expr, $name:expr) => {
+        $crate::ZoneInfo::new($color, { const __TL_ZONE_NAME: &str = $name; __TL_ZONE_NAME }).with_location(concat!(file!(), ":", line!()))
+    };
+}
+
+///Builds a `Color` in a const context, e.g. `color: rgb!(200, 120, 60)`
+#[macro_export]
+macro_rules! rgb {
+    ($r:expr, $g:expr, $b:expr) => { $crate::Color::from_rgb($r, $g, $b) };
+}
+
+///Declares `Color` constants that the macros accept by name, just like the default colors
+///(which take precedence):
+///
+///```ignore
+///temporal_lens::palette! {
+///    pub brand_primary = rgb!(200, 120, 60),
+///    brand_dark = Color::from_hex(0x202020)
+///}
+///
+///profile_scope!("request", color: brand_primary);
+///```
+#[macro_export]
+macro_rules! palette {
+    ($($vis:vis $name:ident = $color:expr),* $(,)?) => {
+        $(
+            #[allow(non_upper_case_globals)]
+            $vis const $name: $crate::Color = $color;
+        )*
+    };
+}
+
+///Starts a zone that ends when the returned `Zone` is dropped (or ended), e.g.
+///`let zone = start_zone_profiling!("load", color: blue);`
+///
+///The name can be any constant `&'static str` expression, such as `concat!(module_path!(), "::load")`
+///or a `const`; names built at runtime aren't supported.
+///
+///Note that each call site has a single `static` describing the zone, and that statics are
+///shared by all the instantiations of a generic function: every `fn f<T>()` is the same zone.
+///Use `start_zone_profiling_generic!()` to tell them apart.
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! start_zone_profiling {
+    ($name:expr, color: $color:literal) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::Color::from_hex($color), $name);
+        $crate::Zone::new(&__TL_ZONE_INFO)
+    }};
+
+    ($name:expr, color: $color:ident) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::default_colors!($color), $name);
+        $crate::Zone::new(&__TL_ZONE_INFO)
+    }};
+
+    ($name:expr, color: $color:expr) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($color, $name);
+        $crate::Zone::new(&__TL_ZONE_INFO)
+    }};
+
+    ($name:expr) => {
+        $crate::start_zone_profiling!($name, color: auto)
+    };
+}
+
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:expr, color: $color:literal) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+    };
+
+    ($name:expr, color: $color:ident) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+    };
+
+    ($name:expr, color: $color:expr) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+    };
+
+    ($name:expr) => {
+        $crate::profile_scope!($name, color: auto);
+    };
+}
+
+///Same as `start_zone_profiling!()`, but with a zone per type `$t`, named `name<type>`, for
+///generic functions, e.g. `start_zone_profiling_generic!("parse", T)` in `fn parse<T>()`. The
+///zone is looked up at runtime (in a per-thread hash map), so this costs a bit more.
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! start_zone_profiling_generic {
+    ($name:expr, $t:ty, color: $color:literal) => {{
+        static __TL_ZONE_SITE: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::Color::from_hex($color), $name);
+        $crate::Zone::new($crate::__tl_generic_zone_info::<$t>(&__TL_ZONE_SITE))
+    }};
+
+    ($name:expr, $t:ty, color: $color:ident) => {{
+        static __TL_ZONE_SITE: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::default_colors!($color), $name);
+        $crate::Zone::new($crate::__tl_generic_zone_info::<$t>(&__TL_ZONE_SITE))
+    }};
+
+    ($name:expr, $t:ty, color: $color:expr) => {{
+        static __TL_ZONE_SITE: $crate::ZoneInfo = $crate::__tl_zone_info!($color, $name);
+        $crate::Zone::new($crate::__tl_generic_zone_info::<$t>(&__TL_ZONE_SITE))
+    }};
+
+    ($name:expr, $t:ty) => {
+        $crate::start_zone_profiling_generic!($name, $t, color: auto)
+    };
+}
+
+///Same as `profile_scope!()`, with a zone per type (see `start_zone_profiling_generic!()`)
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! profile_scope_generic {
+    ($name:expr, $t:ty, color: $color:literal) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_generic!($name, $t, color: $color);
+    };
+
+    ($name:expr, $t:ty, color: $color:ident) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_generic!($name, $t, color: $color);
+    };
+
+    ($name:expr, $t:ty, color: $color:expr) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_generic!($name, $t, color: $color);
+    };
+
+    ($name:expr, $t:ty) => {
+        $crate::profile_scope_generic!($name, $t, color: auto);
+    };
+}
+
+///Profiles the evaluation of an expression and returns its value, e.g.
+///`let rows = profile_expr!("db_query", run_query());`
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! profile_expr {
+    ($name:expr, color: $color:literal, $e:expr) => {{
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+        let __tl_value = $e;
+
+        __tl_profiling_zone.end();
+        __tl_value
+    }};
+
+    ($name:expr, color: $color:ident, $e:expr) => {{
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+        let __tl_value = $e;
+
+        __tl_profiling_zone.end();
+        __tl_value
+    }};
+
+    ($name:expr, color: $color:expr, $e:expr) => {{
+        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+        let __tl_value = $e;
+
+        __tl_profiling_zone.end();
+        __tl_value
+    }};
+
+    ($name:expr, $e:expr) => {
+        $crate::profile_expr!($name, color: auto, $e)
+    };
+}
+
+///Same as `profile_scope!`, but instances shorter than `$min` (a `std::time::Duration`)
+///are not sent, e.g. `profile_scope_min!("name", Duration::from_micros(100), color: red)`
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! profile_scope_min {
+    ($name:expr, $min:expr, color: $color:literal) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::Color::from_hex($color), $name).with_min_duration($min);
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr, $min:expr, color: $color:ident) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::default_colors!($color), $name).with_min_duration($min);
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr, $min:expr, color: $color:expr) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($color, $name).with_min_duration($min);
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr, $min:expr) => {
+        $crate::profile_scope_min!($name, $min, color: auto);
+    };
+}
+
+///Same as `profile_scope!`, but for an aggregate zone (see `ZoneInfo::aggregated()`)
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! profile_scope_aggregate {
+    ($name:expr, color: $color:literal) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::Color::from_hex($color), $name).aggregated();
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr, color: $color:ident) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::default_colors!($color), $name).aggregated();
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr, color: $color:expr) => {
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($color, $name).aggregated();
+        let __tl_profiling_zone = $crate::Zone::new(&__TL_ZONE_INFO);
+    };
+
+    ($name:expr) => {
+        $crate::profile_scope_aggregate!($name, color: auto);
+    };
 }
 
-thread_local! {
-    static THREAD_INFO: RefCell<Option<ThreadInfo>> = RefCell::new(None);
+#[cfg(feature = "enabled")]
+struct FrameInfo {
+    number: u64,
+    start: shmem::Time,
+    end: shmem::Time,
+    duration: shmem::Duration,
+    gpu_present: shmem::Time,
+    set: Option<(&'static str, bool)>
 }
 
-pub struct ZoneInfo {
-    color: shmem::Color,
-    name: &'static str,
-    copy_name: bool
+#[cfg(feature = "enabled")]
+impl shmem::WriteInto<shmem::FrameData> for FrameInfo {
+    fn write_into(&self, target: &mut shmem::FrameData) {
+        target.number = self.number;
+        target.start = self.start;
+        target.end = self.end;
+        target.duration = self.duration;
+        target.gpu_present = self.gpu_present;
+
+        match self.set {
+            Some((name, copy_name)) => target.set.set(name, copy_name),
+            None                    => target.set.set_special(0, None)
+        }
+    }
 }
 
-impl ZoneInfo {
-    pub const fn new(color: shmem::Color, name: &'static str) -> Self {
-        Self {
-            color, name,
-            copy_name: true
+#[cfg(feature = "enabled")]
+unsafe fn push_frame_info(num: u64, set: Option<(&'static str, bool)>, start: Option<clock::Timestamp>, end: clock::Timestamp, gpu_present: shmem::Time) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    //New frame, good time to send what this thread (and signal handlers) have been buffering
+    flush_thread();
+    signal::flush_signal_queue();
+
+    let (opt_mem, start_time) = core::get_shmem_data_and_start_time();
+
+    if let Some(mem) = opt_mem {
+        if !core::check_connection(mem) {
+            return false;
+        }
+
+        mem.client_heartbeat();
+
+        if set.is_none() {
+            flush_frame_plots(mem, core::timeline_time(start_time, end));
+        }
+
+        let entry = FrameInfo {
+            number: num,
+            start: core::timeline_time(start_time, start.unwrap_or(start_time)),
+            end: core::timeline_time(start_time, end),
+            duration: clock::nanos_between(start.unwrap_or(start_time), end),
+            gpu_present, set
+        };
+
+        let ok = mem.frame_data.push(&entry);
+
+        if !ok {
+            mem.dropped_frames.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         }
+
+        mem.notify_data();
+
+        ok
+    } else {
+        false
     }
 }
 
-struct TimeData {
-    end: shmem::Time,
-    duration: shmem::Duration
+//Sends the values of `frame_plot!()`, all at the time of the frame that just ended
+#[cfg(feature = "enabled")]
+fn flush_frame_plots(mem: &mut shmem::SharedMemoryData, time: shmem::Time) {
+    let epoch = core::connection_epoch();
+    let _ = THREAD_INFO.try_with(|ti| {
+        if let Some(ti) = ti.borrow_mut().as_mut() {
+            for (info, value) in ti.frame_plots.drain(..) {
+                if mem.plot_data.push(&PendingPlot { info, time, value }) {
+                    info.name_sent.set_sent(epoch);
+                }
+            }
+        }
+    });
 }
 
-pub struct Zone {
-    info: &'static mut ZoneInfo,
-    start: Instant,
-    time_data: MaybeUninit<TimeData>,
-    thread_id: u64,
-    thread_name: Option<(*const u8, usize)>,
-    depth: u32
+///Records a value for the plot `info`, sent at the end of the current frame (i.e. by the next
+///`frame_delimiter!()` of this thread) with the frame's timestamp. Only the last value given
+///during a frame is kept. Use `frame_plot!()` instead of calling this directly.
+#[cfg(feature = "enabled")]
+pub fn frame_plot(info: &'static PlotInfo, value: f64) {
+    if !core::is_enabled() {
+        return;
+    }
+
+    info.resolve_color(colors::ORANGE);
+
+    THREAD_INFO.with(|ti| {
+        let mut borrowed = ti.borrow_mut();
+        let ti = ThreadInfo::get_or_init(&mut borrowed);
+
+        //By name, as a plot can be fed from several call sites
+        match ti.frame_plots.iter_mut().find(|(x, _)| x.name == info.name) {
+            Some(entry) => entry.1 = value,
+            None        => ti.frame_plots.push((info, value))
+        }
+    });
 }
 
-impl Zone {
-    pub fn new(info: &'static mut ZoneInfo) -> Self {
-        let (thread_id, thread_name, depth) = THREAD_INFO.with(|ti| {
-            let mut borrowed = ti.borrow_mut();
+///Sends a value of the plot `info` right away, timestamped now. Returns false if it couldn't
+///be sent (e.g. `plot_data` was full). Use `plot!()` instead of calling this directly.
+#[cfg(feature = "enabled")]
+pub fn send_plot(info: &'static PlotInfo, value: f64) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
 
-            if borrowed.is_none() {
-                let actual_ti = std::thread::current();
+    info.resolve_color(colors::BLUE);
 
-                *borrowed = Some(ThreadInfo {
-                    id: actual_ti.id().as_u64().get(),
-                    name: actual_ti.name().unwrap_or("").to_string(),
-                    name_sent: false,
-                    depth: 0
-                });
+    match unsafe { core::get_shmem_data_and_start_time() } {
+        (Some(mem), start_time) => {
+            let time = core::timeline_time(start_time, clock::now());
+            let epoch = core::connection_epoch();
+            let ok = mem.plot_data.push(&PendingPlot { info, time, value });
+
+            if ok {
+                info.name_sent.set_sent(epoch);
             }
 
-            let ti = borrowed.as_mut().unwrap();
-            let depth = ti.depth;
+            ok
+        },
 
-            ti.depth += 1;
+        _ => false
+    }
+}
 
-            if ti.name_sent {
-                (ti.id, None, depth)
-            } else {
-                let name_bytes = ti.name.as_bytes();
-                (ti.id, Some((name_bytes.as_ptr(), name_bytes.len())), depth) //Pointer is fine; we don't plan one changing the name once its set
-            }
-        });
+///Sends a value of the plot `name` right away, timestamped now. Returns false if it couldn't
+///be sent. The name is copied with every value; `plot!()` only sends it once per call site.
+#[cfg(feature = "enabled")]
+pub fn plot_value(name: &'static str, value: f64, color: Color) -> bool {
+    submit_plot_at(name, value, color, timeline_now())
+}
 
-        let start = Instant::now();
+///Frame counter and start time of a sequence of frames. The frame macros keep one at each call
+///site; as everything goes through a shared reference, it can live in a plain `static`.
+#[cfg(feature = "enabled")]
+pub struct FrameMarker {
+    number: std::sync::atomic::AtomicU64,
+    last: std::sync::Mutex<Option<clock::Timestamp>>, //End of the previous frame, i.e. start of the current one
+    name_sent: NameSent                               //Whether the name of the set was sent (`mark_named()`)
+}
 
-        Self {
-            info, start,
-            time_data: MaybeUninit::uninit(),
-            thread_id, thread_name, depth
+#[cfg(feature = "enabled")]
+impl FrameMarker {
+    pub const fn new() -> Self {
+        FrameMarker {
+            number: std::sync::atomic::AtomicU64::new(0),
+            last: std::sync::Mutex::new(None),
+            name_sent: NameSent::new()
         }
     }
 
-    pub fn end(self) {
-        //Same as drop(zone)
+    //Ends the current frame now: returns its number, start and end. The lock keeps them in
+    //order if several threads mark frames of the same sequence.
+    fn next(&self) -> (u64, Option<clock::Timestamp>, clock::Timestamp) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let now = clock::now();
+
+        (self.number.fetch_add(1, std::sync::atomic::Ordering::Relaxed), last.replace(now), now)
     }
-}
 
-impl shmem::WriteInto<shmem::ZoneData> for Zone {
-    fn write_into(&self, target: &mut shmem::ZoneData) {
-        target.uid = (self.info as *const ZoneInfo) as usize;
-        target.color = self.info.color;
-        
-        unsafe {
-            let time_data = self.time_data.get_ref();
+    ///Ends the current frame of the default set (and starts the next one). Returns true if
+    ///it was sent.
+    pub fn mark(&self) -> bool {
+        self.mark_gpu(0)
+    }
+
+    ///Same as `mark()`, with the time at which the GPU presented the frame (see `send_frame_info_gpu()`)
+    pub fn mark_gpu(&self, gpu_present: u64) -> bool {
+        if !core::is_enabled() {
+            return false;
+        }
+
+        let (num, start, end) = self.next();
+        unsafe { push_frame_info(num, None, start, end, gpu_present) }
+    }
+
+    ///Same as `mark()`, but for the frame set called `name`. A marker should always be used
+    ///with the same name.
+    pub fn mark_named(&self, name: &'static str) -> bool {
+        if !core::is_enabled() {
+            return false;
+        }
+
+        let (num, start, end) = self.next();
+        let epoch = core::connection_epoch();
+        let ret = unsafe { push_frame_info(num, Some((name, self.name_sent.copy_name())), start, end, 0) };
+
+        if ret {
+            self.name_sent.set_sent(epoch);
+        }
+
+        ret
+    }
 
-            target.end = time_data.end;
-            target.duration = time_data.duration;
-            target.depth = self.depth;
-            target.name.set(self.info.name, self.info.copy_name);
-            target.thread.set_special(self.thread_id as usize, self.thread_name);
+    ///Same as `mark()`, but the frame is sent to `target`
+    pub fn mark_to(&self, target: &Context) {
+        if core::is_enabled() {
+            let (num, start, end) = self.next();
+            target.send_frame_info(num, start, end);
         }
     }
 }
 
-impl Drop for Zone {
-    fn drop(&mut self) {
-        let end = Instant::now();
+#[cfg(feature = "enabled")]
+impl Default for FrameMarker {
+    fn default() -> Self {
+        FrameMarker::new()
+    }
+}
 
-        unsafe {
-            //TODO: Maybe we can "cache" shmem and start_time in the THREAD_INFO,
-            //which is thread local. This would probably result in faster code.
-            let (opt_mem, start_time) = core::get_shmem_data_and_start_time();
-            let ok;
+#[cfg(feature = "enabled")]
+static DEFAULT_FRAMES: FrameMarker = FrameMarker::new();
 
-            if let Some(mem) = opt_mem {
-                self.time_data.write(TimeData {
-                    end: end.saturating_duration_since(start_time).as_secs_f64(),
-                    duration: end.saturating_duration_since(self.start).as_nanos() as u64
-                });
+//Markers of `mark_frame_named()`, by name. Leaked, as there are only a few frame sets.
+#[cfg(feature = "enabled")]
+static NAMED_FRAMES: std::sync::Mutex<Vec<(&'static str, &'static FrameMarker)>> = std::sync::Mutex::new(Vec::new());
 
-                ok = mem.zone_data.push(self);
-            } else {
-                ok = false;
-            }
+///Ends the current frame of the default set, like `frame_delimiter!()`, without any `unsafe`
+///at the call site. Its frame counter is shared by all the callers of `mark_frame()` (but not
+///with `frame_delimiter!()`, which counts at each call site).
+#[cfg(feature = "enabled")]
+pub fn mark_frame() -> bool {
+    DEFAULT_FRAMES.mark()
+}
 
-            if ok {
-                //Name sent; don't need to do it again
-                //NOTE: yeah, this is absolutely be thread unsafe,
-                //      but we don't care as long as the string is
-                //      sent at least once.
+///Same as `mark_frame()`, but for the frame set called `name` (see `frame_mark_named!()`)
+#[cfg(feature = "enabled")]
+pub fn mark_frame_named(name: &'static str) -> bool {
+    if !core::is_enabled() {
+        return false;
+    }
+
+    let marker = {
+        let mut markers = NAMED_FRAMES.lock().unwrap_or_else(|e| e.into_inner());
 
-                self.info.copy_name = false;
+        match markers.iter().find(|(x, _)| *x == name) {
+            Some((_, marker)) => *marker,
+            None => {
+                let marker: &'static FrameMarker = Box::leak(Box::new(FrameMarker::new()));
+                markers.push((name, marker));
+                marker
             }
+        }
+    };
 
-            THREAD_INFO.with(|ti| {
-                let mut borrowed = ti.borrow_mut();
-                let ti = borrowed.as_mut().unwrap();
+    marker.mark_named(name)
+}
 
-                if ok && self.thread_name.is_some() {
-                    ti.name_sent = true;
-                }
+///Ends frame `num` of the default set, which ran from `start` (the start of the timeline if
+///None) to `end`.
+///
+///# Safety
+///There is no requirement anymore; it's only unsafe so that existing callers don't change.
+///`mark_frame()` and `FrameMarker` are the safe replacements.
+#[cfg(feature = "enabled")]
+#[deprecated(note = "use `mark_frame()` or a `FrameMarker` instead")]
+pub unsafe fn send_frame_info(num: u64, start: Option<clock::Timestamp>, end: clock::Timestamp) {
+    push_frame_info(num, None, start, end, 0);
+}
 
-                ti.depth -= 1;
-            });
+///Same as `send_frame_info`, but also records when the GPU presented the frame: `gpu_present`
+///is a time of the timeline (see `timeline_time()`), so GPU timestamps must first be converted
+///to the CPU clock. 0 means unknown.
+///
+///# Safety
+///None, see `send_frame_info()`.
+#[cfg(feature = "enabled")]
+#[deprecated(note = "use `FrameMarker::mark_gpu()` instead")]
+pub unsafe fn send_frame_info_gpu(num: u64, start: Option<clock::Timestamp>, end: clock::Timestamp, gpu_present: u64) {
+    push_frame_info(num, None, start, end, gpu_present);
+}
+
+///Same as `send_frame_info` but for the frame set called `name`. Returns true if the frame
+///was sent, in which case `copy_name` can be set to false for the next frames of this set.
+///
+///# Safety
+///None, see `send_frame_info()`.
+#[cfg(feature = "enabled")]
+#[deprecated(note = "use `mark_frame_named()` or a `FrameMarker` instead")]
+pub unsafe fn send_named_frame_info(name: &'static str, copy_name: bool, num: u64, start: Option<clock::Timestamp>, end: clock::Timestamp) -> bool {
+    push_frame_info(num, Some((name, copy_name)), start, end, 0)
+}
+
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! frame_delimiter {
+    () => {{
+        static __TL_FRAMES: $crate::FrameMarker = $crate::FrameMarker::new();
+        __TL_FRAMES.mark();
+    }}
+}
+
+///Same as `frame_delimiter!()`, but also records when the GPU presented the frame that just
+///ended, as a time of the timeline (see `send_frame_info_gpu()`), so that the server can show
+///the latency between CPU and GPU frames, e.g. `frame_delimiter_gpu!(timeline_time(present))`
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! frame_delimiter_gpu {
+    ($gpu_present:expr) => {{
+        static __TL_FRAMES: $crate::FrameMarker = $crate::FrameMarker::new();
+
+        if $crate::is_enabled() {
+            let gpu_present: u64 = $gpu_present;
+            __TL_FRAMES.mark_gpu(gpu_present);
         }
-    }
+    }}
 }
 
+///Plots a value once per frame, e.g. `frame_plot!("draw_calls", calls)`. The value is sent by
+///the next `frame_delimiter!()` of the same thread, at the frame's end time, so that the plot
+///lines up with the frames; if it's given several times during a frame, only the last one is
+///kept. Frames marked with `frame_mark_named!()` don't send these.
+#[cfg(feature = "enabled")]
 #[macro_export]
-macro_rules! default_colors {
-    (blue)   => { 0x0061afef };
-    (orange) => { 0x00d19a66 };
-    (purple) => { 0x00c678dd };
-    (green)  => { 0x0098c379 };
-    (red)    => { 0x00e06c75 };
-    (cyan)   => { 0x0056b6c2 };
+macro_rules! frame_plot {
+    ($name:expr, color: $color:literal, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($crate::Color::from_hex($color), $name);
+        $crate::frame_plot(&__TL_PLOT_INFO, ($value) as f64);
+    }};
+
+    ($name:expr, color: $color:ident, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($crate::default_colors!($color), $name);
+        $crate::frame_plot(&__TL_PLOT_INFO, ($value) as f64);
+    }};
+
+    ($name:expr, color: $color:expr, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($color, $name);
+        $crate::frame_plot(&__TL_PLOT_INFO, ($value) as f64);
+    }};
+
+    ($name:expr, $value:expr) => {
+        $crate::frame_plot!($name, color: auto, $value)
+    };
 }
 
+///Sends a plot value right away, e.g. `plot!("fps", fps)` or `plot!("fps", color: red, fps)`,
+///and returns whether it made it into the shared memory. Plots are blue unless told otherwise.
+///Unlike `frame_plot!()`, every value is sent, at the time it's given.
+#[cfg(feature = "enabled")]
 #[macro_export]
-macro_rules! start_zone_profiling {
-    ($name:literal, color: $color:literal) => {{
-        static mut __TL_ZONE_INFO: $crate::ZoneInfo = $crate::ZoneInfo::new($color, $name);
-        $crate::Zone::new(unsafe { &mut __TL_ZONE_INFO })
+macro_rules! plot {
+    ($name:expr, color: $color:literal, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($crate::Color::from_hex($color), $name);
+        $crate::send_plot(&__TL_PLOT_INFO, ($value) as f64)
+    }};
+
+    ($name:expr, color: $color:ident, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($crate::default_colors!($color), $name);
+        $crate::send_plot(&__TL_PLOT_INFO, ($value) as f64)
     }};
 
-    ($name:literal, color: $color:ident) => {{
-        static mut __TL_ZONE_INFO: $crate::ZoneInfo = $crate::ZoneInfo::new($crate::default_colors!($color), $name);
-        $crate::Zone::new(unsafe { &mut __TL_ZONE_INFO })
+    ($name:expr, color: $color:expr, $value:expr) => {{
+        static __TL_PLOT_INFO: $crate::PlotInfo = $crate::PlotInfo::new($color, $name);
+        $crate::send_plot(&__TL_PLOT_INFO, ($value) as f64)
     }};
 
-    ($name:literal) => {
-        $crate::start_zone_profiling!($name, color: orange)
+    ($name:expr, $value:expr) => {
+        $crate::plot!($name, color: blue, $value)
     };
 }
 
+///Sends a log message, e.g. `tl_log!("loading done")`, `tl_log!("{} assets", count)` or
+///`tl_log!(msg, color: red)`, and returns whether it was sent entirely (see `log_message()`).
+///Without a color, the message gets the color of the `Info` level.
+#[cfg(feature = "enabled")]
 #[macro_export]
-macro_rules! profile_scope {
-    ($name:literal, color: $color:literal) => {
-        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+macro_rules! tl_log {
+    ($msg:expr, color: $color:literal) => {
+        $crate::log_message($crate::Color::from_hex($color), &$msg)
     };
 
-    ($name:literal, color: $color:ident) => {
-        let __tl_profiling_zone = $crate::start_zone_profiling!($name, color: $color);
+    ($msg:expr, color: $color:ident) => {
+        $crate::log_message($crate::default_colors!($color), &$msg)
+    };
+
+    ($msg:expr, color: $color:expr) => {
+        $crate::log_message($color, &$msg)
+    };
+
+    ($fmt:literal, $($arg:tt)+) => {
+        $crate::log_message($crate::Color::AUTO, &format!($fmt, $($arg)+))
     };
 
-    ($name:literal) => {
-        $crate::profile_scope!($name, color: orange);
+    ($msg:expr) => {
+        $crate::log_message($crate::Color::AUTO, &$msg)
     };
 }
 
-pub unsafe fn send_frame_info(num: u64, start: Option<Instant>, end: Instant) {
-    let (opt_mem, start_time) = core::get_shmem_data_and_start_time();
+///Like `frame_delimiter!()`, but for a separate frame set (i.e. a separate frame track on the server).
+///Note that the frame counter lives at the call site, so each set should be marked from a single place.
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! frame_mark_named {
+    ($name:expr) => {{
+        const __TL_FRAME_NAME: &str = $name;
+        static __TL_FRAMES: $crate::FrameMarker = $crate::FrameMarker::new();
+        __TL_FRAMES.mark_named(__TL_FRAME_NAME);
+    }}
+}
 
-    if let Some(mem) = opt_mem {
-        let entry = shmem::FrameData {
-            number: num,
-            end: end.saturating_duration_since(start_time).as_secs_f64(),
-            duration: end.saturating_duration_since(start.unwrap_or(start_time)).as_nanos() as u64
-        };
+///Same as `start_zone_profiling!`, but the zone is sent to `$target`, a `&'static Context`
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! start_zone_profiling_to {
+    ($target:expr, $name:expr, color: $color:literal) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::Color::from_hex($color), $name);
+        $crate::Zone::new_to($target, &__TL_ZONE_INFO)
+    }};
 
-        mem.frame_data.push(&entry);
-    }
+    ($target:expr, $name:expr, color: $color:ident) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($crate::default_colors!($color), $name);
+        $crate::Zone::new_to($target, &__TL_ZONE_INFO)
+    }};
+
+    ($target:expr, $name:expr, color: $color:expr) => {{
+        static __TL_ZONE_INFO: $crate::ZoneInfo = $crate::__tl_zone_info!($color, $name);
+        $crate::Zone::new_to($target, &__TL_ZONE_INFO)
+    }};
+
+    ($target:expr, $name:expr) => {
+        $crate::start_zone_profiling_to!($target, $name, color: auto)
+    };
 }
 
+///Same as `profile_scope!`, but the zone is sent to `$target`, a `&'static Context`
+#[cfg(feature = "enabled")]
 #[macro_export]
-macro_rules! frame_delimiter {
-    () => {{
-        static mut __TL_FRAME_TIME: Option<std::time::Instant> = None;
-        static mut __TL_FRAME_NUM: u64 = 0;
+macro_rules! profile_scope_to {
+    ($target:expr, $name:expr, color: $color:literal) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_to!($target, $name, color: $color);
+    };
 
-        unsafe {
-            let now = std::time::Instant::now();
-            $crate::send_frame_info(__TL_FRAME_NUM, __TL_FRAME_TIME, now);
+    ($target:expr, $name:expr, color: $color:ident) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_to!($target, $name, color: $color);
+    };
 
-            __TL_FRAME_TIME = Some(now);
-            __TL_FRAME_NUM += 1;
-        }
+    ($target:expr, $name:expr, color: $color:expr) => {
+        let __tl_profiling_zone = $crate::start_zone_profiling_to!($target, $name, color: $color);
+    };
+
+    ($target:expr, $name:expr) => {
+        $crate::profile_scope_to!($target, $name, color: auto);
+    };
+}
+
+///Same as `frame_delimiter!`, but the frame is sent to `$target`, a `&Context`
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! frame_delimiter_to {
+    ($target:expr) => {{
+        static __TL_FRAMES: $crate::FrameMarker = $crate::FrameMarker::new();
+        __TL_FRAMES.mark_to($target);
     }}
 }
 
+///Starts a background thread that tells the server this process is alive every `interval`,
+///which is useful for apps that don't call `frame_delimiter!()` regularly. Frames already
+///do it, so most apps don't need this.
+#[cfg(feature = "enabled")]
+pub fn start_heartbeat(interval: std::time::Duration) {
+    let _ = std::thread::Builder::new().name("temporal-lens-heartbeat".to_string()).spawn(move || {
+        loop {
+            if core::is_enabled() {
+                if let (Some(mem), _) = unsafe { core::get_shmem_data_and_start_time() } {
+                    if core::check_connection(mem) {
+                        mem.client_heartbeat();
+                    }
+                }
+            }
+
+            std::thread::sleep(interval);
+        }
+    });
+}
+
+///Connects to the server right away instead of on the first zone, ignoring failures. See
+///`preinit_with()` to know whether it worked.
+#[cfg(feature = "enabled")]
 pub fn preinit() {
-    unsafe {
-        let _ = core::get_shmem_data_and_start_time();
-    }
+    let _ = preinit_with(PreinitOptions::default());
+}
+
+///Connects to the server right away, with the given options, so that a missing server can be
+///reported at startup. On failure, the client keeps trying in the background every
+///`opts.retry_interval` unless `opts.fail_fast` is set. Returns Ok if already connected, in
+///which case a new path only applies to the next connection.
+#[cfg(feature = "enabled")]
+pub fn preinit_with(opts: PreinitOptions) -> Result<(), SharedMemoryOpenError> {
+    core::connect(&opts)
 }
 
+#[cfg(feature = "track-heap")]
+pub use heap_tracker::{set_heap_min_size, set_heap_sampling, set_heap_count_plot, set_heap_total_plot};
+
+#[cfg(feature = "heap-backtrace")]
+pub use heap_tracker::set_heap_backtrace_threshold;
+
 #[cfg(feature = "track-heap")]
 mod heap_tracker {
     use std::alloc::{GlobalAlloc, Layout, System};
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use super::shmem::{PlotData, WriteInto};
+    use std::sync::Once;
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+    use super::shmem::{HeapData, PlotData, Time, WriteInto};
+
+    #[cfg(feature = "heap-backtrace")]
+    use super::shmem::{HeapBacktraceData, HEAP_BACKTRACE_DEPTH};
 
     struct TLAllocator;
     static SYSTEM_ALLOCATOR: System = System;
     static TOTAL_SIZE: AtomicUsize = AtomicUsize::new(0);
 
+    static MIN_SIZE: AtomicUsize = AtomicUsize::new(0);
+    static SAMPLING: AtomicU32 = AtomicU32::new(1);
+    static CONFIG_FROM_ENV: Once = Once::new();
+
+    fn env_number(name: &str) -> Option<u64> {
+        std::env::var(name).ok().and_then(|x| x.trim().parse().ok())
+    }
+
+    ///Reads `TEMPORAL_LENS_HEAP_MIN_SIZE` and `TEMPORAL_LENS_HEAP_SAMPLING`. Called when the
+    ///core is initialized and never from the allocator, as reading the environment allocates.
+    pub(crate) fn init_from_env() {
+        CONFIG_FROM_ENV.call_once(|| {
+            if let Some(x) = env_number("TEMPORAL_LENS_HEAP_MIN_SIZE") {
+                MIN_SIZE.store(x as usize, Ordering::Relaxed);
+            }
+
+            if let Some(x) = env_number("TEMPORAL_LENS_HEAP_SAMPLING") {
+                SAMPLING.store((x as u32).max(1), Ordering::Relaxed);
+            }
+        });
+    }
+
+    ///(De)allocations smaller than `size` bytes are not reported. This overrides `TEMPORAL_LENS_HEAP_MIN_SIZE`.
+    pub fn set_heap_min_size(size: usize) {
+        CONFIG_FROM_ENV.call_once(|| {});
+        MIN_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    ///Only reports about 1 in `n` (de)allocations. The choice depends on the address, so an
+    ///allocation and its deallocation are either both reported or both skipped, and the
+    ///allocations in `heap_data` can still be matched. The plotted total stays exact since it
+    ///is computed from every allocation; only the amount of points goes down. This overrides
+    ///`TEMPORAL_LENS_HEAP_SAMPLING`.
+    pub fn set_heap_sampling(n: u32) {
+        CONFIG_FROM_ENV.call_once(|| {});
+        SAMPLING.store(n.max(1), Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn should_report(addr: *mut u8, size: usize) -> bool {
+        if addr.is_null() || size < MIN_SIZE.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        //Fibonacci hashing, since addresses share their low bits because of alignment
+        let n = SAMPLING.load(Ordering::Relaxed);
+        n <= 1 || (((addr as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as u32) % n == 0
+    }
+
+    static ALLOC_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static COUNT_PLOT: AtomicBool = AtomicBool::new(false);
+    static TOTAL_PLOT: AtomicBool = AtomicBool::new(true);
+
+    struct HeapEvent {
+        time: Time,
+        addr: usize,
+        size: usize,
+        is_free: bool
+    }
+
+    impl WriteInto<HeapData> for HeapEvent {
+        fn write_into(&self, target: &mut HeapData) {
+            target.time = self.time;
+            target.addr = self.addr;
+            target.size = self.size;
+            target.is_free = self.is_free;
+        }
+    }
+
+    const COUNT_PLOT_NAME: &str = "Heap allocations";
+
     struct HeapPlotData {
-        time: f64,
-        value: f64
+        time: Time,
+        value: usize,
+        is_count: bool,
+        is_free: bool //Total size going down; drawn in another color so that churn stands out
     }
 
     impl WriteInto<PlotData> for HeapPlotData {
         fn write_into(&self, target: &mut PlotData) {
             target.time = self.time;
-            target.color = 0x0098c379;
-            target.value = self.value;
-            target.name.set_special(0, None);
+            target.set_int_value(self.value as i64); //Byte totals past 2^53 would be rounded as f64
+
+            if self.is_count {
+                target.color = crate::default_colors!(purple);
+                target.name.set(COUNT_PLOT_NAME, true); //A small copy, but no allocation
+            } else {
+                target.color = if self.is_free { crate::default_colors!(red) } else { crate::default_colors!(green) };
+                target.name.set_special(0, None);
+            }
         }
     }
 
-    ///Make sure this function never allocates anything, otherwise it goes boom
-    unsafe fn report_heap(sz: usize) {
+    ///Also plots how many allocations were made since the beginning (`"Heap allocations"`),
+    ///from which the server can derive the allocation rate
+    pub fn set_heap_count_plot(enabled: bool) {
+        COUNT_PLOT.store(enabled, Ordering::Relaxed);
+    }
+
+    ///Also plots the total size of the heap, in green when it goes up and in red when it goes
+    ///down (the default). Every (de)allocation reported is sent to `heap_data` either way.
+    pub fn set_heap_total_plot(enabled: bool) {
+        TOTAL_PLOT.store(enabled, Ordering::Relaxed);
+    }
+
+    ///Make sure this function never allocates anything, otherwise it goes boom. `total` is
+    ///the size of the heap after the (de)allocation of `size` bytes at `addr`.
+    unsafe fn report_heap(addr: *mut u8, size: usize, total: usize, is_alloc: bool) {
+        //Not `is_enabled()`: reading the environment would allocate
+        if !super::core::is_enabled_no_env() {
+            return;
+        }
+
         if let Some((core, start)) = super::core::get_shmem_data_and_start_time_ro() {
-            let entry = HeapPlotData {
-                time: start.elapsed().as_secs_f64(),
-                value: sz as f64,
-            };
+            let time = super::core::timeline_time(start, super::clock::now());
+
+            core.heap_data.push(&HeapEvent {
+                time,
+                addr: addr as usize,
+                size,
+                is_free: !is_alloc
+            });
+
+            if TOTAL_PLOT.load(Ordering::Relaxed) {
+                core.plot_data.push(&HeapPlotData {
+                    time,
+                    value: total,
+                    is_count: false,
+                    is_free: !is_alloc
+                });
+            }
 
-            core.plot_data.push(&entry);
+            if is_alloc && COUNT_PLOT.load(Ordering::Relaxed) {
+                core.plot_data.push(&HeapPlotData {
+                    time,
+                    value: ALLOC_COUNT.load(Ordering::Relaxed),
+                    is_count: true,
+                    is_free: false
+                });
+            }
+        }
+    }
+
+    #[cfg(feature = "heap-backtrace")]
+    static BACKTRACE_THRESHOLD: AtomicUsize = AtomicUsize::new(64 * 1024);
+
+    #[cfg(feature = "heap-backtrace")]
+    std::thread_local! {
+        //Capturing a backtrace might allocate, which would bring us back here
+        static IN_BACKTRACE: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    }
+
+    ///Allocations of at least `size` bytes (64 KiB by default) are sent with their call stack
+    #[cfg(feature = "heap-backtrace")]
+    pub fn set_heap_backtrace_threshold(size: usize) {
+        BACKTRACE_THRESHOLD.store(size, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "heap-backtrace")]
+    struct HeapBacktrace {
+        time: Time,
+        addr: usize,
+        size: usize,
+        depth: usize,
+        frames: [usize; HEAP_BACKTRACE_DEPTH]
+    }
+
+    #[cfg(feature = "heap-backtrace")]
+    impl WriteInto<HeapBacktraceData> for HeapBacktrace {
+        fn write_into(&self, target: &mut HeapBacktraceData) {
+            target.time = self.time;
+            target.addr = self.addr;
+            target.size = self.size;
+            target.depth = self.depth as u32;
+            target.frames = self.frames;
+        }
+    }
+
+    //Only captures instruction pointers, symbolizing is up to the server
+    #[cfg(feature = "heap-backtrace")]
+    unsafe fn report_backtrace(addr: *mut u8, size: usize) {
+        if addr.is_null() || size < BACKTRACE_THRESHOLD.load(Ordering::Relaxed) || !super::core::is_enabled_no_env() {
+            return;
         }
+
+        let (core, start) = match super::core::get_shmem_data_and_start_time_ro() {
+            Some(x) => x,
+            None    => return
+        };
+
+        let _ = IN_BACKTRACE.try_with(|busy| {
+            if busy.replace(true) {
+                return;
+            }
+
+            let mut entry = HeapBacktrace {
+                time: super::core::timeline_time(start, super::clock::now()),
+                addr: addr as usize,
+                size,
+                depth: 0,
+                frames: [0; HEAP_BACKTRACE_DEPTH]
+            };
+
+            backtrace::trace_unsynchronized(|frame| {
+                entry.frames[entry.depth] = frame.ip() as usize;
+                entry.depth += 1;
+                entry.depth < HEAP_BACKTRACE_DEPTH
+            });
+
+            core.heap_backtrace_data.push(&entry);
+            busy.set(false);
+        });
     }
 
     unsafe impl GlobalAlloc for TLAllocator {
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-            let old = TOTAL_SIZE.fetch_add(layout.size(), Ordering::SeqCst);
-            report_heap(old + layout.size());
+            //A running total: no other memory access depends on it, so Relaxed is enough
+            let old = TOTAL_SIZE.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOC_COUNT.fetch_add(1, Ordering::Relaxed);
+
+            //After the fact, as the address is only known then
+            let ret = SYSTEM_ALLOCATOR.alloc(layout);
+
+            if should_report(ret, layout.size()) {
+                report_heap(ret, layout.size(), old + layout.size(), true);
+            }
+
+            #[cfg(feature = "heap-backtrace")]
+            report_backtrace(ret, layout.size());
 
-            SYSTEM_ALLOCATOR.alloc(layout)
+            ret
         }
 
         unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-            let old = TOTAL_SIZE.fetch_sub(layout.size(), Ordering::SeqCst);
-            report_heap(old - layout.size());
+            let old = TOTAL_SIZE.fetch_sub(layout.size(), Ordering::Relaxed);
+
+            //Before the fact, so that the free is timed before any reuse of the address
+            if should_report(ptr, layout.size()) {
+                report_heap(ptr, layout.size(), old - layout.size(), false);
+            }
 
             SYSTEM_ALLOCATOR.dealloc(ptr, layout);
         }
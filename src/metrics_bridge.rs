@@ -0,0 +1,151 @@
+///Bridge from the `metrics` crate facade: installing `MetricsRecorder` makes the metrics an app
+///already emits show up as plots, without instrumenting it twice. Each metric key (its name,
+///followed by its labels if any, e.g. `requests{method=GET}`) becomes a plot:
+/// - counters plot their running total
+/// - gauges plot their value
+/// - histograms plot each recorded value, plus their running mean as `<key> (mean)`. Note
+///   that `metrics` records durations in nanoseconds.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+
+use metrics::{Key, Recorder};
+
+use crate::{core, Color, DynamicPlot, NameSent};
+use crate::shmem;
+
+struct Series {
+    name: String,
+    uid: usize,
+    color: Color,
+    name_sent: NameSent,
+    total: u64,      //Counters: sum of the increments
+    count: u64,      //Histograms: number of values recorded
+    sum: f64,        //Histograms: sum of the values recorded
+    mean: Option<Box<Series>> //Histograms: the series of the running mean
+}
+
+impl Series {
+    fn new(name: String) -> Series {
+        Series {
+            uid: shmem::stable_uid(&name, "metrics"),
+            color: Color::from_name(&name),
+            name_sent: NameSent::new(),
+            total: 0,
+            count: 0,
+            sum: 0.0,
+            mean: None,
+            name
+        }
+    }
+
+    fn send(&mut self, value: f64) {
+        if !core::is_enabled() {
+            return;
+        }
+
+        let mem = match unsafe { core::get_shmem_data_and_start_time() } {
+            (Some(mem), _) => mem,
+            _              => return
+        };
+
+        let epoch = core::connection_epoch();
+        let entry = DynamicPlot {
+            time: crate::timeline_now(),
+            color: self.color,
+            value,
+            key: self.uid,
+            name: &self.name,
+            copy_name: self.name_sent.copy_name()
+        };
+
+        if mem.plot_data.push(&entry) {
+            self.name_sent.set_sent(epoch);
+        }
+    }
+}
+
+pub(crate) fn series_name(key: &Key) -> String {
+    let mut ret = key.name().to_string();
+    let mut first = true;
+
+    for label in key.labels() {
+        ret.push(if first { '{' } else { ',' });
+        ret.push_str(label.key());
+        ret.push('=');
+        ret.push_str(label.value());
+        first = false;
+    }
+
+    if !first {
+        ret.push('}');
+    }
+
+    ret
+}
+
+///A `metrics::Recorder` sending everything as plots, see the module documentation
+pub struct MetricsRecorder {
+    series: Mutex<HashMap<Key, Series>> //By key rather than by name, which is only built once per series
+}
+
+impl MetricsRecorder {
+    pub fn new() -> MetricsRecorder {
+        MetricsRecorder {
+            series: Mutex::new(HashMap::new())
+        }
+    }
+
+    ///Installs a `MetricsRecorder` as the global recorder of the `metrics` crate. Fails if
+    ///one was already installed.
+    pub fn install() -> Result<(), metrics::SetRecorderError> {
+        metrics::set_boxed_recorder(Box::new(MetricsRecorder::new()))
+    }
+
+    fn with_series<F: FnOnce(&mut Series)>(&self, key: Key, f: F) {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+
+        f(match series.entry(key) {
+            Entry::Occupied(x) => x.into_mut(),
+            Entry::Vacant(x)   => {
+                let name = series_name(x.key());
+                x.insert(Series::new(name))
+            }
+        });
+    }
+}
+
+impl Default for MetricsRecorder {
+    fn default() -> Self {
+        MetricsRecorder::new()
+    }
+}
+
+impl Recorder for MetricsRecorder {
+    fn increment_counter(&self, key: Key, value: u64) {
+        self.with_series(key, |s| {
+            s.total = s.total.saturating_add(value);
+
+            let total = s.total as f64;
+            s.send(total);
+        });
+    }
+
+    fn update_gauge(&self, key: Key, value: i64) {
+        self.with_series(key, |s| s.send(value as f64));
+    }
+
+    fn record_histogram(&self, key: Key, value: u64) {
+        self.with_series(key, |s| {
+            s.count += 1;
+            s.sum += value as f64;
+            s.send(value as f64);
+
+            let mean = s.sum / (s.count as f64);
+            let name = &s.name;
+
+            s.mean.get_or_insert_with(|| Box::new(Series::new(format!("{} (mean)", name)))).send(mean);
+        });
+    }
+}
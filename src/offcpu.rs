@@ -0,0 +1,132 @@
+///Off-CPU tracking. Gaps between zones may be idle time, but also time the thread spent
+///blocked or waiting for a core. On Linux, the kernel counts the time each thread actually
+///ran in `/proc/self/task/<tid>/schedstat`; a background thread samples it and sends, for
+///each thread, the fraction of the last interval it spent on CPU as the plot
+///`on-CPU <thread name> (<tid>)`. 1 means it ran the whole time, 0 that it was off-CPU.
+
+use crate::{core, Color, DynamicPlot, NameSent};
+use crate::shmem;
+
+const ON_CPU_COLOR: Color = crate::colors::CYAN;
+
+#[cfg(target_os = "linux")]
+struct SampledThread {
+    run_time: u64,   //Time spent on CPU so far, in nanoseconds
+    name: String,
+    key: usize,
+    name_sent: NameSent,
+    seen: bool       //Still alive during the last sample
+}
+
+//Time spent on CPU by the thread `tid`, in nanoseconds (first field of schedstat)
+#[cfg(target_os = "linux")]
+fn read_run_time(tid: &str) -> Option<u64> {
+    let path = format!("/proc/self/task/{}/schedstat", tid);
+    std::fs::read_to_string(path).ok()?.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+fn read_name(tid: &str) -> String {
+    let path = format!("/proc/self/task/{}/comm", tid);
+    let mut ret = format!("on-CPU {} ({})", std::fs::read_to_string(path).unwrap_or_default().trim(), tid);
+
+    while ret.len() > shmem::SHARED_STRING_MAX_SIZE {
+        ret.pop();
+    }
+
+    ret
+}
+
+#[cfg(target_os = "linux")]
+fn sample_loop(interval: std::time::Duration) {
+    use std::collections::HashMap;
+
+    let own_tid = unsafe { libc::syscall(libc::SYS_gettid) }.to_string();
+    let mut threads: HashMap<String, SampledThread> = HashMap::new();
+    let mut last_sample = std::time::Instant::now();
+
+    loop {
+        std::thread::sleep(interval);
+
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(last_sample).as_nanos().max(1) as f64;
+        last_sample = now;
+
+        let entries = match std::fs::read_dir("/proc/self/task") {
+            Ok(x)  => x,
+            Err(_) => continue
+        };
+
+        let mut mem = if core::is_enabled() {
+            match unsafe { core::get_shmem_data_and_start_time() } {
+                (Some(mem), _) => Some(mem),
+                _              => None
+            }
+        } else {
+            None
+        };
+
+        let time = crate::timeline_now();
+
+        for t in threads.values_mut() {
+            t.seen = false;
+        }
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let tid = entry.file_name().to_string_lossy().into_owned();
+
+            if tid == own_tid {
+                continue;
+            }
+
+            let run_time = match read_run_time(&tid) {
+                Some(x) => x,
+                None    => continue //Exited meanwhile
+            };
+
+            let t = match threads.get_mut(&tid) {
+                Some(t) => t,
+                None    => {
+                    //First time we see it: nothing to compare with yet
+                    let name = read_name(&tid);
+                    let key = shmem::stable_uid(&name, "off-cpu");
+
+                    threads.insert(tid, SampledThread { run_time, name, key, name_sent: NameSent::new(), seen: true });
+                    continue;
+                }
+            };
+
+            let value = (run_time.saturating_sub(t.run_time) as f64 / elapsed).min(1.0);
+            t.run_time = run_time;
+            t.seen = true;
+
+            if let Some(mem) = &mut mem {
+                let epoch = core::connection_epoch();
+                let entry = DynamicPlot { time, color: ON_CPU_COLOR, value, key: t.key, name: &t.name, copy_name: t.name_sent.copy_name() };
+
+                if mem.plot_data.push(&entry) {
+                    t.name_sent.set_sent(epoch);
+                }
+            }
+        }
+
+        //Forget threads that exited, their ids may be reused
+        threads.retain(|_, t| t.seen);
+    }
+}
+
+///Starts the background thread sampling the on-CPU time of every thread of this process
+///every `interval` (a few milliseconds is a good start; shorter intervals are mostly noise).
+///Only does something on Linux; returns false elsewhere, or if the thread couldn't be started.
+pub fn start_off_cpu_sampler(interval: std::time::Duration) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        std::thread::Builder::new().name("temporal-lens-offcpu".to_string()).spawn(move || sample_loop(interval)).is_ok()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = interval;
+        false
+    }
+}
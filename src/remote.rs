@@ -0,0 +1,80 @@
+///Streaming of captures over TCP, for when the profiled program runs on another machine
+///than the viewer. A `Forwarder` drains the shared memory next to the program, and a
+///`RemoteReceiver` on the other end gets the same `TraceChunk`s as when replaying a file:
+///the stream uses the trace file format (see `trace`), so its header doubles as a
+///`PROTOCOL_VERSION` handshake.
+
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::shmem::SharedMemoryData;
+use crate::trace::{TraceRecorder, TraceReader, TraceHeader, TraceChunk, TraceError, Codec};
+
+pub struct Forwarder {
+    recorder: TraceRecorder<BufWriter<TcpStream>>
+}
+
+impl Forwarder {
+    ///Sends the header right away, so that the receiver can check it
+    pub fn new(stream: TcpStream) -> Result<Forwarder, TraceError> {
+        Forwarder::with_codec(stream, Codec::None)
+    }
+
+    ///Same as `new()`, but compresses the stream with `codec`. The receiver detects it by itself.
+    pub fn with_codec(stream: TcpStream, codec: Codec) -> Result<Forwarder, TraceError> {
+        stream.set_nodelay(true)?;
+
+        let mut recorder = TraceRecorder::with_codec(BufWriter::new(stream), codec)?;
+        recorder.flush()?;
+
+        Ok(Forwarder { recorder })
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Forwarder, TraceError> {
+        Forwarder::new(TcpStream::connect(addr)?)
+    }
+
+    ///Waits for a receiver to connect to `listener`
+    pub fn accept(listener: &TcpListener) -> Result<Forwarder, TraceError> {
+        Forwarder::new(listener.accept()?.0)
+    }
+
+    ///Drains `mem` and sends everything immediately. The chunk is returned so that it
+    ///can also be used locally.
+    pub fn forward(&mut self, mem: &mut SharedMemoryData) -> Result<TraceChunk, TraceError> {
+        let chunk = self.recorder.record(mem)?;
+        self.recorder.flush()?;
+
+        Ok(chunk)
+    }
+}
+
+pub struct RemoteReceiver {
+    reader: TraceReader<BufReader<TcpStream>>
+}
+
+impl RemoteReceiver {
+    ///Reads the header sent by the forwarder, which must run the same protocol
+    pub fn new(stream: TcpStream) -> Result<RemoteReceiver, TraceError> {
+        Ok(RemoteReceiver { reader: TraceReader::new(BufReader::new(stream))? })
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<RemoteReceiver, TraceError> {
+        RemoteReceiver::new(TcpStream::connect(addr)?)
+    }
+
+    ///Waits for a forwarder to connect to `listener`
+    pub fn accept(listener: &TcpListener) -> Result<RemoteReceiver, TraceError> {
+        RemoteReceiver::new(listener.accept()?.0)
+    }
+
+    #[inline]
+    pub fn header(&self) -> &TraceHeader {
+        self.reader.header()
+    }
+
+    ///Blocks until the next chunk arrives. Returns `None` once the forwarder disconnected.
+    pub fn receive(&mut self) -> Result<Option<TraceChunk>, TraceError> {
+        self.reader.next_chunk()
+    }
+}
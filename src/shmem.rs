@@ -2,56 +2,339 @@
 ///to communicate between the server and the app to profile. Note that
 ///I should have used MaybeUninit everywhere here, but I got really lazy...
 
-use std::sync::atomic::{AtomicBool, Ordering, spin_loop_hint};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence, spin_loop_hint};
 use std::thread::yield_now;
 use std::path::PathBuf;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::time::{SystemTime, UNIX_EPOCH, Instant};
+use std::fmt;
 
-use shared_memory::{Shmem, ShmemConf, ShmemError};
+use shared_memory::ShmemError;
+
+#[cfg(not(feature = "loopback"))]
+use shared_memory::ShmemConf;
+
+#[cfg(all(not(feature = "loopback"), not(all(unix, feature = "mmap-file"))))]
+use shared_memory::Shmem as Handle;
+
+#[cfg(all(unix, feature = "mmap-file", not(feature = "loopback")))]
+use mmap_file::{Handle, FileHandle};
+
+#[cfg(feature = "loopback")]
+use loopback::Handle;
 
 #[cfg(feature = "server-mode")]
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
 
 pub const MAGIC: u32 = 0x1DC45EF1;
-pub const PROTOCOL_VERSION: u32 = 0x00_01_0004; //Major_Minor_Patch
+pub const PROTOCOL_VERSION: u32 = 0x01_00_001F; //Major_Minor_Patch
 pub const NUM_ENTRIES: usize = 256;
+//Capacity of each payload, as zones are by far the busiest stream
+pub const FRAME_ENTRIES: usize = NUM_ENTRIES;
+pub const ZONE_ENTRIES: usize = 4096;
+pub const HEAP_ENTRIES: usize = NUM_ENTRIES;
+pub const PLOT_ENTRIES: usize = NUM_ENTRIES;
+pub const PLOT_META_ENTRIES: usize = 64;
+pub const ZONE_TEXT_ENTRIES: usize = NUM_ENTRIES;
+pub const ZONE_HISTOGRAM_ENTRIES: usize = NUM_ENTRIES;
+pub const HEAP_BACKTRACE_ENTRIES: usize = 64;
+pub const THREAD_TABLE_ENTRIES: usize = 256;
+pub const MAX_CONSUMERS: usize = 4;
 pub const LOG_DATA_SIZE: usize = 8192;
+pub const LOG_CHUNK_SIZE: usize = 1024;
+pub const HISTOGRAM_BUCKETS: usize = 40;
+pub const HEAP_BACKTRACE_DEPTH: usize = 16;
 pub const SHARED_STRING_MAX_SIZE: usize = 128;
+pub const CACHE_LINE_SIZE: usize = 64;
 
-pub type Time = f64;     //Low precision time (seconds since program beginning)
+///A server that didn't call `SharedMemory::heartbeat()` for that long (in seconds) is considered dead, unless its PID can be checked (see `is_server_alive()`)
+pub const SERVER_TIMEOUT: u64 = 10;
+
+///How many times `Payload::snapshot()` starts over because of concurrent writes before giving up
+pub const SNAPSHOT_ATTEMPTS: usize = 1000;
+
+pub type Time = u64;     //Nanoseconds since program beginning
 pub type Duration = u64; //High precision time difference (nanoseconds)
-pub type Color = u32;    //24 bits, 0x00RRGGBB
 
+///24 bits RGB color, stored as 0x00RRGGBB
+#[repr(transparent)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize), serde(transparent))]
+pub struct Color(u32);
+
+impl fmt::Debug for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:06x}", self.0)
+    }
+}
+
+impl Color {
+    ///Placeholder that the client replaces with `colors::ORANGE`, or with `Color::from_name()`
+    ///if `set_auto_colors()` is on. Never sent to the server. The public constructors mask the top
+    ///byte out, so only deserializing an arbitrary `u32` (e.g. a corrupted trace) can produce it.
+    pub const AUTO: Color = Color(0xFF000000);
+
+    ///Derives a color from a name using a hash, so that the same name always gets the same
+    ///color, from one run to the other. Colors are bright enough for the text to be readable.
+    pub fn from_name(name: &str) -> Self {
+        let hash = fnv1a(name.as_bytes());
+
+        //HSV to RGB, with a fixed saturation and value
+        let (s, v) = (0.55f32, 0.85f32);
+        let h = ((hash % 360) as f32) / 60.0;
+        let c = v * s;
+        let x = c * (1.0 - ((h % 2.0) - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x)
+        };
+
+        let m = v - c;
+        let to_u8 = |x: f32| ((x + m) * 255.0).round() as u8;
+
+        Self::from_rgb(to_u8(r), to_u8(g), to_u8(b))
+    }
+
+    #[inline]
+    pub const fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        Self(((r as u32) << 16) | ((g as u32) << 8) | (b as u32))
+    }
+
+    ///Builds a color from its 0xRRGGBB representation. The top byte is ignored.
+    #[inline]
+    pub const fn from_hex(hex: u32) -> Self {
+        Self(hex & 0x00FFFFFF)
+    }
+
+    #[inline]
+    pub const fn r(self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    #[inline]
+    pub const fn g(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    #[inline]
+    pub const fn b(self) -> u8 {
+        self.0 as u8
+    }
+
+    #[inline]
+    pub const fn to_hex(self) -> u32 {
+        self.0
+    }
+
+    //Inverse of `to_hex()`, top byte included, so that `AUTO` survives being stored as a number
+    #[inline]
+    pub(crate) const fn from_raw(x: u32) -> Self {
+        Self(x)
+    }
+}
+
+impl From<u32> for Color {
+    fn from(hex: u32) -> Self {
+        Self::from_hex(hex)
+    }
+}
+
+impl From<Color> for u32 {
+    fn from(color: Color) -> Self {
+        color.0
+    }
+}
+
+///Backoff schedule of the spin locks of this process, see `set_spin_config()`. Each failed
+///attempt is first retried right away `busy` times, then after a `spin_loop_hint()` `spin` times,
+///then after yielding to the OS scheduler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpinConfig {
+    pub busy: u32, //Attempts retried immediately
+    pub spin: u32  //Following attempts preceded by a CPU pause hint, before yielding
+}
+
+impl Default for SpinConfig {
+    fn default() -> Self {
+        SpinConfig { busy: 4, spin: 12 }
+    }
+}
+
+static SPIN_BUSY: AtomicU32 = AtomicU32::new(4);   //`SpinConfig::busy`
+static SPIN_LIMIT: AtomicU32 = AtomicU32::new(16); //`SpinConfig::busy + SpinConfig::spin`
+
+///Tunes how the spin locks of this process wait: yield sooner (e.g. `busy: 0, spin: 0`) on an
+///oversubscribed machine, or spin longer on dedicated cores. Each process (server and client)
+///has its own setting. Has no effect on robust mutexes (`robust-lock` feature).
+pub fn set_spin_config(config: SpinConfig) {
+    SPIN_BUSY.store(config.busy, Ordering::Relaxed);
+    SPIN_LIMIT.store(config.busy.saturating_add(config.spin), Ordering::Relaxed);
+}
+
+pub fn spin_config() -> SpinConfig {
+    let busy = SPIN_BUSY.load(Ordering::Relaxed);
+    SpinConfig { busy, spin: SPIN_LIMIT.load(Ordering::Relaxed).saturating_sub(busy) }
+}
+
+//Each lock gets its own cache line so that threads writing different payloads don't fight over it
 #[derive(Default)]
+#[repr(align(64))]
 struct SpinLock(AtomicBool);
 
 impl SpinLock {
     #[inline]
-    fn lock(&self) {
+    fn init(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    ///Always false: a spin lock can't tell whether its owner died, it just stays locked
+    #[inline]
+    fn lock(&self) -> bool {
+        //Acquire: makes what the previous holder wrote before `unlock()` visible to us
+        if !self.0.swap(true, Ordering::Acquire) {
+            return false;
+        }
+
+        //Contended: only now is the schedule worth reading
+        let busy = SPIN_BUSY.load(Ordering::Relaxed);
+        let limit = SPIN_LIMIT.load(Ordering::Relaxed);
         let mut i = 0;
 
-        while self.0.swap(true, Ordering::Acquire) {
-            match i {
-                0..=3  => {},
-                4..=15 => spin_loop_hint(),
-                _      => yield_now()
+        loop {
+            if i >= limit {
+                yield_now();
+            } else if i >= busy {
+                spin_loop_hint();
             }
 
-            i += 1;
+            if !self.0.swap(true, Ordering::Acquire) {
+                return false;
+            }
+
+            i = i.saturating_add(1);
         }
     }
 
     #[inline]
     fn unlock(&self) {
+        //Release: publishes what we wrote to the next holder
         self.0.store(false, Ordering::Release);
     }
 }
 
+///With the `robust-lock` feature on Linux, the locks are process-shared robust pthread mutexes
+///instead of spin locks. If a client dies while holding one, the next `lock()` succeeds and
+///reports it instead of spinning forever. The recovery contract is:
+/// - Payloads only bump their size once an entry is fully written, so an entry that was being
+///   pushed is simply not there. Their `seq` stays odd until the next owner unlocks them.
+/// - The log ring might be halfway through an eviction or a write, so it's emptied (its
+///   messages are counted in `dropped_logs`)
+///
+///Both sides must be built the same way, which `SharedMemory::open()` checks (`LockMismatch`).
+#[cfg(all(target_os = "linux", feature = "robust-lock"))]
+#[repr(align(64))]
+struct RobustLock(std::cell::UnsafeCell<libc::pthread_mutex_t>);
+
+#[cfg(all(target_os = "linux", feature = "robust-lock"))]
+unsafe impl Sync for RobustLock {}
+
+#[cfg(all(target_os = "linux", feature = "robust-lock"))]
+impl RobustLock {
+    //Done by the server when it creates the shared memory, before any client can lock it
+    fn init(&self) {
+        unsafe {
+            let mut attr = std::mem::MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+
+            libc::pthread_mutexattr_init(attr.as_mut_ptr());
+            libc::pthread_mutexattr_setpshared(attr.as_mut_ptr(), libc::PTHREAD_PROCESS_SHARED);
+            libc::pthread_mutexattr_setrobust(attr.as_mut_ptr(), libc::PTHREAD_MUTEX_ROBUST);
+            libc::pthread_mutex_init(self.0.get(), attr.as_ptr());
+            libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        }
+    }
+
+    ///Returns true if the previous owner died while holding the lock. The lock is then owned
+    ///and consistent again, but the caller must repair what it protects.
+    #[inline]
+    fn lock(&self) -> bool {
+        loop {
+            match unsafe { libc::pthread_mutex_lock(self.0.get()) } {
+                0 => return false,
+
+                libc::EOWNERDEAD => {
+                    unsafe { libc::pthread_mutex_consistent(self.0.get()); }
+                    return true;
+                },
+
+                //ENOTRECOVERABLE: whoever recovered it died before marking it consistent. It
+                //can't be locked anymore, which is what a spin lock would do as well.
+                _ => yield_now()
+            }
+        }
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        unsafe {
+            libc::pthread_mutex_unlock(self.0.get());
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "robust-lock"))]
+type Lock = RobustLock;
+
+#[cfg(not(all(target_os = "linux", feature = "robust-lock")))]
+type Lock = SpinLock;
+
+//Stored in the shared memory, so that a client and a server using different locks don't open each other
+#[cfg(all(target_os = "linux", feature = "robust-lock"))]
+const LOCK_KIND: u32 = 1;
+
+#[cfg(not(all(target_os = "linux", feature = "robust-lock")))]
+const LOCK_KIND: u32 = 0;
+
+///Token that the client and the server must agree on, on top of `MAGIC`, so that unrelated
+///profiled apps (or differently-built profilers) on one machine don't read each other's shared
+///memory. Read from `TEMPORAL_LENS_NAMESPACE` when the shared memory is opened, or else from the
+///same variable at build time. None (the default) is the common case: no namespace at all.
+///
+///This is not a security boundary, just a cheap way to avoid cross-talk. To run several
+///servers side by side, see `temporal_lens::instance_name()` instead.
+pub fn namespace() -> Option<String> {
+    std::env::var("TEMPORAL_LENS_NAMESPACE").ok()
+        .or(option_env!("TEMPORAL_LENS_NAMESPACE").map(String::from))
+        .filter(|x| !x.trim().is_empty())
+}
+
+//What is stored in `SharedMemoryData::namespace`
+fn namespace_token() -> u32 {
+    match namespace() {
+        Some(x) => fnv1a(x.as_bytes()).max(1),
+        None    => 0
+    }
+}
+
 pub trait ShouldStopQuery {
-    fn should_stop_query(&self, t: f64, query_max: f64) -> bool;
+    fn should_stop_query(&self, t: Time, query_max: Time) -> bool;
 }
 
+///A string sent once: the first entry carries its contents, the following ones only its key,
+///which the server maps back to the contents. This only works if a key means the same string
+///for the whole session, hence the rules:
+/// - `set()` uses the address of a `&'static str`, which can't be freed and reused by another
+///   string (strings leaked with `Box::leak()` are fine too)
+/// - `set_copy()` is for strings that don't live that long: their address may be reused by a
+///   different string, so the key must come from the contents instead (e.g. a hash of them)
+/// - `set_special()` takes any key; the caller is responsible for the above
+///
+///Servers also let new contents for a known key replace the old ones (see
+///`analysis::NameCache`), so that a mistake shows up as a renaming rather than a wrong name.
 #[derive(Copy, Clone)]
 pub struct SharedString {
     key: usize,                            //A number that uniquely identifies this zone's name string (typically, the string's address)
@@ -97,6 +380,31 @@ impl SharedString {
         }
     }
 
+    ///Copies a string that doesn't live long enough to be used as a key; `key` is used
+    ///instead, and must not be the address of `string` (see above). Strings longer than
+    ///`SHARED_STRING_MAX_SIZE` are truncated.
+    pub fn set_copy(&mut self, key: usize, string: &str) {
+        let mut len = string.len().min(SHARED_STRING_MAX_SIZE);
+
+        while !string.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.set_special(key, Some((string.as_ptr(), len)));
+    }
+
+    ///Same as `set()`, but strings longer than `SHARED_STRING_MAX_SIZE` are cut from the start
+    ///instead of panicking (e.g. for source paths, where the end is what matters)
+    pub fn set_tail(&mut self, string: &'static str, copy_contents: bool) {
+        let mut start = string.len().saturating_sub(SHARED_STRING_MAX_SIZE);
+
+        while !string.is_char_boundary(start) {
+            start += 1;
+        }
+
+        self.set(&string[start..], copy_contents);
+    }
+
     #[inline]
     pub fn get_key(&self) -> usize {
         self.key
@@ -117,40 +425,229 @@ impl SharedString {
     }
 }
 
-#[derive(Copy, Clone)]
+///Shows the contents if there are any, the key otherwise
+impl fmt::Display for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.make_str() {
+            Some(x) => f.write_str(x),
+            None    => write!(f, "<{:#x}>", self.key)
+        }
+    }
+}
+
+impl fmt::Debug for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.make_str() {
+            Some(x) => write!(f, "{:?} <{:#x}>", x, self.key),
+            None    => write!(f, "<{:#x}>", self.key)
+        }
+    }
+}
+
+///Serialized as a `(key, Option<contents>)` pair since serde can't handle the fixed-size array
+#[cfg(feature = "server-mode")]
+impl Serialize for SharedString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.key, self.make_str()).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "server-mode")]
+impl<'de> Deserialize<'de> for SharedString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (key, contents): (usize, Option<String>) = Deserialize::deserialize(deserializer)?;
+        let mut ret = SharedString {
+            key,
+            size: 0,
+            has_contents: false,
+            contents: [0; SHARED_STRING_MAX_SIZE]
+        };
+
+        if let Some(string) = contents {
+            let raw = string.as_bytes();
+
+            if raw.len() > SHARED_STRING_MAX_SIZE {
+                return Err(serde::de::Error::custom(format!("SharedStrings are limited to {} bytes", SHARED_STRING_MAX_SIZE)));
+            }
+
+            ret.contents[0..raw.len()].copy_from_slice(raw);
+            ret.size = raw.len() as u8;
+            ret.has_contents = true;
+        }
+
+        Ok(ret)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 #[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
 pub struct FrameData {
-    pub number: u64,       //Frame number
-    pub end: Time,         //Time when the frame ended
-    pub duration: Duration //Total frame time. start = end - duration if you convert the units first ;)
+    pub number: u64,        //Frame number, relative to the frame set
+    pub start: Time,        //Time when the frame started
+    pub end: Time,          //Time when the frame ended
+    pub duration: Duration, //Total frame time
+    pub gpu_present: Time,  //Time when the GPU presented the frame (see `frame_delimiter_gpu!()`), 0 if unknown
+    pub set: SharedString   //The frame set this frame belongs to. The default set has key 0 and no contents
+}
+
+impl FrameData {
+    ///Time between the end of the frame on the CPU and its presentation by the GPU, if known
+    #[inline]
+    pub fn gpu_latency(&self) -> Option<Duration> {
+        if self.gpu_present == 0 { None } else { Some(self.gpu_present.saturating_sub(self.end)) }
+    }
 }
 
 impl ShouldStopQuery for FrameData {
-    fn should_stop_query(&self, t: f64, query_max: f64) -> bool {
-        t - (self.duration as f64) * 1e-9 > query_max
+    fn should_stop_query(&self, t: Time, query_max: Time) -> bool {
+        t.saturating_sub(self.duration) > query_max
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
 pub struct ZoneData {
-    pub uid: usize,          //A number that uniquely identifies the zone
-    pub color: Color,        //The color of the zone
-    pub end: Time,           //Time when the zone ended
-    pub duration: Duration,  //The execution time. start = end - duration if you convert the units first ;)
-    pub depth: u32,          //Call stack depth
-    pub name: SharedString,  //The name of the zone
-    pub thread: SharedString //Thread thread ID
+    pub uid: usize,             //A number that uniquely identifies the zone: a hash of its name and location, the same from run to run (the address of its `ZoneInfo` if the location is unknown)
+    pub color: Color,           //The color of the zone
+    pub start: Time,            //Time when the zone started
+    pub end: Time,              //Time when the zone ended
+    pub duration: Duration,     //The execution time
+    pub cpu_duration: Duration, //CPU time spent by the thread during the zone, 0 if unknown (see the `cpu-time` feature)
+    pub depth: u32,             //Call stack depth
+    pub name: SharedString,     //The name of the zone
+    pub thread: usize,          //Thread ID, whose name is in `SharedMemoryData::thread_table`
+    pub loc: SharedString,      //"file:line" where the zone was declared, sent once like `name`. Key 0 if unknown
+    pub category: SharedString, //Category of the zone, sent once like `name`. Key 0 if none
+    pub instance: u64           //Per-thread counter telling apart the instances of a zone
 }
 
+///Statistics of an aggregate zone, sent instead of its instances. Bucket 0 counts
+///instances that took 0ns, bucket `i` those that took `[2^(i-1), 2^i)` nanoseconds.
+///The last bucket also counts everything above.
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
+pub struct ZoneHistogramData {
+    pub uid: usize,                        //Same as `ZoneData::uid`
+    pub color: Color,                      //The color of the zone
+    pub end: Time,                         //The instances ended before this time, and after the previous histogram of this zone
+    pub count: u64,                        //Number of instances
+    pub total: Duration,                   //Sum of all the durations
+    pub min: Duration,                     //Shortest duration
+    pub max: Duration,                     //Longest duration
+    #[cfg_attr(feature = "server-mode", serde(with = "bucket_serde"))]
+    pub buckets: [u32; HISTOGRAM_BUCKETS], //Log-scale duration buckets
+    pub name: SharedString,                //The name of the zone
+    pub thread: usize                      //Thread ID, same as `ZoneData::thread`
+}
+
+//serde only implements its traits for arrays of up to 32 elements
+#[cfg(feature = "server-mode")]
+mod bucket_serde {
+    use super::HISTOGRAM_BUCKETS;
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+    pub fn serialize<S: Serializer>(buckets: &[u32; HISTOGRAM_BUCKETS], serializer: S) -> Result<S::Ok, S::Error> {
+        buckets[..].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; HISTOGRAM_BUCKETS], D::Error> {
+        let v: Vec<u32> = Deserialize::deserialize(deserializer)?;
+        let mut ret = [0; HISTOGRAM_BUCKETS];
+
+        if v.len() != HISTOGRAM_BUCKETS {
+            return Err(serde::de::Error::invalid_length(v.len(), &"HISTOGRAM_BUCKETS buckets"));
+        }
+
+        ret.copy_from_slice(&v);
+        Ok(ret)
+    }
+}
+
+//Written by hand since `Debug` isn't implemented for arrays of more than 32 elements
+impl fmt::Debug for ZoneHistogramData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZoneHistogramData")
+            .field("uid", &self.uid)
+            .field("color", &self.color)
+            .field("end", &self.end)
+            .field("count", &self.count)
+            .field("total", &self.total)
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("buckets", &&self.buckets[..])
+            .field("name", &self.name)
+            .field("thread", &self.thread)
+            .finish()
+    }
+}
+
+impl ZoneHistogramData {
+    #[inline]
+    pub fn bucket_of(duration: Duration) -> usize {
+        ((64 - duration.leading_zeros()) as usize).min(HISTOGRAM_BUCKETS - 1)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
+pub struct ZoneTextData {
+    pub thread: usize,     //Thread ID, same as `ZoneData::thread`
+    pub instance: u64,     //Together with `thread`, identifies the `ZoneData` this text belongs to
+    pub text: SharedString //The text itself. Always has contents, key is 0
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
+pub struct ThreadNameData {
+    pub thread: usize,     //Thread ID, same as `ZoneData::thread`
+    pub name: SharedString //Name of the thread. Always has contents, key is `thread`
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
 pub struct PlotData {
     pub time: Time,        //Time (X axis)
     pub color: Color,      //Color of the plot
-    pub value: f64,        //Value to plot (Y axis)
+    pub value: f64,        //Value to plot (Y axis). For integer plots, the closest f64 to `int_value`
+    pub int_value: i64,    //Exact value of integer plots (counters, byte totals...), 0 otherwise
+    pub is_int: bool,      //Whether this is an integer plot, which should be displayed without decimals
     pub name: SharedString //Plot name, which is also used as unique identifier
 }
 
-#[derive(Copy, Clone)]
+///Sets the color of a plot series from `time` on (see `temporal_lens::set_plot_color()`). These
+///have their own payload so that nothing reading `plot_data` mistakes them for samples.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
+pub struct PlotMetaData {
+    pub time: Time,        //When the color was set
+    pub color: Color,      //New color of the series
+    pub name: SharedString //Name of the series, same as `PlotData::name`. Always has contents
+}
+
+impl PlotData {
+    ///Exact value of integer plots, None for float plots
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        if self.is_int { Some(self.int_value) } else { None }
+    }
+
+    #[inline]
+    pub(crate) fn set_value(&mut self, value: f64) {
+        self.value = value;
+        self.int_value = 0;
+        self.is_int = false;
+    }
+
+    #[inline]
+    pub(crate) fn set_int_value(&mut self, value: i64) {
+        self.value = value as f64;
+        self.int_value = value;
+        self.is_int = true;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
 pub struct HeapData {
     pub time: Time,   //Time at which the (de)allocation happened
     pub addr: usize,  //Address of the (de)allocated memory
@@ -158,18 +655,182 @@ pub struct HeapData {
     pub is_free: bool //True if the memory was deallocated, false otherwise
 }
 
+///Call stack of a large allocation, matched with its `HeapData` using `addr` and `time`
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
+pub struct HeapBacktraceData {
+    pub time: Time,                            //Time at which the allocation happened
+    pub addr: usize,                           //Address of the allocated memory
+    pub size: usize,                           //Size of the allocated memory
+    pub depth: u32,                            //How many entries of `frames` are valid
+    pub frames: [usize; HEAP_BACKTRACE_DEPTH]  //Instruction pointers, innermost first (including the allocator's frames)
+}
+
+const LOG_HEADER_SIZE: usize = std::mem::size_of::<LogEntryHeader>();
+
+///`LogEntryHeader::flags`: the message goes on in the next entry
+pub const LOG_CONTINUED: u8 = 1;
+
+///`LogEntryHeader::flags`: the message didn't fit and was cut after this entry
+pub const LOG_TRUNCATED: u8 = 2;
+
+///`LogEntryHeader::flags`: the message text is followed by key/value fields, see `LOG_FIELD_SEPARATOR`.
+///Set on every chunk of the message.
+pub const LOG_FIELDS: u8 = 4;
+
+///Structured fields are appended to the text of the message: each one is `LOG_FIELD_SEPARATOR`,
+///the key, `LOG_VALUE_SEPARATOR` and the value. These two characters (the ASCII record and
+///unit separators) are replaced by spaces in the message, keys and values. See `split_log_fields()`.
+pub const LOG_FIELD_SEPARATOR: char = '\u{1e}';
+pub const LOG_VALUE_SEPARATOR: char = '\u{1f}';
+
+//Appends `text` to `dst`, without the characters that delimit fields
+fn push_log_text(dst: &mut String, text: &str) {
+    dst.extend(text.chars().map(|c| if c == LOG_FIELD_SEPARATOR || c == LOG_VALUE_SEPARATOR { ' ' } else { c }));
+}
+
+///Encodes a message and its fields as described in `LOG_FIELD_SEPARATOR`
+pub fn encode_log_fields(msg: &str, fields: &[(&str, &str)]) -> String {
+    let mut ret = String::with_capacity(msg.len() + fields.iter().map(|(k, v)| k.len() + v.len() + 2).sum::<usize>());
+    push_log_text(&mut ret, msg);
+
+    for (key, value) in fields {
+        ret.push(LOG_FIELD_SEPARATOR);
+        push_log_text(&mut ret, key);
+        ret.push(LOG_VALUE_SEPARATOR);
+        push_log_text(&mut ret, value);
+    }
+
+    ret
+}
+
+///Splits the text of a message flagged with `LOG_FIELDS` into the message itself and its
+///fields, in the order they were given. If the message was truncated, the last field might
+///be cut, or have no value at all (in which case it's empty).
+#[cfg(feature = "server-mode")]
+pub fn split_log_fields(text: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = text.split(LOG_FIELD_SEPARATOR);
+    let msg = parts.next().unwrap_or("");
+    let fields = parts.map(|field| {
+        let mut kv = field.splitn(2, LOG_VALUE_SEPARATOR);
+        (kv.next().unwrap_or(""), kv.next().unwrap_or(""))
+    }).collect();
+
+    (msg, fields)
+}
+
+///Messages are split in chunks of at most `LOG_CHUNK_SIZE` bytes, cut at char boundaries.
+///Every chunk gets its own header with the same `time` and `color`; all of them but the last
+///are flagged with `LOG_CONTINUED`. If `log_data` runs out of room, the message is cut and
+///the last chunk that made it is flagged with `LOG_TRUNCATED` instead. Entries (headers
+///included) may wrap around the end of `log_data`.
 #[repr(packed)]
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "server-mode", derive(Serialize, Deserialize))]
 pub struct LogEntryHeader {
-    pub time: Time,   //Time at which the message was logged
-    pub color: Color, //Color of the message
-    pub length: usize //Amount of bytes contained in the string
+    pub time: Time,    //Time at which the message was logged
+    pub color: Color,  //Color of the message
+    pub length: usize, //Amount of bytes contained in the string (of this chunk only)
+    pub flags: u8,     //Combination of `LOG_CONTINUED`, `LOG_TRUNCATED` and `LOG_FIELDS`
+    pub level: u8      //Severity of the message, see `LogEntryHeader::level()`
+}
+
+impl LogEntryHeader {
+    #[inline]
+    pub fn level(&self) -> LogLevel {
+        LogLevel::from_u8(self.level)
+    }
+}
+
+//Fields are copied out first since references to packed fields might be unaligned
+impl fmt::Debug for LogEntryHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (time, color, length, flags) = (self.time, self.color, self.length, self.flags);
+
+        f.debug_struct("LogEntryHeader")
+            .field("time", &time)
+            .field("color", &color)
+            .field("length", &length)
+            .field("flags", &flags)
+            .field("level", &self.level())
+            .finish()
+    }
+}
+
+///Severity of a log message. Messages sent without one (`send_log()`) are `Info`.
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error
+}
+
+impl LogLevel {
+    ///Unknown values are read as `Error`, so that filtering by level never hides them
+    pub fn from_u8(x: u8) -> LogLevel {
+        match x {
+            0 => LogLevel::Trace,
+            1 => LogLevel::Debug,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warn,
+            _ => LogLevel::Error
+        }
+    }
+
+    ///Color of the messages of this level that weren't given one (`Color::AUTO`)
+    pub fn default_color(self) -> Color {
+        match self {
+            LogLevel::Trace => crate::colors::CYAN,
+            LogLevel::Debug => crate::colors::BLUE,
+            LogLevel::Info  => crate::colors::GREEN,
+            LogLevel::Warn  => crate::colors::ORANGE,
+            LogLevel::Error => crate::colors::RED
+        }
+    }
+}
+
+//Aligned so that independent payloads never share a cache line
+//
+//Payloads don't wrap around: once `data` is full, new entries are dropped (and counted in `lost`)
+//until the server drains it. What the server retrieves is thus always the oldest entries, in the
+//order they were pushed, and `lost` is exactly how many came after them.
+//
+//Readers that don't lock (`snapshot()`) rely on `seq` instead, a seqlock counter: it's odd while
+//someone holds the lock, and changes each time it's released, so a copy that started and ended
+//with the same even value can't be torn.
+#[repr(align(64))]
+pub struct Payload<T: Sized + Copy, const N: usize> {
+    lock: Lock,                         //A simple spin lock based on an AtomicBool, or a robust mutex (see `RobustLock`)
+    seq: AtomicUsize,                   //Odd while the lock is held, see above
+    size: usize,                        //How many valid entries are available in `data`, never more than N
+    lost: usize,                        //How many entries were dropped because `data` was full, since the last retrieve
+    lost_total: usize,                  //Same as `lost`, but never reset (wraps around), for consumers
+    consumers: u32,                     //Bit i is set if consumer i is registered (see `register_consumer()`)
+    cursors: [usize; MAX_CONSUMERS],    //Index in `data` of the next entry each consumer reads
+    lost_seen: [usize; MAX_CONSUMERS],  //`lost_total` when each consumer last read
+    data: [T; N]
 }
 
-pub struct Payload<T: Sized + Copy> {
-    lock: SpinLock,        //A simple spin lock based on an AtomicBool
-    size: usize,           //How many valid entries are available in `data`
-    data: [T; NUM_ENTRIES]
+///A reader registered with `Payload::register_consumer()`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Consumer(usize);
+
+///A payload of the historical capacity, `NUM_ENTRIES`
+pub type DefaultPayload<T> = Payload<T, NUM_ENTRIES>;
+
+///Names of the client threads, by thread ID. Unlike payloads, entries stay there: clients
+///write the name of each thread once (and again when it's renamed), and the server looks
+///names up whenever it needs them. Once the table is full, new threads replace the oldest
+///entry the server already took (see `take_updates()`); if there is none, they are left unnamed.
+pub struct ThreadTable {
+    lock: Lock,                             //Same as `Payload::lock`
+    seq: AtomicUsize,                       //Same as `Payload::seq`
+    count: usize,                           //How many valid entries are available in `entries`
+    updated: [bool; THREAD_TABLE_ENTRIES],  //Entries that changed since the last `take_updates()`
+    entries: [ThreadNameData; THREAD_TABLE_ENTRIES] //From the oldest to the newest
 }
 
 pub struct SharedMemoryData {
@@ -177,19 +838,55 @@ pub struct SharedMemoryData {
     pub magic: u32,
     pub protocol_version: u32,
     pub size_of_usize: u32,
+    pub lock_kind: u32,                //0 for spin locks, 1 for robust mutexes (`robust-lock` feature)
+    pub namespace: u32,                //Hash of the namespace token (see `namespace()`), 0 if there is none
+
+    //Server liveness
+    pub server_pid: u32,               //Process ID of the server that created the shared memory
+    pub server_heartbeat: AtomicU64,   //Last time the server said it was alive (seconds since UNIX epoch)
+    pub client_heartbeat: AtomicU64,   //Last time the client said it was alive (seconds since UNIX epoch), 0 if never
 
     //Useful data
-    pub frame_data: Payload<FrameData>,
-    pub zone_data: Payload<ZoneData>,
-    pub heap_data: Payload<HeapData>,
-    pub plot_data: Payload<PlotData>,
+    pub frame_data: Payload<FrameData, FRAME_ENTRIES>,
+    pub zone_data: Payload<ZoneData, ZONE_ENTRIES>,
+    pub heap_data: Payload<HeapData, HEAP_ENTRIES>,
+    pub plot_data: Payload<PlotData, PLOT_ENTRIES>,
+    pub plot_meta_data: Payload<PlotMetaData, PLOT_META_ENTRIES>,
+    pub zone_text_data: Payload<ZoneTextData, ZONE_TEXT_ENTRIES>,
+    pub zone_histogram_data: Payload<ZoneHistogramData, ZONE_HISTOGRAM_ENTRIES>,
+    pub heap_backtrace_data: Payload<HeapBacktraceData, HEAP_BACKTRACE_ENTRIES>,
+    pub thread_table: ThreadTable,
+
+    //Statistics
+    pub dropped_frames: AtomicU64, //How many frames could not be pushed because `frame_data` was full
+    pub depth_capped: AtomicU64,   //How many zones weren't sent because they were deeper than the client's `max_depth`
+    pub overloaded: AtomicBool,    //Set by the server when it can't keep up; clients may then reduce what they send
+
+    //Clear protocol (see `SharedMemoryData::request_clear()`)
+    pub clear_generation: AtomicU32,   //Bumped by the server each time it wants the buffers cleared
+    pub cleared_generation: AtomicU32, //Set by the client to `clear_generation` once it cleared the buffers
+    pub timeline_resets: AtomicU32,    //Bumped by the client when it restarts its timeline by itself (`reset_timeline()`)
+
+    //Data notification (see `SharedMemory::wait_for_data()`)
+    pub data_signal: AtomicU32,        //Bumped by the client once per frame; also the futex word on Linux
+    pub server_waiting: AtomicBool,    //Set while the server is blocked in `wait_for_data()`
 
     //Log data; different as it can contain Strings of variable size
-    log_data_lock: SpinLock,          //A simple spin lock based on an AtomicBool
-    pub log_data_count: u32,          //How many valid log messages are available in `log_data`
-    pub log_data: [u8; LOG_DATA_SIZE] //Array of LogEntryHeader followed by `header.length` bytes of log message
+    log_data_lock: Lock,              //Same as `Payload::lock`
+    log_data_seq: AtomicUsize,        //Same as `Payload::seq`
+    pub log_data_count: u32,          //How many valid log entries (chunks) are available in `log_data`
+    pub log_data_start: u32,          //Offset of the oldest entry in `log_data`
+    pub log_data_size: u32,           //How many bytes of `log_data` are used, starting from `log_data_start`
+    pub dropped_logs: AtomicU64,      //How many messages were overwritten because `log_data` was full
+    pub log_data: [u8; LOG_DATA_SIZE] //Ring of LogEntryHeader followed by `header.length` bytes of log message
 }
 
+//Compile-time layout checks: `[(); N - M]` only type checks as `[(); 0]` if N == M
+const _SPINLOCK_SIZE_CHECK: [(); 0] = [(); std::mem::size_of::<Lock>() - CACHE_LINE_SIZE];
+const _PAYLOAD_ALIGN_CHECK: [(); 0] = [(); std::mem::align_of::<Payload<ZoneData, ZONE_ENTRIES>>() - CACHE_LINE_SIZE];
+const _PAYLOAD_SIZE_CHECK: [(); 0] = [(); std::mem::size_of::<Payload<FrameData, FRAME_ENTRIES>>() % CACHE_LINE_SIZE];
+const _PAYLOAD_SMALL_SIZE_CHECK: [(); 0] = [(); std::mem::size_of::<Payload<HeapBacktraceData, HEAP_BACKTRACE_ENTRIES>>() % CACHE_LINE_SIZE];
+
 pub trait WriteInto<T> {
     fn write_into(&self, target: &mut T);
 }
@@ -200,132 +897,1817 @@ impl<T: Copy> WriteInto<T> for T {
     }
 }
 
-impl<T: Sized + Copy> Payload<T> {
+impl<T: Sized + Copy, const N: usize> Payload<T, N> {
+    ///How many entries fit before new ones get dropped
+    pub const CAPACITY: usize = N;
+
     unsafe fn init(&mut self) {
-        self.lock.unlock(); //Hack to init
+        self.lock.init();
+        self.seq.store(0, Ordering::Relaxed);
         self.size = 0;
-        
+        self.lost = 0;
+        self.lost_total = 0;
+        self.consumers = 0;
+    }
+
+    //Takes the lock and makes `seq` odd. Everything that touches the entries or the size goes
+    //through this, returning what `Lock::lock()` returns.
+    #[inline]
+    fn begin_write(&self) -> bool {
+        let ret = self.lock.lock();
+
+        //Only the lock owner writes `seq`. It's already odd if the previous owner died holding it.
+        self.seq.store(self.seq.load(Ordering::Relaxed) | 1, Ordering::Relaxed);
+
+        //Readers that see any of our writes see the odd value too
+        fence(Ordering::Release);
+        ret
+    }
+
+    #[inline]
+    fn end_write(&self) {
+        self.seq.store(self.seq.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+        self.lock.unlock();
+    }
+
+    #[inline]
+    fn count_lost(&mut self, count: usize) {
+        self.lost = self.lost.saturating_add(count);
+        self.lost_total = self.lost_total.wrapping_add(count);
+    }
+
+    //The first `count` entries were removed for everyone, by one of the single-drain methods
+    #[inline]
+    fn drained(&mut self, count: usize) {
+        if self.consumers != 0 {
+            for cursor in &mut self.cursors {
+                *cursor = cursor.saturating_sub(count);
+            }
+        }
     }
 
     pub fn push<U: WriteInto<T>>(&mut self, entry: &U) -> bool {
         let ret;
-        self.lock.lock();
+        self.begin_write();
 
-        if self.size < NUM_ENTRIES {
+        if self.size < N {
             entry.write_into(&mut self.data[self.size]);
+            self.size += 1;
             ret = true;
         } else {
+            self.count_lost(1);
             ret = false;
         }
 
-        self.size += 1;
-        self.lock.unlock();
+        self.end_write();
         
         ret
     }
 
-    pub unsafe fn retrieve_unchecked(&mut self, dst: *mut T) -> (usize, usize) {
-        self.lock.lock();
-
-        let (retrieved, lost) = if self.size <= NUM_ENTRIES {
-            (self.size, 0)
-        } else {
-            (NUM_ENTRIES, self.size - NUM_ENTRIES)
-        };
-
-        std::ptr::copy_nonoverlapping(self.data.as_ptr(), dst, retrieved);
+    ///Drops all entries
+    pub fn clear(&mut self) {
+        self.begin_write();
+        self.drained(self.size);
         self.size = 0;
-
-        self.lock.unlock();
-        (retrieved, lost)
+        self.lost = 0;
+        self.end_write();
     }
 
-    pub fn retrieve(&mut self, dst: &mut [T]) -> (usize, usize) {
-        assert!(dst.len() >= NUM_ENTRIES, "destination slice has an unsufficient size");
+    ///Pushes several entries while locking only once. Returns how many of them were actually written.
+    pub fn push_slice<U: WriteInto<T>>(&mut self, entries: &[U]) -> usize {
+        self.begin_write();
 
-        unsafe {
-            self.retrieve_unchecked(dst.as_mut_ptr())
+        let first = self.size;
+        let ret = (N - first).min(entries.len());
+
+        for (dst, entry) in self.data[first..first + ret].iter_mut().zip(entries) {
+            entry.write_into(dst);
         }
+
+        self.size += ret;
+        self.count_lost(entries.len() - ret);
+        self.end_write();
+
+        ret
+    }
+
+    ///Drains all the entries into `dst`, which must have room for N of them. Returns how many
+    ///entries were copied and how many were lost since the last retrieve.
+    ///
+    ///# Safety
+    ///`dst` must be valid for writes of N entries, and must not point into this payload.
+    pub unsafe fn retrieve_unchecked(&mut self, dst: *mut T) -> (usize, usize) {
+        self.begin_write();
+
+        let retrieved = self.size;
+        let lost = self.lost;
+
+        std::ptr::copy_nonoverlapping(self.data.as_ptr(), dst, retrieved);
+        self.drained(retrieved);
+        self.size = 0;
+        self.lost = 0;
+
+        self.end_write();
+        (retrieved, lost)
+    }
+
+    ///Drains up to `dst.len()` of the oldest entries, leaving the rest for the next call, so that
+    ///the server can poll with a small buffer. Returns how many entries were copied and how many
+    ///were lost since the last retrieve. The remaining entries are moved to the front under the
+    ///lock, which keeps the layout (and the writer) as is.
+    pub fn retrieve_some(&mut self, dst: &mut [T]) -> (usize, usize) {
+        self.begin_write();
+
+        let valid = self.size;
+        let lost = self.lost;
+        let retrieved = valid.min(dst.len());
+
+        dst[0..retrieved].copy_from_slice(&self.data[0..retrieved]);
+        self.data.copy_within(retrieved..valid, 0);
+        self.drained(retrieved);
+        self.size = valid - retrieved;
+        self.lost = 0;
+
+        self.end_write();
+        (retrieved, lost)
+    }
+
+    ///Calls `f` on each valid entry, oldest first, under a single lock and without draining
+    ///them. Clients trying to push wait for it to return, so keep `f` cheap (e.g. serialize
+    ///into an in-memory buffer). It must not panic either, which would leave the payload locked.
+    pub fn for_each<F: FnMut(&T)>(&self, mut f: F) {
+        self.begin_write();
+
+        for entry in &self.data[0..self.size] {
+            f(entry);
+        }
+
+        self.end_write();
+    }
+
+    ///Same as `for_each()`, but drains the entries. Returns how many entries were visited and
+    ///how many were lost since the last retrieve, like `retrieve()`.
+    pub fn drain_each<F: FnMut(&T)>(&mut self, mut f: F) -> (usize, usize) {
+        self.begin_write();
+
+        let retrieved = self.size;
+        let lost = self.lost;
+
+        for entry in &self.data[0..retrieved] {
+            f(entry);
+        }
+
+        self.drained(retrieved);
+        self.size = 0;
+        self.lost = 0;
+
+        self.end_write();
+        (retrieved, lost)
+    }
+
+    ///Copies the valid entries without locking nor draining them, returning how many were copied.
+    ///This is the only way to read a read-only mapping. As the writer doesn't wait for us, the
+    ///copy is started over whenever it overlaps a write (see `seq`), so that no entry ever comes
+    ///out partially updated. Returns None if a writer kept it busy for `SNAPSHOT_ATTEMPTS`
+    ///attempts in a row (or died holding the lock).
+    pub fn snapshot(&self, dst: &mut [T]) -> Option<usize> {
+        assert!(dst.len() >= N, "destination slice has an unsufficient size");
+
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let before = self.seq.load(Ordering::Acquire);
+
+            if before & 1 != 0 {
+                yield_now();
+                continue;
+            }
+
+            let count = unsafe {
+                let count = std::ptr::read_volatile(&self.size).min(N); //Could be anything if the mapping is corrupted
+                std::ptr::copy_nonoverlapping(self.data.as_ptr(), dst.as_mut_ptr(), count);
+
+                count
+            };
+
+            //Our reads happen before the second load of `seq`
+            fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == before {
+                return Some(count);
+            }
+        }
+
+        None
+    }
+
+    //`debug_dump()` of this payload: its counters and the first few entries. Doesn't lock
+    //either, for the same reason as `snapshot()`.
+    #[cfg(feature = "server-mode")]
+    fn debug_dump<W: std::io::Write>(&self, name: &str, out: &mut W) -> std::io::Result<()> where T: fmt::Debug {
+        let (size, lost) = unsafe { (std::ptr::read_volatile(&self.size), std::ptr::read_volatile(&self.lost)) };
+        writeln!(out, "{}: size = {}/{}, dropped = {}, consumers = {:#b}, seq = {}", name, size, N, lost, self.consumers, self.seq.load(Ordering::Relaxed))?;
+
+        for entry in &self.data[0..size.min(N).min(DEBUG_DUMP_SAMPLES)] {
+            writeln!(out, "    {:?}", entry)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn retrieve(&mut self, dst: &mut [T]) -> (usize, usize) {
+        assert!(dst.len() >= N, "destination slice has an unsufficient size");
+
+        unsafe {
+            self.retrieve_unchecked(dst.as_mut_ptr())
+        }
+    }
+
+    //Multi-consumer mode: several readers (e.g. a live viewer and a recorder, possibly in
+    //different processes) each get every entry. Entries stay in `data` until every registered
+    //consumer read them, so a consumer that stops reading stalls the stream for everyone.
+    //The single-drain methods above still work, but drain the entries for every consumer.
+
+    ///Registers a new consumer, which will read the entries from the oldest one still
+    ///buffered. Returns None if `MAX_CONSUMERS` are already registered.
+    pub fn register_consumer(&mut self) -> Option<Consumer> {
+        self.begin_write();
+
+        let ret = (0..MAX_CONSUMERS).find(|&i| self.consumers & (1 << i) == 0).map(|i| {
+            self.consumers |= 1 << i;
+            self.cursors[i] = 0;
+            self.lost_seen[i] = self.lost_total;
+
+            Consumer(i)
+        });
+
+        self.end_write();
+        ret
+    }
+
+    ///Unregisters `consumer`, so that entries don't wait for it anymore
+    pub fn unregister_consumer(&mut self, consumer: Consumer) {
+        self.begin_write();
+        self.consumers &= !(1 << consumer.0);
+        self.reclaim();
+        self.end_write();
+    }
+
+    ///Copies the entries `consumer` didn't read yet into `dst` (at most `dst.len()` of them).
+    ///Returns how many entries were copied and how many were lost since this consumer's
+    ///previous read, or (0, 0) if `consumer` isn't registered anymore. Space is reclaimed once
+    ///every consumer read an entry.
+    pub fn retrieve_for(&mut self, consumer: Consumer, dst: &mut [T]) -> (usize, usize) {
+        self.begin_write();
+
+        let i = consumer.0;
+
+        if self.consumers & (1 << i) == 0 {
+            self.end_write();
+            return (0, 0);
+        }
+
+        let first = self.cursors[i].min(self.size);
+        let retrieved = (self.size - first).min(dst.len());
+        let lost = self.lost_total.wrapping_sub(self.lost_seen[i]);
+
+        dst[0..retrieved].copy_from_slice(&self.data[first..first + retrieved]);
+        self.cursors[i] = first + retrieved;
+        self.lost_seen[i] = self.lost_total;
+        self.reclaim();
+
+        self.end_write();
+        (retrieved, lost)
+    }
+
+    //Drops the entries that every consumer read, moving the others to the front. Lock must be held.
+    fn reclaim(&mut self) {
+        if self.consumers == 0 {
+            return;
+        }
+
+        let consumers = self.consumers;
+        let read = (0..MAX_CONSUMERS).filter(|&i| consumers & (1 << i) != 0).map(|i| self.cursors[i]).min().unwrap_or(0).min(self.size);
+
+        if read > 0 {
+            self.data.copy_within(read..self.size, 0);
+            self.size -= read;
+
+            for cursor in &mut self.cursors {
+                *cursor = cursor.saturating_sub(read);
+            }
+        }
+    }
+
+    //Escape hatch for readers the safe API can't serve (e.g. accumulating zones in place
+    //without any copy). Nothing here is checked: get the contract wrong and clients block
+    //forever, or the reader sees entries while they are being written.
+
+    ///Takes the lock of this payload, which blocks every client trying to push into it until
+    ///`unlock_raw()` is called. Returns true if the previous owner died while holding it (only
+    ///possible with the `robust-lock` feature), in which case the last entry might be garbled.
+    ///
+    ///# Safety
+    ///Must be followed by exactly one `unlock_raw()`, from the same thread, without panicking in
+    ///between. The safe methods of this payload take the lock too, so calling any of them while
+    ///holding it deadlocks.
+    pub unsafe fn lock_raw(&self) -> bool {
+        self.begin_write()
+    }
+
+    ///Releases the lock taken by `lock_raw()`
+    ///
+    ///# Safety
+    ///The calling thread must hold the lock, through `lock_raw()`.
+    pub unsafe fn unlock_raw(&self) {
+        self.end_write();
+    }
+
+    ///Returns a pointer to the first entry and how many entries are valid, oldest first. The
+    ///entries are NOT drained; see `mark_drained()`.
+    ///
+    ///# Safety
+    ///Only valid while the lock is held (see `lock_raw()`): once it's released, clients write
+    ///into these entries and change the size at any time, so neither the pointer nor the count
+    ///may be used anymore. Never write through the pointer.
+    pub unsafe fn data_ptr(&self) -> (*const T, usize) {
+        (self.data.as_ptr(), self.size.min(N))
+    }
+
+    ///Drops all entries and returns how many were lost since the last retrieve, like the
+    ///second value of `retrieve()`. This is what ends a custom drain.
+    ///
+    ///# Safety
+    ///The lock must be held (see `lock_raw()`).
+    pub unsafe fn mark_drained(&mut self) -> usize {
+        let lost = self.lost;
+
+        self.drained(self.size);
+        self.size = 0;
+        self.lost = 0;
+        lost
+    }
+}
+
+//The mapping is shared, so these must not be FUTEX_PRIVATE
+#[cfg(target_os = "linux")]
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: std::time::Duration) {
+    let ts = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long
+    };
+
+    //Returns right away if `word` isn't `expected` anymore; spurious wake ups are fine
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT, expected, &ts as *const libc::timespec);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+//Elsewhere, the server polls instead (see `SharedMemory::wait_for_data()`)
+#[cfg(not(target_os = "linux"))]
+fn futex_wait(_word: &AtomicU32, _expected: u32, timeout: std::time::Duration) {
+    std::thread::sleep(timeout.min(std::time::Duration::from_millis(1)));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wake(_word: &AtomicU32) {
+}
+
+impl ThreadTable {
+    unsafe fn init(&mut self) {
+        self.lock.init();
+        self.seq.store(0, Ordering::Relaxed);
+        self.count = 0;
+        self.updated = [false; THREAD_TABLE_ENTRIES];
+    }
+
+    //Same as `Payload::begin_write()`, without the dead owner report as there's nothing to recover
+    #[inline]
+    fn begin_write(&self) {
+        self.lock.lock();
+        self.seq.store(self.seq.load(Ordering::Relaxed) | 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    #[inline]
+    fn end_write(&self) {
+        self.seq.store(self.seq.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+        self.lock.unlock();
+    }
+
+    ///Sets the name of `thread` (truncated to `SHARED_STRING_MAX_SIZE` bytes), replacing the
+    ///previous one. Returns false if the table is full of entries the server didn't take yet.
+    pub fn set(&mut self, thread: usize, name: &str) -> bool {
+        //If the owner of a robust lock died, the entry it was writing might be garbled, but
+        //it's still a valid entry, so there's nothing to recover
+        self.begin_write();
+
+        let ret = match self.entries[0..self.count].iter().position(|x| x.thread == thread) {
+            Some(i) => {
+                if self.entries[i].name.make_str() != Some(name) {
+                    self.entries[i].name.set_copy(thread, name);
+                    self.updated[i] = true;
+                }
+
+                true
+            },
+
+            None if self.count < THREAD_TABLE_ENTRIES => {
+                let i = self.count;
+
+                self.entries[i].thread = thread;
+                self.entries[i].name.set_copy(thread, name);
+                self.updated[i] = true;
+                self.count += 1;
+                true
+            },
+
+            None => match self.updated.iter().position(|&updated| !updated) {
+                Some(i) => {
+                    //Evict the oldest entry, keeping all the others in order
+                    let last = THREAD_TABLE_ENTRIES - 1;
+
+                    self.entries.copy_within(i + 1.., i);
+                    self.updated.copy_within(i + 1.., i);
+                    self.entries[last].thread = thread;
+                    self.entries[last].name.set_copy(thread, name);
+                    self.updated[last] = true;
+                    true
+                },
+
+                None => false
+            }
+        };
+
+        self.end_write();
+        ret
+    }
+
+    ///Returns the name of `thread`, if known
+    pub fn get(&self, thread: usize) -> Option<String> {
+        self.lock.lock();
+        let ret = self.entries[0..self.count].iter().find(|x| x.thread == thread).and_then(|x| x.name.make_str()).map(String::from);
+        self.lock.unlock();
+
+        ret
+    }
+
+    ///Returns a copy of all the entries
+    pub fn entries(&self) -> Vec<ThreadNameData> {
+        self.lock.lock();
+        let ret = self.entries[0..self.count].to_vec();
+        self.lock.unlock();
+
+        ret
+    }
+
+    ///Copies all the entries without locking, like `Payload::snapshot()`, which makes it the
+    ///only way to read the table of a read-only mapping. Returns None if a writer kept it busy
+    ///for `SNAPSHOT_ATTEMPTS` attempts in a row.
+    pub fn snapshot(&self) -> Option<Vec<ThreadNameData>> {
+        let mut ret = Vec::with_capacity(THREAD_TABLE_ENTRIES);
+
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let before = self.seq.load(Ordering::Acquire);
+
+            if before & 1 != 0 {
+                yield_now();
+                continue;
+            }
+
+            unsafe {
+                let count = std::ptr::read_volatile(&self.count).min(THREAD_TABLE_ENTRIES); //Same as in `Payload::snapshot()`
+
+                std::ptr::copy_nonoverlapping(self.entries.as_ptr(), ret.as_mut_ptr(), count);
+                ret.set_len(count);
+            }
+
+            fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == before {
+                return Some(ret);
+            }
+        }
+
+        None
+    }
+
+    ///Returns the entries that were added or renamed since the last call, e.g. to store them
+    ///along with the zones retrieved at the same time
+    pub fn take_updates(&mut self) -> Vec<ThreadNameData> {
+        self.begin_write();
+
+        let mut ret = Vec::new();
+
+        for i in 0..self.count {
+            if self.updated[i] {
+                ret.push(self.entries[i]);
+                self.updated[i] = false;
+            }
+        }
+
+        self.end_write();
+        ret
+    }
+
+    //After a clear, everything is new again to whoever records from now on
+    fn mark_all_updated(&mut self) {
+        self.begin_write();
+
+        for i in 0..self.count {
+            self.updated[i] = true;
+        }
+
+        self.end_write();
+    }
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |h, &b| (h ^ (b as u32)).wrapping_mul(0x01000193))
+}
+
+pub(crate) fn fnv1a_64(bytes: &[u8], seed: u64) -> u64 {
+    bytes.iter().fold(seed, |h, &b| (h ^ (b as u64)).wrapping_mul(0x100000001b3))
+}
+
+pub(crate) const FNV1A_64_SEED: u64 = 0xcbf29ce484222325;
+
+///Identifier of a zone that is the same across runs and processes: a hash of its name and of
+///where it was declared. Never 0.
+pub(crate) fn stable_uid(name: &str, location: &str) -> usize {
+    let h = fnv1a_64(name.as_bytes(), FNV1A_64_SEED);
+    let h = fnv1a_64(&[0], h); //So that ("ab", "c") and ("a", "bc") differ
+    let h = fnv1a_64(location.as_bytes(), h);
+
+    (h as usize).max(1)
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+//Parses `count` chunks of a copy of the log ring, starting with the oldest one; an entry running
+//past the end of `raw` ends the parsing. Chunks are reassembled as described in `retrieve_logs()`.
+fn parse_logs(raw: &[u8], count: u32) -> Vec<(LogEntryHeader, String)> {
+    let mut ret = Vec::with_capacity(count as usize);
+    let mut pending: Option<(LogEntryHeader, Vec<u8>)> = None;
+    let mut offset = 0;
+
+    for _ in 0..count {
+        if offset + LOG_HEADER_SIZE > raw.len() {
+            break;
+        }
+
+        //Packed, so it has to be read unaligned
+        let header: LogEntryHeader = unsafe { std::ptr::read_unaligned(raw.as_ptr().add(offset) as *const LogEntryHeader) };
+        let start = offset + LOG_HEADER_SIZE;
+        let length = header.length;
+
+        if length > raw.len() - start {
+            break;
+        }
+
+        let chunk = &raw[start..start + length];
+        let (first, bytes) = pending.get_or_insert_with(|| (header, Vec::with_capacity(length)));
+
+        bytes.extend_from_slice(chunk);
+        first.flags = header.flags;
+        offset = start + length;
+
+        if header.flags & LOG_CONTINUED == 0 {
+            ret.push(pending.take().unwrap());
+        }
+    }
+
+    //Only happens if the data is corrupted, as all the chunks of a message are pushed at once
+    if let Some((mut header, bytes)) = pending {
+        header.flags = LOG_TRUNCATED;
+        ret.push((header, bytes));
+    }
+
+    ret.into_iter().map(|(mut header, bytes)| {
+        header.length = bytes.len();
+        (header, String::from_utf8_lossy(&bytes).into_owned())
+    }).collect()
+}
+
+impl SharedMemoryData {
+    ///Tells the server that the client is still alive
+    #[inline]
+    pub fn client_heartbeat(&self) {
+        self.client_heartbeat.store(unix_time(), Ordering::Relaxed);
+    }
+
+    ///Returns how many seconds ago the client was last seen, or `None` if it never showed up
+    pub fn client_last_seen(&self) -> Option<u64> {
+        match self.client_heartbeat.load(Ordering::Relaxed) {
+            0 => None,
+            t => Some(unix_time().saturating_sub(t))
+        }
+    }
+
+    ///Returns true if the server that created this shared memory still seems to be running: it
+    ///called `SharedMemory::heartbeat()` less than `SERVER_TIMEOUT` seconds ago or, on unix, its
+    ///process still exists. The heartbeat comes first since it doesn't cost a syscall, and it's
+    ///the only way to tell when the server runs in another PID namespace (e.g. in a container
+    ///sharing the segment through a volume), where its PID means nothing to us.
+    pub fn is_server_alive(&self) -> bool {
+        if unix_time().saturating_sub(self.server_heartbeat.load(Ordering::Relaxed)) < SERVER_TIMEOUT {
+            return true;
+        }
+
+        #[cfg(unix)]
+        unsafe {
+            //Signal 0 only checks whether the process exists. EPERM means it does, but isn't ours.
+            //ESRCH may also mean it's in another PID namespace, but then it should heartbeat.
+            libc::kill(self.server_pid as libc::pid_t, 0) == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+
+        #[cfg(not(unix))]
+        {
+            false
+        }
+    }
+
+    ///Called by the server to tell the clients whether it's lagging behind (typically,
+    ///when `retrieve` reports lost entries) or has caught up again.
+    #[inline]
+    pub fn set_overloaded(&self, overloaded: bool) {
+        self.overloaded.store(overloaded, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    ///Asks the client to clear all buffers and to restart its timeline, so that a fresh capture
+    ///can be started without restarting the app. This happens the next time the client sends
+    ///something; `is_clear_done()` tells when it's over. Returns the new generation.
+    pub fn request_clear(&self) -> u32 {
+        //Relaxed: the server doesn't publish anything along with the request, only the value matters
+        self.clear_generation.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    #[inline]
+    pub fn is_clear_done(&self) -> bool {
+        //Acquire: pairs with the client's Release once it's done with `clear_all()`
+        self.cleared_generation.load(Ordering::Acquire) == self.clear_generation.load(Ordering::Relaxed)
+    }
+
+    ///Empties all buffers. Each payload is cleared under its own lock, so concurrent
+    ///pushes either end up before the clear (and are removed) or after it.
+    pub fn clear_all(&mut self) {
+        self.frame_data.clear();
+        self.zone_data.clear();
+        self.heap_data.clear();
+        self.plot_data.clear();
+        self.plot_meta_data.clear();
+        self.zone_text_data.clear();
+        self.zone_histogram_data.clear();
+        self.heap_backtrace_data.clear();
+        self.thread_table.mark_all_updated();
+
+        self.lock_log_data();
+        self.log_data_count = 0;
+        self.log_data_start = 0;
+        self.log_data_size = 0;
+        self.unlock_log_data();
+
+        self.dropped_frames.store(0, Ordering::Relaxed);
+        self.depth_capped.store(0, Ordering::Relaxed);
+        self.dropped_logs.store(0, Ordering::Relaxed);
+    }
+
+    //Locks `log_data`; if its previous owner died in the middle of an update, the ring can't be
+    //trusted anymore and is emptied
+    fn lock_log_data(&mut self) {
+        let dead_owner = self.log_data_lock.lock();
+
+        //Same as `Payload::begin_write()`
+        self.log_data_seq.store(self.log_data_seq.load(Ordering::Relaxed) | 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        if dead_owner {
+            self.dropped_logs.fetch_add(self.log_data_count as u64, Ordering::Relaxed);
+            self.log_data_count = 0;
+            self.log_data_start = 0;
+            self.log_data_size = 0;
+        }
+    }
+
+    #[inline]
+    fn unlock_log_data(&mut self) {
+        self.log_data_seq.store(self.log_data_seq.load(Ordering::Relaxed).wrapping_add(1), Ordering::Release);
+        self.log_data_lock.unlock();
+    }
+
+    //Copies `src` into the log ring from `pos`, wrapping around its end
+    fn log_ring_write(&mut self, pos: usize, src: &[u8]) {
+        let first = src.len().min(LOG_DATA_SIZE - pos);
+
+        self.log_data[pos..pos + first].copy_from_slice(&src[0..first]);
+        self.log_data[0..src.len() - first].copy_from_slice(&src[first..]);
+    }
+
+    //Copies the log ring from `pos` into `dst`, wrapping around its end
+    fn log_ring_read(&self, pos: usize, dst: &mut [u8]) {
+        let first = dst.len().min(LOG_DATA_SIZE - pos);
+        let len = dst.len();
+
+        dst[0..first].copy_from_slice(&self.log_data[pos..pos + first]);
+        dst[first..].copy_from_slice(&self.log_data[0..len - first]);
+    }
+
+    //Removes the oldest message, with all of its chunks. The lock must be held.
+    fn evict_log(&mut self) {
+        while self.log_data_size > 0 {
+            let mut raw = [0u8; LOG_HEADER_SIZE];
+            self.log_ring_read(self.log_data_start as usize, &mut raw);
+
+            let header: LogEntryHeader = unsafe { std::ptr::read_unaligned(raw.as_ptr() as *const LogEntryHeader) };
+            let size = (LOG_HEADER_SIZE + header.length).min(self.log_data_size as usize);
+
+            self.log_data_start = ((self.log_data_start as usize + size) % LOG_DATA_SIZE) as u32;
+            self.log_data_size -= size as u32;
+            self.log_data_count = self.log_data_count.saturating_sub(1);
+
+            if header.flags & LOG_CONTINUED == 0 {
+                break;
+            }
+        }
+
+        self.dropped_logs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Appends a log message, split in chunks as described in `LogEntryHeader`. If there isn't
+    ///enough room left in `log_data`, the oldest messages are overwritten (and counted in
+    ///`dropped_logs`). Returns false if the message is too big for the whole region, in which
+    ///case it is cut and flagged with `LOG_TRUNCATED`. Never allocates.
+    pub fn push_log(&mut self, time: Time, level: LogLevel, color: Color, msg: &str) -> bool {
+        self.push_log_flags(time, level, color, msg, 0)
+    }
+
+    ///Same as `push_log()`, with structured fields encoded after the message (see `LOG_FIELDS`).
+    ///Unlike `push_log()`, this allocates.
+    pub fn push_log_kv(&mut self, time: Time, level: LogLevel, color: Color, msg: &str, fields: &[(&str, &str)]) -> bool {
+        self.push_log_flags(time, level, color, &encode_log_fields(msg, fields), LOG_FIELDS)
+    }
+
+    //`flags` are added to the flags of every chunk. `Color::AUTO` is replaced by the color of `level`.
+    fn push_log_flags(&mut self, time: Time, level: LogLevel, color: Color, msg: &str, flags: u8) -> bool {
+        let color = if color == Color::AUTO { level.default_color() } else { color };
+
+        //First find out how much of the message fits, headers included
+        let mut total = 0;
+        let mut kept = 0;
+
+        while total + LOG_HEADER_SIZE <= LOG_DATA_SIZE {
+            let rest = &msg[kept..];
+            let mut len = rest.len().min(LOG_CHUNK_SIZE).min(LOG_DATA_SIZE - total - LOG_HEADER_SIZE);
+
+            while !rest.is_char_boundary(len) {
+                len -= 1;
+            }
+
+            if len == 0 && !rest.is_empty() {
+                break;
+            }
+
+            total += LOG_HEADER_SIZE + len;
+            kept += len;
+
+            if kept == msg.len() {
+                break;
+            }
+        }
+
+        let ret = kept == msg.len();
+        let mut rest = &msg[0..kept];
+
+        self.lock_log_data();
+
+        while LOG_DATA_SIZE - (self.log_data_size as usize) < total {
+            self.evict_log();
+        }
+
+        loop {
+            let mut len = rest.len().min(LOG_CHUNK_SIZE);
+
+            while !rest.is_char_boundary(len) {
+                len -= 1;
+            }
+
+            let last = len == rest.len();
+            let header = LogEntryHeader {
+                time, color,
+                length: len,
+                flags: flags | match (last, ret) {
+                    (false, _)    => LOG_CONTINUED,
+                    (true, true)  => 0,
+                    (true, false) => LOG_TRUNCATED
+                },
+                level: level as u8
+            };
+
+            let mut raw = [0u8; LOG_HEADER_SIZE];
+            unsafe { std::ptr::write_unaligned(raw.as_mut_ptr() as *mut LogEntryHeader, header); }
+
+            let pos = (self.log_data_start + self.log_data_size) as usize % LOG_DATA_SIZE;
+            self.log_ring_write(pos, &raw);
+            self.log_ring_write((pos + LOG_HEADER_SIZE) % LOG_DATA_SIZE, &rest.as_bytes()[0..len]);
+
+            self.log_data_size += (LOG_HEADER_SIZE + len) as u32;
+            self.log_data_count += 1;
+            rest = &rest[len..];
+
+            if last {
+                break;
+            }
+        }
+
+        self.unlock_log_data();
+        ret
+    }
+
+    ///Drains the log messages. The written part of `log_data` is copied under the lock and
+    ///parsed afterwards; an entry running past it ends the parsing. Chunks are reassembled,
+    ///so the returned headers hold the total length and `LOG_TRUNCATED` if it was set.
+    #[cfg(feature = "server-mode")]
+    pub fn retrieve_logs(&mut self) -> Vec<(LogEntryHeader, String)> {
+        self.lock_log_data();
+
+        let mut raw = vec![0u8; (self.log_data_size as usize).min(LOG_DATA_SIZE)];
+        self.log_ring_read(self.log_data_start as usize % LOG_DATA_SIZE, &mut raw);
+        let count = self.log_data_count;
+
+        self.log_data_count = 0;
+        self.log_data_start = 0;
+        self.log_data_size = 0;
+        self.unlock_log_data();
+
+        parse_logs(&raw, count)
+    }
+
+    ///Copies the log messages without locking nor draining them, like `Payload::snapshot()`,
+    ///which makes it the only way to read the logs of a read-only mapping. Returns None if a
+    ///writer kept the ring busy for `SNAPSHOT_ATTEMPTS` attempts in a row.
+    pub fn snapshot_logs(&self) -> Option<Vec<(LogEntryHeader, String)>> {
+        let mut raw = vec![0u8; LOG_DATA_SIZE];
+
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let before = self.log_data_seq.load(Ordering::Acquire);
+
+            if before & 1 != 0 {
+                yield_now();
+                continue;
+            }
+
+            //Could be anything if the mapping is corrupted, or if a writer got in meanwhile
+            let (start, size, count) = unsafe {(
+                std::ptr::read_volatile(&self.log_data_start) as usize % LOG_DATA_SIZE,
+                (std::ptr::read_volatile(&self.log_data_size) as usize).min(LOG_DATA_SIZE),
+                std::ptr::read_volatile(&self.log_data_count)
+            )};
+
+            self.log_ring_read(start, &mut raw[0..size]);
+            fence(Ordering::Acquire);
+
+            if self.log_data_seq.load(Ordering::Relaxed) == before {
+                raw.truncate(size);
+                return Some(parse_logs(&raw, count));
+            }
+        }
+
+        None
+    }
+
+    ///Returns how many frames were dropped since the last call, and resets the counter
+    pub fn take_dropped_frames(&self) -> u64 {
+        self.dropped_frames.swap(0, Ordering::Relaxed)
+    }
+
+    ///Wakes the server up if it's blocked in `SharedMemory::wait_for_data()`. Clients call this
+    ///once per frame (coalescing everything pushed meanwhile); unless the server is actually
+    ///waiting, it's only an increment.
+    #[inline]
+    pub fn notify_data(&self) {
+        //SeqCst on both sides: either we see the server waiting, or it sees the new value
+        self.data_signal.fetch_add(1, Ordering::SeqCst);
+
+        if self.server_waiting.load(Ordering::SeqCst) {
+            futex_wake(&self.data_signal);
+        }
+    }
+
+    ///Returns how many log messages were overwritten since the last call, and resets the counter
+    pub fn take_dropped_logs(&self) -> u64 {
+        self.dropped_logs.swap(0, Ordering::Relaxed)
+    }
+
+    ///Returns how many zones were skipped for being too deep (see `temporal_lens::set_max_depth()`)
+    ///since the last call, and resets the counter. Non-zero means the call trees are truncated.
+    pub fn take_depth_capped(&self) -> u64 {
+        self.depth_capped.swap(0, Ordering::Relaxed)
+    }
+
+    unsafe fn init(&mut self) {
+        self.magic = MAGIC;
+        self.protocol_version = PROTOCOL_VERSION;
+        self.size_of_usize = std::mem::size_of::<usize>() as u32;
+        self.lock_kind = LOCK_KIND;
+        self.namespace = namespace_token();
+
+        self.server_pid = std::process::id();
+        self.server_heartbeat.store(unix_time(), Ordering::Relaxed);
+        self.client_heartbeat.store(0, Ordering::Relaxed);
+
+        self.frame_data.init();
+        self.zone_data.init();
+        self.heap_data.init();
+        self.plot_data.init();
+        self.plot_meta_data.init();
+        self.zone_text_data.init();
+        self.zone_histogram_data.init();
+        self.heap_backtrace_data.init();
+        self.thread_table.init();
+
+        self.dropped_frames.store(0, Ordering::Relaxed);
+        self.depth_capped.store(0, Ordering::Relaxed);
+        self.dropped_logs.store(0, Ordering::Relaxed);
+        self.overloaded.store(false, Ordering::Relaxed);
+        self.clear_generation.store(0, Ordering::Relaxed);
+        self.cleared_generation.store(0, Ordering::Relaxed);
+        self.timeline_resets.store(0, Ordering::Relaxed);
+        self.data_signal.store(0, Ordering::Relaxed);
+        self.server_waiting.store(false, Ordering::Relaxed);
+
+        self.log_data_lock.init();
+        self.log_data_seq.store(0, Ordering::Relaxed);
+        self.log_data_count = 0;
+        self.log_data_start = 0;
+        self.log_data_size = 0;
+    }
+}
+
+//How many entries of each payload `debug_dump()` prints
+#[cfg(feature = "server-mode")]
+const DEBUG_DUMP_SAMPLES: usize = 3;
+
+///Prints the header fields of `mem`, the counters of each payload and their first few
+///entries, for debugging layout and version issues. Nothing is locked nor drained, so this
+///works on a read-only mapping too, but entries being written meanwhile may come out garbled.
+#[cfg(feature = "server-mode")]
+pub fn debug_dump<W: std::io::Write>(mem: &SharedMemoryData, out: &mut W) -> std::io::Result<()> {
+    writeln!(out, "magic = {:#010X} (expected {:#010X})", mem.magic, MAGIC)?;
+    writeln!(out, "protocol_version = {:#010X} (expected {:#010X})", mem.protocol_version, PROTOCOL_VERSION)?;
+    writeln!(out, "size_of_usize = {}, lock_kind = {}, namespace = {:#010X}", mem.size_of_usize, mem.lock_kind, mem.namespace)?;
+    writeln!(out, "server_pid = {}, server_heartbeat = {}, client_heartbeat = {}", mem.server_pid, mem.server_heartbeat.load(Ordering::Relaxed), mem.client_heartbeat.load(Ordering::Relaxed))?;
+    writeln!(out, "dropped_frames = {}, depth_capped = {}, dropped_logs = {}, overloaded = {}",
+        mem.dropped_frames.load(Ordering::Relaxed), mem.depth_capped.load(Ordering::Relaxed),
+        mem.dropped_logs.load(Ordering::Relaxed), mem.overloaded.load(Ordering::Relaxed))?;
+    writeln!(out, "clear_generation = {}, cleared_generation = {}, timeline_resets = {}, data_signal = {}",
+        mem.clear_generation.load(Ordering::Relaxed), mem.cleared_generation.load(Ordering::Relaxed),
+        mem.timeline_resets.load(Ordering::Relaxed), mem.data_signal.load(Ordering::Relaxed))?;
+
+    mem.frame_data.debug_dump("frame_data", out)?;
+    mem.zone_data.debug_dump("zone_data", out)?;
+    mem.heap_data.debug_dump("heap_data", out)?;
+    mem.plot_data.debug_dump("plot_data", out)?;
+    mem.plot_meta_data.debug_dump("plot_meta_data", out)?;
+    mem.zone_text_data.debug_dump("zone_text_data", out)?;
+    mem.zone_histogram_data.debug_dump("zone_histogram_data", out)?;
+    mem.heap_backtrace_data.debug_dump("heap_backtrace_data", out)?;
+
+    let threads = unsafe { std::ptr::read_volatile(&mem.thread_table.count) }.min(THREAD_TABLE_ENTRIES);
+    writeln!(out, "thread_table: count = {}/{}", threads, THREAD_TABLE_ENTRIES)?;
+
+    for entry in &mem.thread_table.entries[0..threads.min(DEBUG_DUMP_SAMPLES)] {
+        writeln!(out, "    {:?}", entry)?;
+    }
+
+    let (count, start, size) = unsafe {
+        (std::ptr::read_volatile(&mem.log_data_count), std::ptr::read_volatile(&mem.log_data_start), std::ptr::read_volatile(&mem.log_data_size))
+    };
+
+    writeln!(out, "log_data: count = {}, start = {}, size = {}/{}", count, start, size, LOG_DATA_SIZE)?;
+
+    //Same parsing as `retrieve_logs()`, chunk by chunk: the header is packed, so it's read unaligned
+    let mut raw = vec![0u8; (size as usize).min(LOG_DATA_SIZE)];
+    mem.log_ring_read(start as usize % LOG_DATA_SIZE, &mut raw);
+
+    let mut offset = 0;
+
+    for _ in 0..(count as usize).min(DEBUG_DUMP_SAMPLES) {
+        if offset + LOG_HEADER_SIZE > raw.len() {
+            break;
+        }
+
+        let header: LogEntryHeader = unsafe { std::ptr::read_unaligned(raw.as_ptr().add(offset) as *const LogEntryHeader) };
+        let start = offset + LOG_HEADER_SIZE;
+        let length = header.length;
+
+        if length > raw.len() - start {
+            writeln!(out, "    {:?} (runs past the written data)", header)?;
+            break;
+        }
+
+        writeln!(out, "    {:?} {:?}", header, String::from_utf8_lossy(&raw[start..start + length]))?;
+        offset = start + length;
+    }
+
+    Ok(())
+}
+
+///With the `mmap-file` feature, a regular file mapped with `mmap()` is used whenever the
+///`shared_memory` crate can't create its mapping (which happens in some sandboxes and
+///containers), or always if `TEMPORAL_LENS_BACKEND` is set to `file`. The file lives next to the
+///link file of the regular backend, so put the data directory in a volume shared by the server
+///and the client. The layout of the shared memory is exactly the same. Unix only.
+#[cfg(all(unix, feature = "mmap-file", not(feature = "loopback")))]
+mod mmap_file {
+    use std::fs::OpenOptions;
+    use std::io::ErrorKind;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::ptr::null_mut;
+
+    use shared_memory::{Shmem, ShmemError};
+
+    pub struct FileHandle {
+        ptr: *mut u8,
+        len: usize,
+        path: PathBuf,
+        owner: bool
+    }
+
+    fn last_errno() -> u32 {
+        std::io::Error::last_os_error().raw_os_error().unwrap_or(0) as u32
+    }
+
+    unsafe fn map(file: &std::fs::File, len: usize) -> *mut u8 {
+        let ptr = libc::mmap(null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0);
+
+        if ptr == libc::MAP_FAILED {
+            null_mut()
+        } else {
+            ptr as *mut u8
+        }
+    }
+
+    impl FileHandle {
+        pub fn create(path: &Path, len: usize) -> Result<FileHandle, ShmemError> {
+            let file = OpenOptions::new().read(true).write(true).create_new(true).open(path).map_err(|err| match err.kind() {
+                ErrorKind::AlreadyExists => ShmemError::LinkExists,
+                _                        => ShmemError::LinkCreateFailed(err)
+            })?;
+
+            //Zero-filled, as `shared_memory` mappings are
+            if let Err(err) = file.set_len(len as u64) {
+                let _ = std::fs::remove_file(path);
+                return Err(ShmemError::LinkWriteFailed(err));
+            }
+
+            let ptr = unsafe { map(&file, len) };
+            if ptr.is_null() {
+                let errno = last_errno();
+                let _ = std::fs::remove_file(path);
+                return Err(ShmemError::MapCreateFailed(errno));
+            }
+
+            Ok(FileHandle { ptr, len, path: path.to_path_buf(), owner: true })
+        }
+
+        pub fn open(path: &Path) -> Result<FileHandle, ShmemError> {
+            let file = OpenOptions::new().read(true).write(true).open(path).map_err(|err| match err.kind() {
+                ErrorKind::NotFound => ShmemError::LinkDoesNotExist,
+                _                   => ShmemError::LinkOpenFailed(err)
+            })?;
+
+            let len = file.metadata().map_err(ShmemError::LinkReadFailed)?.len() as usize;
+            if len == 0 {
+                return Err(ShmemError::MapSizeZero);
+            }
+
+            let ptr = unsafe { map(&file, len) };
+            if ptr.is_null() {
+                return Err(ShmemError::MapOpenFailed(last_errno()));
+            }
+
+            Ok(FileHandle { ptr, len, path: path.to_path_buf(), owner: false })
+        }
+    }
+
+    impl Drop for FileHandle {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+
+            //`Shmem` already removes its link file when its owner is dropped, this does the same
+            //for the backing file, so that the next `open()` doesn't find a stale segment. Doing
+            //it for `Shmem` too would remove the link of a server that took over in the meantime.
+            if self.owner {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+
+    ///Either backend, behind the same interface as `Shmem`
+    pub enum Handle {
+        Shmem(Shmem),
+        File(FileHandle)
+    }
+
+    impl Handle {
+        #[inline]
+        pub fn as_ptr(&self) -> *mut u8 {
+            match self {
+                Handle::Shmem(x) => x.as_ptr(),
+                Handle::File(x)  => x.ptr
+            }
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            match self {
+                Handle::Shmem(x) => x.len(),
+                Handle::File(x)  => x.len
+            }
+        }
+
+        #[inline]
+        pub fn is_owner(&self) -> bool {
+            match self {
+                Handle::Shmem(x) => x.is_owner(),
+                Handle::File(x)  => x.owner
+            }
+        }
+
+        pub fn set_owner(&mut self, owner: bool) -> bool {
+            match self {
+                Handle::Shmem(x) => x.set_owner(owner),
+                Handle::File(x)  => std::mem::replace(&mut x.owner, owner)
+            }
+        }
+    }
+
+    ///Whether `TEMPORAL_LENS_BACKEND=file` asks for this backend only
+    pub fn forced() -> bool {
+        std::env::var("TEMPORAL_LENS_BACKEND").map(|x| x.trim().eq_ignore_ascii_case("file")).unwrap_or(false)
     }
 }
 
-impl SharedMemoryData {
-    unsafe fn init(&mut self) {
-        self.magic = MAGIC;
-        self.protocol_version = PROTOCOL_VERSION;
-        self.size_of_usize = std::mem::size_of::<usize>() as u32;
+///With the `loopback` feature, `create()` and `open()` don't use actual shared memory but a
+///segment allocated in the current process, so that tests can play both the server and the
+///client without requiring anything else to run. It's obviously useless for anything else.
+#[cfg(feature = "loopback")]
+mod loopback {
+    use std::alloc::{alloc_zeroed, Layout};
+    use std::ptr::null_mut;
+    use std::sync::atomic::{AtomicPtr, Ordering};
 
-        self.frame_data.init();
-        self.zone_data.init();
-        self.heap_data.init();
-        self.plot_data.init();
+    use shared_memory::ShmemError;
 
-        self.log_data_lock.unlock(); //Init hack
-        self.log_data_count = 0;
+    //Never freed so that pointers held by clients remain valid, even after the "server" is gone
+    //Acquire/Release so that whoever opens a segment sees its zeroed contents
+    static SEGMENT: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+
+    pub struct Handle {
+        ptr: *mut u8,
+        owner: bool
+    }
+
+    impl Handle {
+        pub fn create(layout: Layout) -> Result<Handle, ShmemError> {
+            if !SEGMENT.load(Ordering::Acquire).is_null() {
+                return Err(ShmemError::LinkExists);
+            }
+
+            let ptr = unsafe { alloc_zeroed(layout) };
+            if ptr.is_null() {
+                return Err(ShmemError::MapCreateFailed(0));
+            }
+
+            match SEGMENT.compare_exchange(null_mut(), ptr, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_)  => Ok(Handle { ptr, owner: true }),
+                Err(_) => Err(ShmemError::LinkExists) //Lost the race; leaking `ptr` is fine here
+            }
+        }
+
+        pub fn exists() -> bool {
+            !SEGMENT.load(Ordering::Acquire).is_null()
+        }
+
+        pub fn open() -> Result<Handle, ShmemError> {
+            let ptr = SEGMENT.load(Ordering::Acquire);
+
+            if ptr.is_null() {
+                Err(ShmemError::LinkDoesNotExist)
+            } else {
+                Ok(Handle { ptr, owner: false })
+            }
+        }
+
+        #[inline]
+        pub fn as_ptr(&self) -> *mut u8 {
+            self.ptr
+        }
+
+        #[inline]
+        pub fn is_owner(&self) -> bool {
+            self.owner
+        }
+
+        pub fn set_owner(&mut self, owner: bool) -> bool {
+            std::mem::replace(&mut self.owner, owner)
+        }
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            //Same as when a server exits: the next `create()` starts from scratch
+            if self.owner {
+                let _ = SEGMENT.compare_exchange(self.ptr, null_mut(), Ordering::AcqRel, Ordering::Acquire);
+            }
+        }
     }
 }
 
 pub struct SharedMemory {
     data: *mut SharedMemoryData,
-    handle: Shmem
+    handle: Handle,
+    last_signal: u32,                     //Value of `data_signal` last seen by `wait_for_data()`
+    placement_errors: Vec<PlacementError> //What `CreateOptions` couldn't get, see `placement_errors()`
 }
 
 unsafe impl Send for SharedMemory {}
 
+impl SharedMemory {
+    //For the owners that share it between threads (see `Context`), which can't hand out `&mut`s
+    pub(crate) fn as_ptr(&self) -> *mut SharedMemoryData {
+        self.data
+    }
+}
+
+///Memory placement of the segment created by `SharedMemory::create_with()`, for servers on big
+///machines where the locality of the profiler's own memory shows in its overhead. These are
+///hints: they only do something on Linux, and a failure (e.g. no such node) doesn't prevent the
+///creation; `SharedMemory::placement_errors()` tells what went wrong instead.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CreateOptions {
+    pub huge_pages: bool,      //Ask for transparent huge pages; with the regular backend (tmpfs), this requires `/sys/kernel/mm/transparent_hugepage/shmem_enabled` to be at least `advise`
+    pub numa_node: Option<u32> //NUMA node to bind the segment to. None lets the kernel place each page where it's first touched, usually on the server's node
+}
+
+#[derive(Debug)]
+pub enum SharedMemoryCreateError {
+    ShmemError(ShmemError),
+    AlreadyRunning(u32),                  //Another live server (whose PID is provided) owns the shared memory
+    Incompatible(SharedMemoryOpenError)   //An existing shared memory was found, but is from another version so it's impossible to tell if it's stale
+}
+
+///Why the segment isn't placed as requested by `CreateOptions`
+#[derive(Debug)]
+pub enum PlacementError {
+    HugePages(std::io::Error), //`madvise(MADV_HUGEPAGE)` failed, e.g. because transparent huge pages are disabled
+    NumaNode(std::io::Error)   //`mbind()` failed, e.g. because there's no such node
+}
+
 #[derive(Debug)]
 pub enum SharedMemoryOpenError {
     ShmemError(ShmemError),
     BadMagic,
-    ProtocolMismatch,
-    PlatformMismatch
+    ProtocolMismatch(CompatReport),
+    PlatformMismatch,
+    LockMismatch,      //Either the server or the client was built with the `robust-lock` feature, not both
+    ServerNotRunning,  //The shared memory was left behind by a server that isn't running anymore
+    NamespaceMismatch  //The shared memory belongs to another namespace (see `namespace()`)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VersionComponent {
+    Major,
+    Minor,
+    Patch
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompatReport {
+    pub ours: (u16, u16, u16),             //Our `PROTOCOL_VERSION`, decoded
+    pub theirs: (u16, u16, u16),           //The version we were compared with, decoded
+    pub mismatch: Option<VersionComponent> //Most significant component that differs, if any
+}
+
+impl CompatReport {
+    #[inline]
+    pub fn is_compatible(&self) -> bool {
+        self.mismatch.is_none()
+    }
+}
+
+impl fmt::Display for CompatReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b) = (self.ours, self.theirs);
+        write!(f, "protocol {}.{}.{} vs {}.{}.{}", a.0, a.1, a.2, b.0, b.1, b.2)?;
+
+        match self.mismatch {
+            Some(x) => write!(f, " ({:?} differs)", x),
+            None    => f.write_str(" (compatible)")
+        }
+    }
+}
+
+///Splits a version following the `PROTOCOL_VERSION` layout (`0xMM_mm_pppp`) into its components
+#[inline]
+pub const fn decode_version(version: u32) -> (u16, u16, u16) {
+    ((version >> 24) as u16, ((version >> 16) & 0xFF) as u16, (version & 0xFFFF) as u16)
+}
+
+///Returns the `(major, minor, patch)` protocol version of this build
+#[inline]
+pub const fn protocol_version() -> (u16, u16, u16) {
+    decode_version(PROTOCOL_VERSION)
 }
 
+///Compares another protocol version with ours. Any change to the layout of the shared memory
+///bumps the patch, so only the exact same version is compatible.
+pub fn check_compat(theirs: u32) -> CompatReport {
+    let ours = protocol_version();
+    let theirs = decode_version(theirs);
+    let mismatch = if ours.0 != theirs.0 {
+        Some(VersionComponent::Major)
+    } else if ours.1 != theirs.1 {
+        Some(VersionComponent::Minor)
+    } else if ours.2 != theirs.2 {
+        Some(VersionComponent::Patch)
+    } else {
+        None
+    };
+
+    CompatReport { ours, theirs, mismatch }
+}
+
+//Set by `SharedMemory::set_path()`, None to follow `temporal_lens::instance_name()`
+static PATH_OVERRIDE: std::sync::Mutex<Option<PathBuf>> = std::sync::Mutex::new(None);
+
 impl SharedMemory {
+    ///Path of the shared memory of the current instance (see `temporal_lens::instance_name()`),
+    ///unless another one was given to `set_path()`
     pub fn get_path() -> PathBuf {
-        let mut ret = super::get_data_dir();
+        match &*PATH_OVERRIDE.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(path) => path.clone(),
+            None       => Self::get_path_for(super::instance_name().as_deref())
+        }
+    }
+
+    ///Makes `get_path()` (and thus `open()`, `create()`...) use `path` from now on,
+    ///None going back to the default
+    pub fn set_path(path: Option<PathBuf>) {
+        *PATH_OVERRIDE.lock().unwrap_or_else(|e| e.into_inner()) = path;
+    }
+
+    ///Path of the shared memory of a given instance, None being the default one
+    pub fn get_path_for(instance: Option<&str>) -> PathBuf {
+        let mut ret = match instance {
+            Some(name) => super::get_data_dir_for(name),
+            None       => super::get_data_dir()
+        };
+
         ret.push("shmem");
+        ret
+    }
+
+    ///Name of the mapping on Windows, where it's found by name rather than through a link file.
+    ///It is derived from `get_path()` so that different data directories (e.g. users) don't collide.
+    pub fn get_os_id() -> String {
+        format!("Local\\temporal-lens-{:08x}", fnv1a(Self::get_path().to_string_lossy().to_lowercase().as_bytes()))
+    }
+
+    #[cfg(all(windows, not(feature = "loopback")))]
+    fn conf() -> ShmemConf {
+        ShmemConf::new().os_id(Self::get_os_id())
+    }
+
+    #[cfg(all(not(windows), not(feature = "loopback")))]
+    fn conf() -> ShmemConf {
+        ShmemConf::new().flink(Self::get_path().as_path())
+    }
+
+    ///Returns true if a shared memory seems to exist, without opening it. It might be stale though.
+    ///On Windows, the mapping has to be opened to find out.
+    pub fn exists() -> bool {
+        #[cfg(all(windows, not(feature = "loopback")))]
+        let ret = Self::conf().open().is_ok();
+
+        #[cfg(all(not(windows), not(feature = "loopback")))]
+        let ret = Self::get_path().exists() || (cfg!(feature = "mmap-file") && Self::get_file_path().exists());
+
+        #[cfg(feature = "loopback")]
+        let ret = Handle::exists();
 
         ret
     }
 
+    ///Removes the shared memory left behind by a server that crashed. Does nothing if there's
+    ///none, but fails if its server is still running or if it's from another version (in which
+    ///case it's impossible to tell whether it's stale). On Windows, mappings go away by themselves
+    ///once nobody has them open anymore, so there's only something to do if clients still do;
+    ///this can't be forced, but `create()` takes such a mapping over.
+    pub fn remove_stale() -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        if !Self::exists() {
+            return Ok(());
+        }
+
+        match Self::open_handle() {
+            Ok((mut old, data)) => {
+                let old_data = unsafe { &*data };
+
+                if old_data.is_server_alive() {
+                    return Err(Error::new(ErrorKind::Other, format!("the server (PID {}) is still running", old_data.server_pid)));
+                }
+
+                //Dropping it as the owner cleans everything up
+                old.set_owner(true);
+                Ok(())
+            },
+
+            //The mapping itself is gone, only its files are left
+            Err(SharedMemoryOpenError::ShmemError(ref err)) if is_mapping_gone(err) => Self::remove_files(),
+            Err(SharedMemoryOpenError::ShmemError(err)) => Err(Error::new(ErrorKind::Other, format!("{:?}", err))),
+            Err(err)                                    => Err(Error::new(ErrorKind::InvalidData, format!("{:?}", err)))
+        }
+    }
+
+    ///Path of the file used by the `mmap-file` backend
+    pub fn get_file_path() -> PathBuf {
+        Self::get_path().with_extension("mmap")
+    }
+
+    //Removes what a dead mapping left behind: the link file of the regular backend and, with
+    //`mmap-file`, the file of that backend. Either may not be there.
+    fn remove_files() -> std::io::Result<()> {
+        let remove = |path: PathBuf| match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _                                                      => Ok(())
+        };
+
+        remove(Self::get_path())?;
+
+        if cfg!(feature = "mmap-file") {
+            remove(Self::get_file_path())?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(not(feature = "loopback"), not(all(unix, feature = "mmap-file"))))]
+    fn create_handle() -> Result<Handle, ShmemError> {
+        Self::conf()
+            .size(std::mem::size_of::<SharedMemoryData>())
+            .create()
+    }
+
+    #[cfg(all(unix, feature = "mmap-file", not(feature = "loopback")))]
+    fn create_handle() -> Result<Handle, ShmemError> {
+        let size = std::mem::size_of::<SharedMemoryData>();
+        let file = || FileHandle::create(&Self::get_file_path(), size).map(Handle::File);
+
+        if mmap_file::forced() {
+            return file();
+        }
+
+        match Self::conf().size(size).create() {
+            Ok(x)                        => Ok(Handle::Shmem(x)),
+            Err(ShmemError::LinkExists)  => Err(ShmemError::LinkExists),
+            Err(_)                       => file()
+        }
+    }
+
+    #[cfg(feature = "loopback")]
+    fn create_handle() -> Result<Handle, ShmemError> {
+        Handle::create(std::alloc::Layout::new::<SharedMemoryData>())
+    }
+
     ///Creates and maps the shared memory
     ///
-    ///Note that the directory provided by `temporal_lens::get_data_dir()`
+    ///Note that the directory provided by `temporal_lens::get_instance_data_dir()`
     ///must be created prior to calling this function, otherwise it will
-    ///just fail.
-    pub fn create() -> Result<SharedMemory, ShmemError> {
-        let handle = ShmemConf::new()
-            .flink(Self::get_path().as_path())
-            .size(std::mem::size_of::<SharedMemoryData>())
-            .create()?;
+    ///just fail. To serve a named instance, set `TEMPORAL_LENS_INSTANCE`
+    ///before calling this.
+    ///
+    ///If a previous server crashed and left its shared memory behind, it
+    ///is removed and replaced. If it is still running, `AlreadyRunning`
+    ///is returned instead.
+    pub fn create() -> Result<SharedMemory, SharedMemoryCreateError> {
+        Self::create_with(CreateOptions::default())
+    }
+
+    ///Same as `create()`, but with control over where the segment lives in memory
+    pub fn create_with(opts: CreateOptions) -> Result<SharedMemory, SharedMemoryCreateError> {
+        let handle = match Self::create_handle() {
+            Err(ShmemError::LinkExists) | Err(ShmemError::MappingIdExists) => {
+                match Self::open_handle() {
+                    Ok((mut old, data)) => {
+                        let old_data = unsafe { &*data };
+
+                        if old_data.is_server_alive() {
+                            return Err(SharedMemoryCreateError::AlreadyRunning(old_data.server_pid));
+                        }
+
+                        //Stale; take ownership so that dropping it cleans everything up
+                        old.set_owner(true);
+
+                        //Except on Windows, where it's the clients of the dead server that keep the
+                        //mapping (and thus its name) alive. Take it over instead.
+                        #[cfg(all(windows, not(feature = "loopback")))]
+                        return Ok(Self::init(old, opts));
+                    },
+
+                    Err(SharedMemoryOpenError::ShmemError(ref err)) if is_mapping_gone(err) => {
+                        //The mapping itself is gone, only its files are left
+                        let _ = Self::remove_files();
+                    },
+
+                    Err(SharedMemoryOpenError::ShmemError(err)) => return Err(SharedMemoryCreateError::ShmemError(err)),
+                    Err(err)                                    => return Err(SharedMemoryCreateError::Incompatible(err))
+                }
+
+                Self::create_handle().map_err(SharedMemoryCreateError::ShmemError)?
+            },
+
+            result => result.map_err(SharedMemoryCreateError::ShmemError)?
+        };
+
+        Ok(Self::init(handle, opts))
+    }
 
+    fn init(handle: Handle, opts: CreateOptions) -> SharedMemory {
         let data = handle.as_ptr() as *mut SharedMemoryData;
+
+        //Before `init()` touches the pages, so that they're allocated accordingly
+        let placement_errors = place_segment(data as *mut u8, std::mem::size_of::<SharedMemoryData>(), opts);
+
         unsafe {
             (*data).init();
         }
 
-        Ok(SharedMemory { data, handle })
+        SharedMemory { data, handle, last_signal: 0, placement_errors }
     }
 
-    pub fn open() -> Result<SharedMemory, SharedMemoryOpenError> {
-        let handle = ShmemConf::new()
-            .flink(Self::get_path().as_path())
-            .open().map_err(SharedMemoryOpenError::ShmemError)?;
+    ///What the `CreateOptions` given to `create_with()` couldn't get. Empty if everything was
+    ///applied, or if the segment wasn't created by this instance.
+    pub fn placement_errors(&self) -> &[PlacementError] {
+        &self.placement_errors
+    }
+
+    fn open_handle() -> Result<(Handle, *mut SharedMemoryData), SharedMemoryOpenError> {
+        #[cfg(all(not(feature = "loopback"), not(all(unix, feature = "mmap-file"))))]
+        let handle = Self::conf().open().map_err(SharedMemoryOpenError::ShmemError)?;
+
+        //If neither works, the error of the regular backend is the most relevant one
+        #[cfg(all(unix, feature = "mmap-file", not(feature = "loopback")))]
+        let handle = if mmap_file::forced() {
+            FileHandle::open(&Self::get_file_path()).map(Handle::File).map_err(SharedMemoryOpenError::ShmemError)?
+        } else {
+            Self::conf().open().map(Handle::Shmem)
+                .or_else(|err| FileHandle::open(&Self::get_file_path()).map(Handle::File).map_err(|_| err))
+                .map_err(SharedMemoryOpenError::ShmemError)?
+        };
+
+        #[cfg(feature = "loopback")]
+        let handle = Handle::open().map_err(SharedMemoryOpenError::ShmemError)?;
 
         let data = handle.as_ptr() as *mut SharedMemoryData;
-        let data_ref = unsafe { &mut *data };
+        let data_ref = unsafe { &*data };
 
         if data_ref.magic != MAGIC {
             Err(SharedMemoryOpenError::BadMagic)
         } else if data_ref.protocol_version != PROTOCOL_VERSION {
-            Err(SharedMemoryOpenError::ProtocolMismatch)
+            Err(SharedMemoryOpenError::ProtocolMismatch(check_compat(data_ref.protocol_version)))
         } else if data_ref.size_of_usize != std::mem::size_of::<usize>() as u32 {
             //Might happen if the lib was compiled for x86 and the server was compiled for x86_64
             Err(SharedMemoryOpenError::PlatformMismatch)
+        } else if data_ref.lock_kind != LOCK_KIND {
+            Err(SharedMemoryOpenError::LockMismatch)
+        } else if data_ref.namespace != namespace_token() {
+            Err(SharedMemoryOpenError::NamespaceMismatch)
         } else {
-            Ok(SharedMemory { data, handle })
+            Ok((handle, data))
+        }
+    }
+
+    pub fn open() -> Result<SharedMemory, SharedMemoryOpenError> {
+        let (handle, data) = Self::open_handle()?;
+        let last_signal = unsafe { (*data).data_signal.load(Ordering::Relaxed) };
+
+        Ok(SharedMemory { data, handle, last_signal, placement_errors: Vec::new() })
+    }
+
+    ///Blocks until a client calls `notify_data()` (which it does at the end of each frame), or
+    ///until `timeout` elapses. Returns immediately if it did so since the last call, and false
+    ///on timeout. This is a futex on Linux.
+    ///
+    ///Known limitation: on other platforms, it polls every millisecond, which costs the server
+    ///a few wake ups per frame and delays it by up to a millisecond. A futex can't be shared
+    ///between processes there (`WaitOnAddress` only works within one), so doing better would
+    ///take a named event next to the mapping, which clients would have to open as well.
+    pub fn wait_for_data(&mut self, timeout: std::time::Duration) -> bool {
+        let data = unsafe { &*self.data };
+        let deadline = Instant::now() + timeout;
+
+        data.server_waiting.store(true, Ordering::SeqCst);
+
+        let ret = loop {
+            let current = data.data_signal.load(Ordering::SeqCst);
+
+            if current != self.last_signal {
+                self.last_signal = current;
+                break true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                break false;
+            }
+
+            futex_wait(&data.data_signal, current, deadline - now);
+        };
+
+        data.server_waiting.store(false, Ordering::Relaxed);
+        ret
+    }
+
+    ///Opens the shared memory for reading only. Where the platform supports it (unix),
+    ///the pages are also protected so that any accidental write crashes right away
+    ///instead of silently corrupting the data of the profiled app.
+    ///
+    ///Since locks can't be taken, only snapshots can be taken through this (see `SharedMemoryRo`).
+    pub fn open_ro() -> Result<SharedMemoryRo, SharedMemoryOpenError> {
+        let (handle, data) = Self::open_handle()?;
+        let protected = protect_read_only(&handle);
+
+        Ok(SharedMemoryRo { data, handle, protected })
+    }
+
+    ///Tells the clients that the server is still alive. Servers should call this regularly
+    ///(at least every `SERVER_TIMEOUT` seconds), typically each time they retrieve data.
+    pub fn heartbeat(&self) {
+        self.server_heartbeat.store(unix_time(), Ordering::Relaxed);
+    }
+}
+
+//Whether `open()` failed because there's no mapping behind the files anymore, as opposed to one
+//that's there but can't be opened (e.g. without the rights to), whose files must be left alone
+fn is_mapping_gone(err: &ShmemError) -> bool {
+    match err {
+        ShmemError::LinkDoesNotExist | ShmemError::MapSizeZero => true,
+
+        #[cfg(unix)]
+        ShmemError::MapOpenFailed(errno) => *errno == libc::ENOENT as u32,
+
+        #[cfg(windows)]
+        ShmemError::MapOpenFailed(errno) => *errno == winapi::shared::winerror::ERROR_FILE_NOT_FOUND,
+
+        _ => false
+    }
+}
+
+#[cfg(all(target_os = "linux", not(feature = "loopback")))]
+fn place_segment(ptr: *mut u8, size: usize, opts: CreateOptions) -> Vec<PlacementError> {
+    const MPOL_BIND: libc::c_long = 2;
+    const MPOL_MF_MOVE: libc::c_long = 1 << 1; //Pages that are already there need to move too
+
+    let mut errors = Vec::new();
+
+    //Only the huge-page-aligned parts of the segment can be backed by huge pages; the kernel
+    //quietly keeps regular pages for the rest, which is why this isn't an error
+    if opts.huge_pages && unsafe { libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_HUGEPAGE) } != 0 {
+        errors.push(PlacementError::HugePages(std::io::Error::last_os_error()));
+    }
+
+    if let Some(node) = opts.numa_node {
+        let bits = 8 * std::mem::size_of::<libc::c_ulong>();
+        let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+        mask[node as usize / bits] |= 1 << (node as usize % bits);
+
+        //The kernel ignores the last bit of `maxnode`, hence the + 1
+        let max_node = (mask.len() * bits + 1) as libc::c_ulong;
+
+        if unsafe { libc::syscall(libc::SYS_mbind, ptr, size, MPOL_BIND, mask.as_ptr(), max_node, MPOL_MF_MOVE) } != 0 {
+            errors.push(PlacementError::NumaNode(std::io::Error::last_os_error()));
+        }
+    }
+
+    errors
+}
+
+//The loopback segment lives in our own heap, which isn't ours to move around
+#[cfg(any(not(target_os = "linux"), feature = "loopback"))]
+fn place_segment(_ptr: *mut u8, _size: usize, _opts: CreateOptions) -> Vec<PlacementError> {
+    Vec::new()
+}
+
+#[cfg(all(unix, not(feature = "loopback")))]
+fn protect_read_only(handle: &Handle) -> bool {
+    unsafe {
+        libc::mprotect(handle.as_ptr() as *mut libc::c_void, handle.len(), libc::PROT_READ) == 0
+    }
+}
+
+//The loopback segment lives in our own heap; protecting it would prevent the client from writing too
+#[cfg(any(not(unix), feature = "loopback"))]
+fn protect_read_only(_handle: &Handle) -> bool {
+    false
+}
+
+pub struct SharedMemoryRo {
+    data: *const SharedMemoryData,
+    handle: Handle,
+    protected: bool
+}
+
+unsafe impl Send for SharedMemoryRo {}
+
+///A payload of a read-only mapping, which can only be copied (see `Payload::snapshot()`)
+pub struct PayloadRo<'a, T: Sized + Copy, const N: usize>(&'a Payload<T, N>);
+
+impl<'a, T: Sized + Copy, const N: usize> PayloadRo<'a, T, N> {
+    ///How many entries `snapshot()` may copy
+    pub const CAPACITY: usize = N;
+
+    ///See `Payload::snapshot()`
+    #[inline]
+    pub fn snapshot(&self, dst: &mut [T]) -> Option<usize> {
+        self.0.snapshot(dst)
+    }
+}
+
+//Unlike `SharedMemory`, this doesn't deref to `SharedMemoryData`: most of its methods take
+//locks or update counters, which would crash on a protected mapping. Only what reads without
+//writing anything is exposed.
+impl SharedMemoryRo {
+    ///Returns true if the mapping is actually write protected
+    #[inline]
+    pub fn is_protected(&self) -> bool {
+        self.protected
+    }
+
+    #[inline]
+    fn data(&self) -> &SharedMemoryData {
+        unsafe {
+            &*self.data
         }
     }
+
+    pub fn frame_data(&self) -> PayloadRo<'_, FrameData, FRAME_ENTRIES> {
+        PayloadRo(&self.data().frame_data)
+    }
+
+    pub fn zone_data(&self) -> PayloadRo<'_, ZoneData, ZONE_ENTRIES> {
+        PayloadRo(&self.data().zone_data)
+    }
+
+    pub fn heap_data(&self) -> PayloadRo<'_, HeapData, HEAP_ENTRIES> {
+        PayloadRo(&self.data().heap_data)
+    }
+
+    pub fn plot_data(&self) -> PayloadRo<'_, PlotData, PLOT_ENTRIES> {
+        PayloadRo(&self.data().plot_data)
+    }
+
+    pub fn plot_meta_data(&self) -> PayloadRo<'_, PlotMetaData, PLOT_META_ENTRIES> {
+        PayloadRo(&self.data().plot_meta_data)
+    }
+
+    pub fn zone_text_data(&self) -> PayloadRo<'_, ZoneTextData, ZONE_TEXT_ENTRIES> {
+        PayloadRo(&self.data().zone_text_data)
+    }
+
+    pub fn zone_histogram_data(&self) -> PayloadRo<'_, ZoneHistogramData, ZONE_HISTOGRAM_ENTRIES> {
+        PayloadRo(&self.data().zone_histogram_data)
+    }
+
+    pub fn heap_backtrace_data(&self) -> PayloadRo<'_, HeapBacktraceData, HEAP_BACKTRACE_ENTRIES> {
+        PayloadRo(&self.data().heap_backtrace_data)
+    }
+
+    ///See `ThreadTable::snapshot()`
+    pub fn thread_names(&self) -> Option<Vec<ThreadNameData>> {
+        self.data().thread_table.snapshot()
+    }
+
+    ///See `SharedMemoryData::snapshot_logs()`
+    pub fn logs(&self) -> Option<Vec<(LogEntryHeader, String)>> {
+        self.data().snapshot_logs()
+    }
 }
 
 impl Deref for SharedMemory {
@@ -0,0 +1,159 @@
+///Async-signal-safe pushes, meant to be called from a signal handler (e.g. a `SIGPROF`
+///timer doing statistical profiling). The regular push path can't be used there: it takes
+///spin locks, borrows thread locals and might even open the shared memory, any of which can
+///deadlock (or allocate) if the signal interrupted the same thread in the middle of it.
+///
+///Instead, the functions of this module only write into a fixed-size, lock-free queue living
+///in a static (so there's nothing to allocate nor to touch beforehand). The queue is emptied
+///into the shared memory from normal context by `flush_signal_queue()`, which each frame does
+///as well. If the queue is full, new entries are dropped.
+///
+///Things to keep in mind:
+/// - Only `&'static str` names and messages are accepted, as nothing can be copied
+/// - Timestamps use `clock::now()`; with the `tsc` feature make sure it's calibrated first
+///   (`TscClock::calibrate()`), as calibration isn't signal-safe
+/// - Nothing is sent if profiling is disabled, but `TEMPORAL_LENS_ENABLE` is never read here
+
+use crate::{clock, core, Color};
+use crate::shmem::{PlotData, WriteInto};
+
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const SIGNAL_QUEUE_SIZE: usize = 1024;
+
+#[derive(Copy, Clone)]
+enum SignalEvent {
+    Empty,
+    Plot(&'static str, f64),
+    Message(&'static str)
+}
+
+//`seq` is even when the slot is free (`2 * lap`) and odd once it holds the entry of that
+//lap (`2 * lap + 1`), where `lap` is how many times the queue wrapped around. Since the
+//queue starts zeroed, nothing has to be initialized at runtime.
+#[derive(Copy, Clone)]
+struct Slot {
+    seq: usize, //Only ever accessed as an AtomicUsize
+    time: MaybeUninit<clock::Timestamp>,
+    color: Color,
+    event: SignalEvent
+}
+
+static mut SLOTS: [Slot; SIGNAL_QUEUE_SIZE] = [Slot { seq: 0, time: MaybeUninit::uninit(), color: Color::from_hex(0), event: SignalEvent::Empty }; SIGNAL_QUEUE_SIZE];
+static HEAD: AtomicUsize = AtomicUsize::new(0);      //Next position to write
+static TAIL: AtomicUsize = AtomicUsize::new(0);      //Next position to read, only touched by the consumer
+static CONSUMING: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+unsafe fn seq_of(index: usize) -> &'static AtomicUsize {
+    //AtomicUsize has the same in-memory representation as usize
+    &*(&SLOTS[index].seq as *const usize as *const AtomicUsize)
+}
+
+fn push(color: Color, event: SignalEvent) -> bool {
+    if !core::is_enabled_no_env() {
+        return false;
+    }
+
+    let time = clock::now();
+    let mut pos = HEAD.load(Ordering::Relaxed);
+
+    loop {
+        let index = pos % SIGNAL_QUEUE_SIZE;
+        let lap = pos / SIGNAL_QUEUE_SIZE;
+        //Acquire: pairs with the consumer's Release, so that it's done reading the slot
+        let seq = unsafe { seq_of(index) }.load(Ordering::Acquire);
+
+        if seq == 2 * lap {
+            match HEAD.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => unsafe {
+                    let slot = &mut SLOTS[index];
+
+                    slot.time = MaybeUninit::new(time);
+                    slot.color = color;
+                    slot.event = event;
+
+                    //Release: publishes the entry to the consumer
+                    seq_of(index).store(2 * lap + 1, Ordering::Release);
+                    return true;
+                },
+                Err(x) => pos = x
+            }
+        } else if seq < 2 * lap {
+            //The consumer didn't free this slot yet: the queue is full
+            return false;
+        } else {
+            //Another thread (or handler) took this position
+            pos = HEAD.load(Ordering::Relaxed);
+        }
+    }
+}
+
+///Queues a plot value. Async-signal-safe. Returns false if the queue is full.
+pub fn signal_plot(name: &'static str, value: f64, color: Color) -> bool {
+    push(color, SignalEvent::Plot(name, value))
+}
+
+///Queues a log message. Async-signal-safe. Returns false if the queue is full.
+pub fn signal_message(msg: &'static str, color: Color) -> bool {
+    push(color, SignalEvent::Message(msg))
+}
+
+struct SignalPlot {
+    time: crate::shmem::Time,
+    color: Color,
+    value: f64,
+    name: &'static str
+}
+
+impl WriteInto<PlotData> for SignalPlot {
+    fn write_into(&self, target: &mut PlotData) {
+        target.time = self.time;
+        target.color = self.color;
+        target.set_value(self.value);
+        target.name.set(self.name, true);
+    }
+}
+
+///Sends what signal handlers queued to the server. Must NOT be called from a signal handler.
+///Returns how many entries were dequeued; if the shared memory isn't open, they're lost.
+///Concurrent calls are fine: all but one return 0 right away.
+pub fn flush_signal_queue() -> usize {
+    if CONSUMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    let (mut mem, start_time) = unsafe { core::get_shmem_data_and_start_time() };
+    let mut pos = TAIL.load(Ordering::Relaxed);
+    let first = pos;
+
+    loop {
+        let index = pos % SIGNAL_QUEUE_SIZE;
+        let lap = pos / SIGNAL_QUEUE_SIZE;
+        let seq = unsafe { seq_of(index) };
+
+        if seq.load(Ordering::Acquire) != 2 * lap + 1 {
+            break;
+        }
+
+        let slot = unsafe { SLOTS[index] };
+        seq.store(2 * lap + 2, Ordering::Release);
+        pos += 1;
+
+        if let Some(mem) = mem.as_deref_mut() {
+            let time = core::timeline_time(start_time, unsafe { slot.time.assume_init() });
+
+            match slot.event {
+                SignalEvent::Plot(name, value) => { mem.plot_data.push(&SignalPlot { time, color: slot.color, value, name }); },
+                SignalEvent::Message(msg)      => { mem.push_log(time, crate::LogLevel::Info, slot.color, msg); },
+                SignalEvent::Empty             => {}
+            }
+        }
+    }
+
+    TAIL.store(pos, Ordering::Relaxed);
+    CONSUMING.store(false, Ordering::Release);
+
+    pos - first
+}
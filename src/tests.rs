@@ -30,15 +30,65 @@ impl shmem::WriteInto<shmem::ZoneData> for TestZoneData {
     fn write_into(&self, target: &mut shmem::ZoneData) {
         target.uid = self.uid;
         target.color = self.color;
+        target.start = self.end.saturating_sub(self.duration);
         target.end = self.end;
         target.duration = self.duration;
+        target.cpu_duration = 0;
         target.depth = self.depth;
         target.name.set(self.name, self.copy_strings);
-        target.thread.set("thread", self.copy_strings);
+        target.thread = 1;
+        target.loc.set_special(0, None);
+        target.category.set_special(0, None);
+        target.instance = 0;
+    }
+}
+
+struct TestPlotData {
+    time: shmem::Time,
+    value: f64,
+    name: &'static str,
+    copy_name: bool
+}
+
+impl shmem::WriteInto<shmem::PlotData> for TestPlotData {
+    fn write_into(&self, target: &mut shmem::PlotData) {
+        target.time = self.time;
+        target.color = shmem::Color::from_rgb(1, 2, 3);
+        target.set_value(self.value);
+        target.name.set(self.name, self.copy_name);
+    }
+}
+
+struct TestFrameData {
+    number: u64,
+    end: shmem::Time,
+    duration: shmem::Duration
+}
+
+impl shmem::WriteInto<shmem::FrameData> for TestFrameData {
+    fn write_into(&self, target: &mut shmem::FrameData) {
+        target.number = self.number;
+        target.start = self.end.saturating_sub(self.duration);
+        target.end = self.end;
+        target.duration = self.duration;
+        target.gpu_present = 0;
+        target.set.set_special(0, None);
+    }
+}
+
+fn retrieve_all<T: Copy, const N: usize>(payload: &mut shmem::Payload<T, N>) -> (Vec<T>, usize) {
+    let mut ret = Vec::with_capacity(N);
+
+    unsafe {
+        let (retrieved, lost) = payload.retrieve_unchecked(ret.as_mut_ptr());
+        ret.set_len(retrieved);
+
+        (ret, lost)
     }
 }
 
 #[test]
+#[cfg_attr(feature = "loopback", ignore)]
 fn test_shmem() {
     let mut mem = shmem::SharedMemory::open().expect("Failed to open shared memory. Make sure the server is actually running.");
     let mut rng = rand::thread_rng();
@@ -50,7 +100,7 @@ fn test_shmem() {
 
         let test = TestZoneData {
             uid: ez.uid,
-            color: rng.gen(),
+            color: shmem::Color::from_hex(rng.gen()),
             end: rng.gen(),
             duration: rng.gen(),
             depth: rng.gen(),
@@ -96,3 +146,1103 @@ fn test_scope_profiling() {
         frame_delimiter!();
     }
 }
+
+#[cfg(feature = "enabled")]
+#[test]
+fn test_generic_zones() {
+    //The uid is resolved when the zone starts
+    fn zone_of<T>() -> (usize, &'static str) {
+        let zone = crate::start_zone_profiling_generic!("generic", T);
+        (zone.info.uid(), zone.info.name)
+    }
+
+    let (uid_a, name_a) = zone_of::<u32>();
+    let (uid_b, name_b) = zone_of::<String>();
+
+    assert_eq!(name_a, "generic<u32>");
+    assert_eq!(name_b, "generic<alloc::string::String>");
+    assert_ne!(uid_a, 0);
+    assert_ne!(uid_a, uid_b);
+
+    //From another thread too, since they share the same `ZoneInfo`
+    assert_eq!(std::thread::spawn(zone_of::<u32>).join().unwrap(), (uid_a, name_a));
+}
+
+#[cfg(not(feature = "enabled"))]
+#[test]
+fn test_disabled_is_free() {
+    //Everything should compile down to nothing: no handle carries state or runs code on drop.
+    //That no static ends up in the binary either is checked by `ci/check-disabled.sh`.
+    const _: [(); 0] = [(); std::mem::size_of::<crate::Zone>() + std::mem::needs_drop::<crate::Zone>() as usize];
+    const _: [(); 0] = [(); std::mem::size_of::<crate::FrameMarker>() + std::mem::needs_drop::<crate::FrameMarker>() as usize];
+    const _: [(); 0] = [(); std::mem::size_of::<crate::PlotDownsampler>() + std::mem::needs_drop::<crate::PlotDownsampler>() as usize];
+    const _: [(); 0] = [(); std::mem::size_of::<crate::Context>() + std::mem::needs_drop::<crate::Context>() as usize];
+
+    assert!(!crate::is_enabled());
+
+    let zone = crate::start_zone_profiling!("disabled_zone", color: red);
+    zone.end();
+
+    profile_scope!("disabled_scope");
+    frame_delimiter!();
+    crate::frame_mark_named!("disabled_set");
+    assert!(!crate::mark_frame());
+    let plotted = crate::plot!("disabled_plot", 1.0);
+    assert!(!plotted);
+    let logged = crate::tl_log!("disabled {}", 1);
+    assert!(!logged);
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_zone_codec_round_trip() {
+    let zones: Vec<shmem::ZoneData> = (0..100).map(|i| {
+        let ez = &EXAMPLE_ZONES[i as usize % EXAMPLE_ZONES.len()];
+        let mut ret: shmem::ZoneData = unsafe { std::mem::zeroed() }; //Plain data
+
+        shmem::WriteInto::write_into(&TestZoneData {
+            uid: ez.uid,
+            color: shmem::Color::from_hex(0x00123456),
+            end: 1_000_000 + i * 1000,
+            duration: 500 + i,
+            depth: i as u32 % 4,
+            name: ez.name,
+            copy_strings: i < 3
+        }, &mut ret);
+
+        ret
+    }).collect();
+
+    let encoded = crate::zone_codec::encode_zones(&zones);
+    let decoded = crate::zone_codec::decode_zones(&encoded).expect("Failed to decode zones");
+
+    //Same fields as what bincode sees, in a fraction of the size
+    assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&zones).unwrap());
+    assert!(encoded.len() * 3 < bincode::serialize(&zones).unwrap().len());
+    assert!(crate::zone_codec::decode_zones(&encoded[0..encoded.len() - 1]).is_err());
+
+    //Even zones the dictionaries know take at least the minimum
+    for i in 1..zones.len() {
+        let added = crate::zone_codec::encode_zones(&zones[0..=i]).len() - crate::zone_codec::encode_zones(&zones[0..i]).len();
+        assert!(added >= crate::zone_codec::MIN_ENCODED_ZONE_SIZE, "zone {} took {} bytes", i, added);
+    }
+
+    //Hostile counts fail without reserving room for them first
+    let mut hostile = vec![0xFF; 9];
+    hostile.push(0x01);
+    hostile.extend_from_slice(&encoded[1..]);
+    assert_eq!(crate::zone_codec::decode_zones(&hostile).err(), Some(crate::zone_codec::DecodeError::Truncated));
+}
+
+//Loopback tests share the same segment
+#[cfg(feature = "loopback")]
+static LOOPBACK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_loopback_round_trip() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let name = EXAMPLE_ZONES[0].name;
+
+    //Zones: the name should only be sent with the first one
+    for (i, &copy_strings) in [true, false].iter().enumerate() {
+        let zone = TestZoneData {
+            uid: 61,
+            color: shmem::Color::from_hex(0x00123456),
+            end: 1000 * (i as u64 + 1),
+            duration: 500,
+            depth: i as u32,
+            name, copy_strings
+        };
+
+        assert!(client.zone_data.push(&zone));
+    }
+
+    let (zones, lost) = retrieve_all(&mut server.zone_data);
+    assert_eq!((zones.len(), lost), (2, 0));
+    assert_eq!(zones[0].uid, 61);
+    assert_eq!(zones[0].color.to_hex(), 0x00123456);
+    assert_eq!((zones[1].end, zones[1].duration, zones[1].depth), (2000, 500, 1));
+    assert_eq!(zones[0].name.make_str(), Some(name));
+    assert_eq!(zones[0].thread, 1);
+    assert!(!zones[1].name.has_contents());
+    assert_eq!(zones[1].name.get_key(), zones[0].name.get_key());
+
+    //Plots
+    assert!(client.plot_data.push(&TestPlotData { time: 10, value: 4.5, name: "plot", copy_name: true }));
+    assert!(client.plot_data.push(&TestPlotData { time: 20, value: -1.0, name: "plot", copy_name: false }));
+
+    let (plots, _) = retrieve_all(&mut server.plot_data);
+    assert_eq!(plots.len(), 2);
+    assert_eq!((plots[0].time, plots[0].value, plots[0].name.make_str()), (10, 4.5, Some("plot")));
+    assert_eq!((plots[1].time, plots[1].value, plots[1].name.make_str()), (20, -1.0, None));
+
+    //Frames
+    for i in 0..3 {
+        assert!(client.frame_data.push(&TestFrameData { number: i, end: 100 * (i + 1), duration: 100 }));
+    }
+
+    let (frames, _) = retrieve_all(&mut server.frame_data);
+    assert_eq!(frames.iter().map(|f| f.number).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(frames.iter().all(|f| f.set.get_key() == 0 && !f.set.has_contents()));
+
+    //Everything was drained
+    assert_eq!(retrieve_all(&mut server.zone_data).0.len(), 0);
+}
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_payload_overflow() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let capacity = shmem::FRAME_ENTRIES;
+    let extra = 10;
+
+    //The oldest entries are kept, and what doesn't fit is dropped and counted
+    for i in 0..(capacity + extra) as u64 {
+        assert_eq!(client.frame_data.push(&TestFrameData { number: i, end: i + 1, duration: 1 }), i < capacity as u64);
+    }
+
+    let (frames, lost) = retrieve_all(&mut server.frame_data);
+    assert_eq!((frames.len(), lost), (capacity, extra));
+    assert!(frames.iter().enumerate().all(|(i, f)| f.number == i as u64));
+
+    //Retrieving starts over
+    assert!(client.frame_data.push(&TestFrameData { number: 1000, end: 1, duration: 1 }));
+
+    let (frames, lost) = retrieve_all(&mut server.frame_data);
+    assert_eq!((frames.len(), lost), (1, 0));
+    assert_eq!(frames[0].number, 1000);
+
+    //A stalled server: the size stays clamped and every extra push is counted exactly once
+    let pushed = (0..1000).filter(|&i| client.frame_data.push(&TestFrameData { number: i, end: i + 1, duration: 1 })).count();
+    assert_eq!(pushed, capacity);
+
+    let (frames, lost) = retrieve_all(&mut server.frame_data);
+    assert_eq!((frames.len(), lost), (capacity, 1000 - capacity));
+    assert_eq!(lost, 744);
+}
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_snapshot_not_torn() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    //Keeps overwriting the same slots with entries whose fields all derive from their number
+    let writer = {
+        let done = done.clone();
+
+        std::thread::spawn(move || {
+            let mut i = 1;
+
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                let frames: Vec<_> = (i..i + 64).map(|n| TestFrameData { number: n, end: 2 * n, duration: n }).collect();
+
+                client.frame_data.push_slice(&frames);
+
+                //Leaves the entries there once in a while, so that some snapshots aren't empty
+                if i % (64 * 64) == 1 {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+
+                client.frame_data.clear();
+                i += 64;
+            }
+        })
+    };
+
+    //Plain data, so all zeroes is a valid entry
+    let mut dst = vec![unsafe { std::mem::zeroed::<shmem::FrameData>() }; shmem::FRAME_ENTRIES];
+
+    //Entries must be consistent, and all come from the same batch
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(500);
+    let mut checked = 0;
+
+    while std::time::Instant::now() < deadline {
+        if let Some(count) = server.frame_data.snapshot(&mut dst) {
+            let first = dst[0].number;
+            assert!(dst[0..count].iter().enumerate().all(|(i, f)| f.number == first + i as u64 && f.start == f.number && f.end == 2 * f.number && f.duration == f.number));
+
+            if count > 0 {
+                checked += 1;
+            }
+        }
+    }
+
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    writer.join().unwrap();
+
+    //Otherwise, nothing was tested
+    assert!(checked > 0, "no snapshot caught the writer between a push and a clear");
+}
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_read_only() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+
+    assert!(client.frame_data.push(&TestFrameData { number: 7, end: 20, duration: 10 }));
+    assert!(client.thread_table.set(3, "worker"));
+    assert!(client.push_log(5, shmem::LogLevel::Info, shmem::Color::AUTO, &"é".repeat(shmem::LOG_CHUNK_SIZE)));
+
+    let ro = shmem::SharedMemory::open_ro().expect("Failed to open loopback shared memory");
+    let mut frames = vec![unsafe { std::mem::zeroed::<shmem::FrameData>() }; shmem::FRAME_ENTRIES]; //Plain data
+
+    assert_eq!(ro.frame_data().snapshot(&mut frames), Some(1));
+    assert_eq!(frames[0].number, 7);
+
+    let names = ro.thread_names().expect("thread table busy");
+    assert_eq!(names.iter().map(|x| (x.thread, x.name.make_str())).collect::<Vec<_>>(), vec![(3, Some("worker"))]);
+
+    //Reassembled, and left there for the server
+    let logs = ro.logs().expect("log ring busy");
+    assert_eq!(logs.iter().map(|(_, msg)| msg.clone()).collect::<Vec<_>>(), vec!["é".repeat(shmem::LOG_CHUNK_SIZE)]);
+    assert_eq!(ro.logs().map(|x| x.len()), Some(1));
+}
+
+#[cfg(all(feature = "loopback", feature = "enabled"))]
+#[test]
+fn test_context() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let context: &'static crate::Context = Box::leak(Box::new(crate::Context::open().expect("Failed to open loopback shared memory")));
+
+    for _ in 0..3 {
+        //Names may be built by any constant expression
+        crate::profile_scope_to!(context, concat!("Context", " zone"), color: blue);
+        crate::frame_delimiter_to!(context);
+    }
+
+    //Not batched, so no flush required
+    let (zones, _) = retrieve_all(&mut server.zone_data);
+    assert_eq!(zones.len(), 3);
+    assert_eq!(zones[0].name.make_str(), Some("Context zone"));
+    assert!(server.thread_table.get(zones[0].thread).is_some());
+
+    let (frames, _) = retrieve_all(&mut server.frame_data);
+    assert_eq!(frames.iter().map(|f| f.number).collect::<Vec<_>>(), vec![0, 1, 2]);
+}
+
+#[cfg(all(feature = "loopback", feature = "enabled"))]
+#[test]
+fn test_context_names() {
+    static INFO: crate::ZoneInfo = crate::ZoneInfo::new(shmem::Color::from_hex(0x0000FF00), "Already sent zone");
+
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let context: &'static crate::Context = Box::leak(Box::new(crate::Context::open().expect("Failed to open loopback shared memory")));
+
+    //What the global connection sent doesn't count for the context, which sends it once too
+    INFO.name_sent.set_sent(crate::core::connection_epoch());
+
+    for _ in 0..3 {
+        let _zone = crate::Zone::new_to(context, &INFO);
+    }
+
+    let (zones, _) = retrieve_all(&mut server.zone_data);
+    assert_eq!(zones.iter().map(|z| z.name.make_str()).collect::<Vec<_>>(), vec![Some("Already sent zone"), None, None]);
+    assert!(zones.iter().all(|z| z.name.get_key() == zones[0].name.get_key()));
+}
+
+#[cfg(all(feature = "loopback", feature = "enabled"))]
+#[test]
+fn test_plot_downsampler() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+
+    //Connects right away even if an earlier attempt failed. The global connection may still be
+    //on the segment of an earlier test, so read from whichever one it uses.
+    crate::preinit_with(Default::default()).expect("Failed to open loopback shared memory");
+    let mem = unsafe { crate::core::get_shmem_data_and_start_time() }.0.expect("Failed to open loopback shared memory");
+    retrieve_all(&mut mem.plot_data);
+
+    {
+        let mut plot = crate::PlotDownsampler::new("downsampled", shmem::Color::from_hex(0x000000FF), std::time::Duration::from_nanos(100));
+
+        //Only the min, the max and the last value of each bucket are sent, once it ends...
+        plot.push_at(3.0, 0);
+        plot.push_at(1.0, 10);
+        plot.push_at(5.0, 20);
+        plot.push_at(2.0, 30);
+
+        //...min and max are both kept when they share a time, and this last bucket is sent on drop
+        plot.push_at(4.0, 100);
+        plot.push_at(-4.0, 100);
+    }
+
+    let (plots, _) = retrieve_all(&mut mem.plot_data);
+    assert_eq!(plots.iter().map(|p| (p.time, p.value)).collect::<Vec<_>>(), vec![(10, 1.0), (20, 5.0), (30, 2.0), (100, -4.0), (100, 4.0)]);
+    assert_eq!(plots.iter().map(|p| p.name.make_str()).collect::<Vec<_>>(), vec![Some("downsampled"), None, None, None, None]);
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+#[test]
+fn test_log_round_trip() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let red = shmem::Color::from_hex(0x00FF0000);
+    let long = "é".repeat(shmem::LOG_CHUNK_SIZE + 10);
+    let too_long = "x".repeat(shmem::LOG_DATA_SIZE + 1);
+
+    //Messages split in chunks come back whole
+    assert!(client.push_log(5, shmem::LogLevel::Warn, red, "hello"));
+    assert!(client.push_log(6, shmem::LogLevel::Info, red, &long));
+
+    let logs = server.retrieve_logs();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0].1, "hello");
+    assert_eq!(logs[0].0.level(), shmem::LogLevel::Warn);
+    assert_eq!(logs[1].1, long);
+    assert_eq!({ logs[1].0.length }, long.len());
+    assert_eq!({ logs[1].0.flags } & (shmem::LOG_CONTINUED | shmem::LOG_TRUNCATED), 0);
+
+    //What doesn't fit in `log_data` is cut and flagged
+    assert!(!client.push_log(7, shmem::LogLevel::Info, red, &too_long));
+
+    let logs = server.retrieve_logs();
+    assert_eq!(logs.len(), 1);
+    assert!(logs[0].1.len() < too_long.len() && too_long.starts_with(&logs[0].1));
+    assert_eq!({ logs[0].0.flags } & shmem::LOG_TRUNCATED, shmem::LOG_TRUNCATED);
+
+    //Everything was drained
+    assert!(server.retrieve_logs().is_empty());
+}
+
+//Records a few chunks with `codec`, returning the trace and each chunk as bincode
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+fn record_trace(codec: crate::trace::Codec) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let mut trace = Vec::new();
+    let mut recorded = Vec::new();
+    let mut recorder = crate::trace::TraceRecorder::with_codec(&mut trace, codec).expect("Failed to write trace header");
+
+    for i in 0..3 {
+        let zone = TestZoneData {
+            uid: 61,
+            color: shmem::Color::from_hex(0x00123456),
+            end: 1000 * (i + 1),
+            duration: 500,
+            depth: 0,
+            name: EXAMPLE_ZONES[0].name,
+            copy_strings: i == 0
+        };
+
+        assert!(client.zone_data.push(&zone));
+        assert!(client.frame_data.push(&TestFrameData { number: i, end: 1000 * (i + 1), duration: 1000 }));
+        assert!(client.plot_data.push(&TestPlotData { time: 1000 * i, value: i as f64, name: "plot", copy_name: i == 0 }));
+        assert!(client.push_log(1000 * i, shmem::LogLevel::Info, shmem::Color::from_hex(0x00FF0000), "log"));
+
+        let chunk = recorder.record(&mut server).expect("Failed to record chunk");
+        assert_eq!((chunk.zones.len(), chunk.frames.len(), chunk.plots.len(), chunk.logs.len()), (1, 1, 1, 1));
+        recorded.push(bincode::serialize(&chunk).unwrap());
+    }
+
+    recorder.flush().unwrap();
+    drop(recorder);
+
+    (trace, recorded)
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+fn replay_trace(trace: &[u8]) -> Result<Vec<Vec<u8>>, crate::trace::TraceError> {
+    crate::trace::TraceReader::new(trace)?.map(|chunk| Ok(bincode::serialize(&chunk?).unwrap())).collect()
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+#[test]
+fn test_trace_round_trip() {
+    use crate::trace::{TraceReader, TraceError, Codec};
+
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let (trace, recorded) = record_trace(Codec::None);
+
+    assert_eq!(TraceReader::new(&trace[..]).unwrap().header().codec, Codec::None);
+    assert_eq!(replay_trace(&trace).unwrap(), recorded);
+
+    //Cutting the trace between two chunks just ends it early, anywhere else is an error
+    let header_end = crate::trace::TRACE_MAGIC.len() + bincode::serialized_size(TraceReader::new(&trace[..]).unwrap().header()).unwrap() as usize;
+    let first_end = header_end + 4 + u32::from_le_bytes([trace[header_end], trace[header_end + 1], trace[header_end + 2], trace[header_end + 3]]) as usize;
+
+    assert!(replay_trace(&trace[..header_end]).unwrap().is_empty());
+    assert_eq!(replay_trace(&trace[..first_end]).unwrap(), recorded[0..1]);
+    assert!(replay_trace(&trace[..header_end - 1]).is_err());
+    assert!(matches!(replay_trace(&trace[..header_end + 2]), Err(TraceError::Io(_))));
+    assert!(matches!(replay_trace(&trace[..trace.len() - 1]), Err(TraceError::Io(_))));
+
+    //Hostile sizes are rejected before allocating anything
+    let mut huge = trace[..header_end].to_vec();
+    huge.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(replay_trace(&huge), Err(TraceError::ChunkTooLarge(_))));
+
+    //Recorded by a server with another shared memory layout
+    let header = crate::trace::TraceHeader { version: crate::trace::TRACE_VERSION, protocol_version: shmem::PROTOCOL_VERSION + 1, start: 0, codec: Codec::None };
+    let mut bumped = crate::trace::TRACE_MAGIC.to_vec();
+    bumped.extend_from_slice(&bincode::serialize(&header).unwrap());
+    bumped.extend_from_slice(&trace[header_end..]);
+    assert!(matches!(TraceReader::new(&bumped[..]), Err(TraceError::ProtocolMismatch(v)) if v == shmem::PROTOCOL_VERSION + 1));
+}
+
+#[cfg(all(feature = "loopback", feature = "compression"))]
+#[test]
+fn test_compressed_trace_round_trip() {
+    use crate::trace::{TraceReader, TraceError, Codec};
+
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    for &codec in &[Codec::Lz4, Codec::Zstd] {
+        let (trace, recorded) = record_trace(codec);
+
+        //The reader picks the codec from the header
+        assert_eq!(TraceReader::new(&trace[..]).unwrap().header().codec, codec);
+        assert_eq!(replay_trace(&trace).unwrap(), recorded);
+        assert!(replay_trace(&trace[..trace.len() - 1]).is_err());
+    }
+
+    //A small chunk claiming to decompress to more than `MAX_CHUNK_SIZE` is rejected before allocating
+    let (trace, _) = record_trace(Codec::Lz4);
+    let header_end = crate::trace::TRACE_MAGIC.len() + bincode::serialized_size(TraceReader::new(&trace[..]).unwrap().header()).unwrap() as usize;
+    let mut bomb = trace[..header_end].to_vec();
+
+    bomb.extend_from_slice(&8u32.to_le_bytes());
+    bomb.extend_from_slice(&(crate::trace::MAX_CHUNK_SIZE as u32 + 1).to_le_bytes());
+    bomb.extend_from_slice(&[0; 4]);
+    assert!(matches!(replay_trace(&bomb), Err(TraceError::ChunkTooLarge(_))));
+}
+
+#[cfg(all(feature = "server-mode", not(feature = "compression")))]
+#[test]
+fn test_compression_unsupported() {
+    use crate::trace::{TraceRecorder, TraceError, Codec};
+
+    assert!(matches!(TraceRecorder::with_codec(Vec::new(), Codec::Lz4), Err(TraceError::UnsupportedCodec(Codec::Lz4))));
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+#[test]
+fn test_remote_round_trip() {
+    use crate::remote::{Forwarder, RemoteReceiver};
+    use std::io::Write as _;
+
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let receiver = std::thread::spawn(move || {
+        let mut receiver = RemoteReceiver::accept(&listener).expect("Failed to accept forwarder");
+        let mut received = Vec::new();
+
+        while let Some(chunk) = receiver.receive().expect("Failed to receive chunk") {
+            received.push(bincode::serialize(&chunk).unwrap());
+        }
+
+        //A peer that hangs up in the middle of the header is refused
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(&crate::trace::TRACE_MAGIC).unwrap();
+        drop(stream);
+
+        (received, RemoteReceiver::accept(&listener).is_err())
+    });
+
+    let mut forwarder = Forwarder::connect(addr).expect("Failed to connect to receiver");
+    let mut forwarded = Vec::new();
+
+    for i in 0..3 {
+        assert!(client.frame_data.push(&TestFrameData { number: i, end: 100 * (i + 1), duration: 100 }));
+        forwarded.push(bincode::serialize(&forwarder.forward(&mut server).expect("Failed to forward chunk")).unwrap());
+    }
+
+    drop(forwarder);
+
+    let (received, refused) = receiver.join().unwrap();
+    assert_eq!(received, forwarded);
+    assert!(refused);
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+#[test]
+fn test_thread_table_eviction() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let capacity = shmem::THREAD_TABLE_ENTRIES;
+
+    for thread in 1..=capacity {
+        assert!(client.thread_table.set(thread, &format!("thread {}", thread)));
+    }
+
+    //Nothing can be evicted until the server has seen the names
+    assert!(!client.thread_table.set(capacity + 1, "late"));
+    assert_eq!(server.thread_table.take_updates().len(), capacity);
+
+    //Now the oldest goes first, and renames update in place
+    assert!(client.thread_table.set(capacity + 1, "late"));
+    assert!(client.thread_table.set(2, "renamed"));
+    assert!(client.thread_table.set(capacity + 2, "later"));
+
+    assert_eq!(server.thread_table.get(1), None);
+    assert_eq!(server.thread_table.get(2).as_deref(), Some("renamed"));
+    assert_eq!(server.thread_table.get(3), None);
+    assert_eq!(server.thread_table.get(capacity + 2).as_deref(), Some("later"));
+    assert_eq!(server.thread_table.entries().len(), capacity);
+
+    let updates: Vec<_> = server.thread_table.take_updates().iter().map(|entry| entry.thread).collect();
+    assert_eq!(updates, vec![2, capacity + 1, capacity + 2]);
+}
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_payload_consumers() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let capacity = shmem::FRAME_ENTRIES;
+    let mut dst = vec![unsafe { std::mem::zeroed::<shmem::FrameData>() }; capacity]; //Plain data
+    let a = server.frame_data.register_consumer().unwrap();
+    let b = server.frame_data.register_consumer().unwrap();
+
+    fn numbers(frames: &[shmem::FrameData]) -> Vec<u64> {
+        frames.iter().map(|f| f.number).collect()
+    }
+
+    //Every consumer gets every entry, at its own pace
+    for i in 0..3 {
+        assert!(client.frame_data.push(&TestFrameData { number: i, end: i + 1, duration: 1 }));
+    }
+
+    assert_eq!(server.frame_data.retrieve_for(a, &mut dst[0..2]), (2, 0));
+    assert_eq!(numbers(&dst[0..2]), vec![0, 1]);
+    assert_eq!(server.frame_data.retrieve_for(b, &mut dst), (3, 0));
+    assert_eq!(numbers(&dst[0..3]), vec![0, 1, 2]);
+
+    //Only what `a` didn't read yet is still buffered
+    assert_eq!(server.frame_data.snapshot(&mut dst), Some(1));
+    assert_eq!(server.frame_data.retrieve_for(a, &mut dst), (1, 0));
+    assert_eq!(dst[0].number, 2);
+    assert_eq!(server.frame_data.snapshot(&mut dst), Some(0));
+
+    //A lagging consumer keeps the payload full, and each consumer counts the losses since its own last read
+    let pushed = (0..capacity as u64 + 5).filter(|&i| client.frame_data.push(&TestFrameData { number: i, end: i + 1, duration: 1 })).count();
+    assert_eq!(pushed, capacity);
+    assert_eq!(server.frame_data.retrieve_for(a, &mut dst), (capacity, 5));
+    assert!(!client.frame_data.push(&TestFrameData { number: 0, end: 1, duration: 1 }));
+    assert_eq!(server.frame_data.retrieve_for(b, &mut dst), (capacity, 6));
+    assert_eq!(server.frame_data.retrieve_for(a, &mut dst), (0, 1));
+
+    //Unregistering the laggard releases what it didn't read
+    for i in 0..3 {
+        assert!(client.frame_data.push(&TestFrameData { number: i, end: i + 1, duration: 1 }));
+    }
+
+    assert_eq!(server.frame_data.retrieve_for(a, &mut dst), (3, 0));
+    assert_eq!(server.frame_data.snapshot(&mut dst), Some(3));
+    server.frame_data.unregister_consumer(b);
+    assert_eq!(server.frame_data.snapshot(&mut dst), Some(0));
+    assert_eq!(server.frame_data.retrieve_for(b, &mut dst), (0, 0));
+
+    //Slots are reused
+    let others: Vec<_> = std::iter::from_fn(|| server.frame_data.register_consumer()).collect();
+    assert_eq!(others.len(), shmem::MAX_CONSUMERS - 1);
+    assert!(others.contains(&b));
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode"))]
+#[test]
+fn test_log_ring_wrap() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let mut client = shmem::SharedMemory::open().expect("Failed to open loopback shared memory");
+    let color = shmem::Color::from_hex(0x00FF0000);
+
+    //3 chunks each (the first one is cut before a 'é'), so that only 3 messages fit at once. As
+    //nothing is retrieved in between, the ring wraps a few times, in the middle of messages.
+    let messages: Vec<String> = (0..10).map(|i| format!("{}{}", i, "é".repeat(shmem::LOG_CHUNK_SIZE + 76))).collect();
+    let dropped = messages.len() - 3;
+
+    for (i, msg) in messages.iter().enumerate() {
+        assert!(client.push_log(i as u64, shmem::LogLevel::Info, color, msg));
+    }
+
+    assert_eq!(server.take_dropped_logs(), dropped as u64);
+
+    //Oldest messages were evicted as a whole, the others come back intact
+    let logs = server.retrieve_logs();
+    assert_eq!(logs.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>(), messages[dropped..].iter().map(String::as_str).collect::<Vec<_>>());
+    assert!(logs.iter().enumerate().all(|(i, (header, _))| { header.time } == (dropped + i) as u64 && { header.flags } == 0));
+
+    //An empty ring starts over from the beginning
+    assert!(client.push_log(100, shmem::LogLevel::Info, color, &messages[0]));
+    assert_eq!(server.retrieve_logs()[0].1, messages[0]);
+    assert_eq!(server.take_dropped_logs(), 0);
+}
+
+#[cfg(feature = "loopback")]
+#[test]
+fn test_path_override() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let default = shmem::SharedMemory::get_path();
+    let custom = std::env::temp_dir().join("temporal-lens-test").join("shmem");
+
+    //Only the loopback backend runs here, which doesn't use the path
+    shmem::SharedMemory::set_path(Some(custom.clone()));
+    assert_eq!(shmem::SharedMemory::get_path(), custom);
+    assert_eq!(shmem::SharedMemory::get_file_path(), custom.with_extension("mmap"));
+
+    shmem::SharedMemory::set_path(None);
+    assert_eq!(shmem::SharedMemory::get_path(), default);
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_plot_colors() {
+    let color = shmem::Color::from_hex;
+    let sample = |time, name, copy_name, c| {
+        let mut ret: shmem::PlotData = unsafe { std::mem::zeroed() }; //Plain data
+
+        shmem::WriteInto::write_into(&TestPlotData { time, value: 1.0, name, copy_name }, &mut ret);
+        ret.color = color(c);
+        ret
+    };
+
+    let meta = |time, name, c| {
+        let mut ret: shmem::PlotMetaData = unsafe { std::mem::zeroed() };
+
+        ret.time = time;
+        ret.color = color(c);
+        ret.name.set(name, true);
+        ret
+    };
+
+    let mut colors = crate::analysis::PlotColors::new();
+    let mut samples = vec![
+        sample(10, "a", true, 0x01),
+        sample(20, "a", false, 0x02),  //The first color wins...
+        sample(30, "b", true, 0x03),
+        sample(40, "a", false, 0x02),  //...until one is set
+        sample(50, "c", false, 0x05)   //Never named
+    ];
+
+    colors.apply(&mut samples, &[meta(35, "a", 0x04)]);
+    assert_eq!(samples.iter().map(|s| s.color.to_hex()).collect::<Vec<_>>(), vec![0x01, 0x01, 0x03, 0x04, 0x05]);
+    assert_eq!((colors.color_of("a"), colors.color_of("c")), (Some(color(0x04)), None));
+
+    //Metas after the last sample still apply, and the colors carry over to the next retrieve
+    let mut samples = vec![sample(60, "b", false, 0x03)];
+
+    colors.apply(&mut samples, &[meta(70, "b", 0x06)]);
+    assert_eq!(samples[0].color.to_hex(), 0x03);
+    assert_eq!(colors.color_of("b"), Some(color(0x06)));
+
+    let mut samples = vec![sample(80, "b", false, 0x03), sample(90, "a", false, 0x02)];
+
+    colors.apply(&mut samples, &[]);
+    assert_eq!(samples.iter().map(|s| s.color.to_hex()).collect::<Vec<_>>(), vec![0x06, 0x04]);
+}
+
+#[test]
+fn test_color_auto_reserved() {
+    //Real colors never turn into the placeholder
+    assert_ne!(shmem::Color::from_hex(shmem::Color::AUTO.to_hex()), shmem::Color::AUTO);
+    assert_ne!(shmem::Color::from_hex(0xFFFFFFFF), shmem::Color::AUTO);
+    assert_ne!(shmem::Color::from_rgb(0, 0, 0), shmem::Color::AUTO);
+}
+
+//A zone of `thread` running from `start` to `end`, for the analysis tests
+#[cfg(feature = "server-mode")]
+fn analysis_zone(thread: usize, uid: usize, start: shmem::Time, end: shmem::Time, depth: u32) -> shmem::ZoneData {
+    let mut ret: shmem::ZoneData = unsafe { std::mem::zeroed() }; //Plain data
+
+    shmem::WriteInto::write_into(&TestZoneData {
+        uid, end, depth,
+        color: shmem::Color::from_hex(0),
+        duration: end - start,
+        name: "zone",
+        copy_strings: false
+    }, &mut ret);
+
+    ret.thread = thread;
+    ret
+}
+
+//Call trees as text: "uid(children...)", with "*n" after zones that merged n recursion levels
+#[cfg(feature = "server-mode")]
+fn tree_shape(nodes: &[crate::analysis::CallNode]) -> String {
+    nodes.iter().map(|node| {
+        let mut ret = node.uid.to_string();
+
+        if node.recursion > 0 {
+            ret += &format!("*{}", node.recursion);
+        }
+
+        if !node.children.is_empty() {
+            ret += &format!("({})", tree_shape(&node.children));
+        }
+
+        ret
+    }).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_build_call_tree() {
+    //(thread, uid, start, end, depth) of each zone, and the expected tree of each thread
+    type Case = (&'static [(usize, usize, u64, u64, u32)], &'static [(usize, &'static str)]);
+
+    let cases: &[Case] = &[
+        //Plain nesting
+        (&[(1, 1, 0, 100, 0), (1, 2, 10, 50, 1), (1, 3, 20, 30, 2), (1, 4, 60, 90, 1), (1, 5, 200, 300, 0)], &[(1, "1(2(3) 4) 5")]),
+
+        //Same, in the order clients send them (by end time)
+        (&[(1, 3, 20, 30, 2), (1, 2, 10, 50, 1), (1, 4, 60, 90, 1), (1, 1, 0, 100, 0), (1, 5, 200, 300, 0)], &[(1, "1(2(3) 4) 5")]),
+
+        //A dropped parent: its children go to the next ancestor, or become roots
+        (&[(1, 1, 0, 100, 0), (1, 3, 20, 30, 2)], &[(1, "1(3)")]),
+        (&[(1, 3, 20, 30, 2), (1, 4, 40, 50, 2)], &[(1, "3 4")]),
+
+        //A deeper zone that isn't contained isn't a child
+        (&[(1, 1, 0, 100, 0), (1, 2, 50, 150, 1)], &[(1, "1 2")]),
+
+        //Threads are kept apart, ordered by ID
+        (&[(2, 1, 0, 100, 0), (1, 2, 10, 50, 1), (2, 3, 10, 50, 1)], &[(1, "2"), (2, "1(3)")]),
+
+        (&[], &[])
+    ];
+
+    //Indices point back into the input
+    fn check_indices(nodes: &[crate::analysis::CallNode], zones: &[shmem::ZoneData]) {
+        for node in nodes {
+            assert_eq!((zones[node.index].uid, zones[node.index].start), (node.uid, node.start));
+            check_indices(&node.children, zones);
+        }
+    }
+
+    for (zones, expected) in cases {
+        let zones: Vec<_> = zones.iter().map(|&(thread, uid, start, end, depth)| analysis_zone(thread, uid, start, end, depth)).collect();
+        let trees = crate::analysis::build_call_tree(&zones);
+        let shapes: Vec<_> = trees.iter().map(|tree| (tree.thread, tree_shape(&tree.roots))).collect();
+
+        assert_eq!(shapes, expected.iter().map(|&(thread, shape)| (thread, shape.to_string())).collect::<Vec<_>>(), "zones: {:?}", zones.iter().map(|z| z.uid).collect::<Vec<_>>());
+
+        for tree in &trees {
+            check_indices(&tree.roots, &zones);
+        }
+    }
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_aggregate_by_uid() {
+    //(uid, start, end, depth) of each zone, and the expected (uid, count, total, self_time, min, max, avg)
+    type Case = (&'static [(usize, u64, u64, u32)], &'static [(usize, u64, u64, u64, u64, u64, u64)]);
+
+    let cases: &[Case] = &[
+        //Self-time excludes the children, but not the gaps between them
+        (&[(1, 0, 100, 0), (2, 10, 40, 1), (2, 50, 70, 1), (1, 200, 250, 0)], &[(1, 2, 150, 100, 50, 100, 75), (2, 2, 50, 50, 20, 30, 25)]),
+
+        //Recursion: nested calls count twice in `total`, once in `self_time`
+        (&[(3, 0, 100, 0), (3, 10, 60, 1)], &[(3, 2, 150, 100, 50, 100, 75)]),
+
+        //Children running longer than their parent (broken depths) don't underflow
+        (&[(1, 0, 10, 0), (2, 0, 10, 1), (2, 0, 10, 1)], &[(1, 1, 10, 0, 10, 10, 10), (2, 2, 20, 20, 10, 10, 10)])
+    ];
+
+    for (zones, expected) in cases {
+        let zones: Vec<_> = zones.iter().map(|&(uid, start, end, depth)| analysis_zone(1, uid, start, end, depth)).collect();
+        let stats = crate::analysis::aggregate_by_uid(&zones);
+
+        assert_eq!(stats.len(), expected.len());
+
+        for &(uid, count, total, self_time, min, max, avg) in expected.iter() {
+            let s = &stats[&uid];
+            assert_eq!((s.count, s.total, s.self_time, s.min, s.max, s.avg()), (count, total, self_time, min, max, avg), "uid {}", uid);
+        }
+    }
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_frame_stats() {
+    use crate::analysis::{FrameStats, SpikeThreshold};
+
+    let frames = |durations: &[u64]| -> Vec<shmem::FrameData> {
+        durations.iter().enumerate().map(|(i, &duration)| {
+            let mut ret: shmem::FrameData = unsafe { std::mem::zeroed() }; //Plain data
+
+            shmem::WriteInto::write_into(&TestFrameData { number: i as u64, end: 1000 * (i as u64 + 1), duration }, &mut ret);
+            ret
+        }).collect()
+    };
+
+    //Frame 0 is always left out. Expected (count, p50, p95, p99, max, spikes)
+    type Expected = (usize, u64, u64, u64, u64, &'static [u64]);
+
+    let cases: Vec<(&[u64], SpikeThreshold, Expected)> = vec![
+        (&[1000, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10], SpikeThreshold::MedianFactor(1.5), (10, 5, 10, 10, 10, &[8, 9, 10])),
+        (&[1000, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10], SpikeThreshold::Absolute(9), (10, 5, 10, 10, 10, &[10])),
+        (&[1000, 10, 1, 10, 1], SpikeThreshold::Absolute(5), (4, 1, 10, 10, 10, &[1, 3])),
+        (&[0, 7], SpikeThreshold::MedianFactor(2.0), (1, 7, 7, 7, 7, &[]))
+    ];
+
+    for (durations, threshold, expected) in cases {
+        let stats = FrameStats::new(&frames(durations), threshold).expect("No frame left");
+        assert_eq!((stats.count, stats.p50, stats.p95, stats.p99, stats.max, &stats.spikes[..]), expected, "durations: {:?}", durations);
+    }
+
+    //Percentiles use the nearest rank
+    let hundred: Vec<u64> = (0..=100).collect();
+    let stats = FrameStats::new(&frames(&hundred), SpikeThreshold::Absolute(u64::MAX)).unwrap();
+    assert_eq!((stats.p50, stats.p95, stats.p99, stats.max), (50, 95, 99, 100));
+    assert!(stats.spikes.is_empty());
+
+    assert!(FrameStats::new(&frames(&[5]), SpikeThreshold::Absolute(0)).is_none());
+    assert!(FrameStats::new(&[], SpikeThreshold::Absolute(0)).is_none());
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_heap_series() {
+    const S: u64 = 1_000_000_000;
+
+    let event = |time, size, is_free| shmem::HeapData { time, addr: 0x1000, size, is_free };
+    let heap = [
+        event(0, 10, false),
+        event(S / 2, 20, false),
+        event(S + S / 5, 10, true),
+        event(S + S / 3, 5, false),
+        event(3 * S + S / 2, 1, false)  //After a window without any event
+    ];
+
+    let series = crate::analysis::heap_series(&heap, S);
+
+    assert_eq!(series.live_bytes, vec![(0, 10), (S / 2, 30), (S + S / 5, 20), (S + S / 3, 25), (3 * S + S / 2, 26)]);
+    assert_eq!(series.allocs_per_sec, vec![(S, 2.0), (2 * S, 1.0), (3 * S, 0.0), (4 * S, 1.0)]);
+    assert_eq!(series.allocated_bytes, vec![(S, 30), (2 * S, 5), (3 * S, 0), (4 * S, 1)]);
+    assert_eq!(series.freed_bytes, vec![(S, 0), (2 * S, 10), (3 * S, 0), (4 * S, 0)]);
+
+    //Freeing what was allocated before the first event goes negative
+    //One point for a long gap, not one per empty window
+    let series = crate::analysis::heap_series(&[event(0, 1, false), event(1_000_000 * S + S / 2, 1, false)], S);
+    assert_eq!(series.allocs_per_sec, vec![(S, 1.0), (1_000_000 * S, 0.0), (1_000_001 * S, 1.0)]);
+    assert_eq!(series.allocated_bytes, vec![(S, 1), (1_000_000 * S, 0), (1_000_001 * S, 1)]);
+
+    let series = crate::analysis::heap_series(&[event(0, 8, true)], S);
+    assert_eq!(series.live_bytes, vec![(0, -8)]);
+    assert_eq!(series.allocs_per_sec, vec![(S, 0.0)]);
+
+    let series = crate::analysis::heap_series(&[], S);
+    assert!(series.live_bytes.is_empty() && series.allocs_per_sec.is_empty() && series.allocated_bytes.is_empty() && series.freed_bytes.is_empty());
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_validate_depths() {
+    use crate::analysis::AnomalyKind;
+
+    //(thread, start, end, depth) of each zone, and the expected (index, parent, kind) of each anomaly
+    type Case = (&'static [(usize, u64, u64, u32)], &'static [(usize, usize, AnomalyKind)]);
+
+    let cases: &[Case] = &[
+        (&[(1, 0, 100, 0), (1, 10, 50, 1), (1, 20, 30, 2), (1, 60, 90, 1)], &[]),
+        (&[(1, 0, 100, 0), (1, 50, 150, 1)], &[(1, 0, AnomalyKind::Overlap)]),
+        (&[(1, 0, 100, 1), (1, 10, 50, 1)], &[(1, 0, AnomalyKind::NotDeeper)]),
+        (&[(1, 0, 100, 0), (1, 10, 50, 2)], &[(1, 0, AnomalyKind::DepthJump)]),
+
+        //Against the innermost zone still running, in the order clients send them
+        (&[(1, 20, 30, 3), (1, 10, 50, 1), (1, 0, 100, 0)], &[(0, 1, AnomalyKind::DepthJump)]),
+
+        //Back to back zones and zones of other threads aren't parents
+        (&[(1, 0, 50, 0), (1, 50, 60, 2)], &[]),
+        (&[(1, 0, 100, 0), (2, 10, 50, 2)], &[])
+    ];
+
+    for (zones, expected) in cases {
+        let zones: Vec<_> = zones.iter().map(|&(thread, start, end, depth)| analysis_zone(thread, 1, start, end, depth)).collect();
+        let anomalies: Vec<_> = crate::analysis::validate_depths(&zones).iter().map(|a| (a.index, a.parent, a.kind)).collect();
+
+        assert_eq!(anomalies, *expected, "zones: {:?}", zones.iter().map(|z| (z.start, z.end, z.depth)).collect::<Vec<_>>());
+    }
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_collapse_recursion() {
+    //(uid, start, end, depth) of each zone (on a single thread), and the expected trees before and after
+    type Zones = &'static [(usize, u64, u64, u32)];
+
+    let cases: &[(Zones, &str, &str)] = &[
+        //A tower of identical zones becomes one
+        (&[(1, 0, 100, 0), (1, 10, 90, 1), (1, 20, 80, 2), (2, 30, 40, 3)], "1(1(1(2)))", "1*2(2)"),
+
+        //Only direct nesting counts, and children move to the outermost zone in order
+        (&[(1, 0, 100, 0), (2, 10, 40, 1), (2, 15, 35, 2), (1, 50, 90, 1), (3, 60, 70, 2)], "1(2(2) 1(3))", "1*1(2*1 3)"),
+        (&[(1, 0, 100, 0), (2, 10, 90, 1), (1, 20, 80, 2)], "1(2(1))", "1(2(1))"),
+
+        //Levels merged is the deepest chain, not the number of zones
+        (&[(1, 0, 100, 0), (1, 10, 40, 1), (1, 50, 90, 1), (1, 60, 80, 2)], "1(1 1(1))", "1*2")
+    ];
+
+    for &(zones, before, after) in cases {
+        let zones: Vec<_> = zones.iter().map(|&(uid, start, end, depth)| analysis_zone(1, uid, start, end, depth)).collect();
+        let mut trees = crate::analysis::build_call_tree(&zones);
+
+        assert_eq!(tree_shape(&trees[0].roots), before);
+        crate::analysis::collapse_recursion(&mut trees);
+        assert_eq!(tree_shape(&trees[0].roots), after);
+
+        //The outermost zone keeps its duration
+        assert_eq!(trees[0].roots[0].duration, 100);
+    }
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_in_range() {
+    use crate::analysis::{sort_by_time, plots_in_range, zones_in_range, frames_in_range};
+
+    let plot = |time| {
+        let mut ret: shmem::PlotData = unsafe { std::mem::zeroed() }; //Plain data
+
+        shmem::WriteInto::write_into(&TestPlotData { time, value: time as f64, name: "plot", copy_name: false }, &mut ret);
+        ret
+    };
+
+    let mut plots: Vec<_> = [30, 10, 20, 40, 20].iter().map(|&t| plot(t)).collect();
+    sort_by_time(&mut plots);
+
+    //Bounds are inclusive on both ends
+    let cases: &[(u64, u64, &[u64])] = &[
+        (20, 30, &[20, 20, 30]),
+        (20, 20, &[20, 20]),
+        (15, 25, &[20, 20]),
+        (0, u64::MAX, &[10, 20, 20, 30, 40]),
+        (0, 5, &[]),
+        (41, 100, &[]),
+        (30, 20, &[])
+    ];
+
+    for &(start, end, expected) in cases {
+        let times: Vec<_> = plots_in_range(&plots, start, end).iter().map(|p| p.time).collect();
+        assert_eq!(times, expected, "[{}, {}]", start, end);
+    }
+
+    //Zones and frames by their end
+    let mut zones = vec![analysis_zone(1, 1, 0, 50, 0), analysis_zone(1, 2, 45, 70, 0), analysis_zone(1, 3, 10, 40, 1)];
+    sort_by_time(&mut zones);
+    assert_eq!(zones_in_range(&zones, 45, 60).iter().map(|z| z.uid).collect::<Vec<_>>(), vec![1]);
+    assert_eq!(zones_in_range(&zones, 40, 70).iter().map(|z| z.uid).collect::<Vec<_>>(), vec![3, 1, 2]);
+
+    let frames: Vec<_> = (0..5).map(|i| {
+        let mut ret: shmem::FrameData = unsafe { std::mem::zeroed() };
+
+        shmem::WriteInto::write_into(&TestFrameData { number: i, end: 100 * (i + 1), duration: 100 }, &mut ret);
+        ret
+    }).collect();
+
+    assert_eq!(frames_in_range(&frames, 150, 400).iter().map(|f| f.number).collect::<Vec<_>>(), vec![1, 2, 3]);
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_downsample_plot() {
+    let plot = |time, value| {
+        let mut ret: shmem::PlotData = unsafe { std::mem::zeroed() }; //Plain data
+
+        shmem::WriteInto::write_into(&TestPlotData { time, value, name: "plot", copy_name: false }, &mut ret);
+        ret
+    };
+
+    let samples = [plot(0, 1.0), plot(5, 3.0), plot(9, 2.0), plot(10, -1.0), plot(25, 4.0), plot(27, 4.0)];
+
+    //(bucket, expected (start, count, min, min_time, max, max_time, avg, last, last_time) of each bucket)
+    type Bucket = (u64, u64, f64, u64, f64, u64, f64, f64, u64);
+
+    let cases: &[(u64, &[Bucket])] = &[
+        (10, &[(0, 3, 1.0, 0, 3.0, 5, 2.0, 2.0, 9), (10, 1, -1.0, 10, -1.0, 10, -1.0, -1.0, 10), (20, 2, 4.0, 25, 4.0, 25, 4.0, 4.0, 27)]),
+        (100, &[(0, 6, -1.0, 10, 4.0, 25, 13.0 / 6.0, 4.0, 27)]),
+
+        //0 is taken as 1: one bucket per distinct time
+        (0, &[(0, 1, 1.0, 0, 1.0, 0, 1.0, 1.0, 0), (5, 1, 3.0, 5, 3.0, 5, 3.0, 3.0, 5), (9, 1, 2.0, 9, 2.0, 9, 2.0, 2.0, 9),
+              (10, 1, -1.0, 10, -1.0, 10, -1.0, -1.0, 10), (25, 1, 4.0, 25, 4.0, 25, 4.0, 4.0, 25), (27, 1, 4.0, 27, 4.0, 27, 4.0, 4.0, 27)])
+    ];
+
+    for &(bucket, expected) in cases {
+        let buckets: Vec<_> = crate::analysis::downsample_plot(&samples, bucket).iter()
+            .map(|b| (b.start, b.count, b.min, b.min_time, b.max, b.max_time, b.avg, b.last, b.last_time))
+            .collect();
+
+        assert_eq!(buckets, expected, "bucket {}", bucket);
+    }
+
+    assert!(crate::analysis::downsample_plot(&[], 10).is_empty());
+}
+
+#[cfg(feature = "server-mode")]
+#[test]
+fn test_name_cache() {
+    let string = |key, contents: Option<&str>| {
+        let mut ret: shmem::SharedString = unsafe { std::mem::zeroed() }; //Plain data
+
+        match contents {
+            Some(x) => ret.set_copy(key, x),
+            None    => ret.set_special(key, None)
+        }
+
+        ret
+    };
+
+    let mut names = crate::analysis::NameCache::new();
+
+    //Unknown until the contents arrive, then known by key
+    assert_eq!(names.resolve(&string(1, None)), None);
+    assert_eq!(names.resolve(&string(1, Some("first"))), Some("first"));
+    assert_eq!(names.resolve(&string(1, None)), Some("first"));
+    assert_eq!(names.name_of(&string(2, None)), None);
+
+    //The same contents again are fine, new ones replace the old ones and are reported
+    assert!(!names.update(&string(1, Some("first"))));
+    assert!(names.update(&string(1, Some("renamed"))));
+    assert_eq!(names.name_of(&string(1, None)), Some("renamed"));
+
+    //A string's own contents win over the cache, without `update()`
+    assert_eq!(names.name_of(&string(1, Some("own"))), Some("own"));
+    assert_eq!(names.name_of(&string(1, None)), Some("renamed"));
+    assert!(!names.update(&string(3, None)));
+}
+
+#[cfg(all(feature = "loopback", feature = "server-mode", feature = "enabled"))]
+#[test]
+fn test_context_log_levels() {
+    let _guard = LOOPBACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut server = shmem::SharedMemory::create().expect("Failed to create loopback shared memory");
+    let context = crate::Context::open().expect("Failed to open loopback shared memory");
+
+    assert!(context.send_log_kv("plain", &[("k", "v")], shmem::Color::AUTO));
+    assert!(context.send_log_kv_level("warned", &[("k", "v")], shmem::LogLevel::Warn, shmem::Color::AUTO));
+
+    let logs = server.retrieve_logs();
+    assert_eq!(logs.iter().map(|(header, _)| header.level()).collect::<Vec<_>>(), vec![shmem::LogLevel::Info, shmem::LogLevel::Warn]);
+    assert_eq!({ logs[1].0.color }, shmem::LogLevel::Warn.default_color());
+    assert_eq!(shmem::split_log_fields(&logs[1].1), ("warned", vec![("k", "v")]));
+}
+
+#[cfg(feature = "metrics-bridge")]
+#[test]
+fn test_metrics_series_name() {
+    use metrics::{Key, Label};
+
+    let labels = vec![Label::new("method", "GET"), Label::new("code", "200")];
+
+    assert_eq!(crate::metrics_bridge::series_name(&Key::from_name("requests")), "requests");
+    assert_eq!(crate::metrics_bridge::series_name(&Key::from_name_and_labels("requests", labels)), "requests{method=GET,code=200}");
+}
@@ -0,0 +1,417 @@
+///Recording of capture sessions to disk, and replay. A trace file starts with
+///`TRACE_MAGIC` followed by a `TraceHeader`, then by as many chunks as the server
+///recorded. Each chunk holds everything retrieved by one `TraceRecorder::record()`
+///call and is prefixed with its size in bytes (little-endian u32). Both the header
+///and the chunks are encoded with bincode, except for the zones of each chunk, which
+///are a `zone_codec` block. With the `compression` feature, chunks can also be
+///compressed individually; the codec is then stored in the header.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::shmem::{self, SharedMemoryData, Payload, Time};
+use crate::shmem::{FrameData, ZoneData, HeapData, PlotData, PlotMetaData, ZoneTextData, ZoneHistogramData, HeapBacktraceData, LogEntryHeader, ThreadNameData};
+
+pub const TRACE_MAGIC: [u8; 8] = *b"TLTRACE\0";
+pub const TRACE_VERSION: u32 = 14;
+
+///Largest chunk a trace may contain, both as stored and once decompressed. One `record()` is
+///bounded by the size of the shared memory, so anything bigger is a corrupted file or stream.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    BadMagic,
+    VersionMismatch(u32),
+    ProtocolMismatch(u32),
+    ChunkTooLarge(usize), //Above `MAX_CHUNK_SIZE`
+    UnsupportedCodec(Codec) //The `compression` feature is required for this codec
+}
+
+impl From<io::Error> for TraceError {
+    fn from(err: io::Error) -> Self {
+        TraceError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for TraceError {
+    fn from(err: bincode::Error) -> Self {
+        TraceError::Encoding(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TraceHeader {
+    pub version: u32,          //Version of the trace format, `TRACE_VERSION`
+    pub protocol_version: u32, //`shmem::PROTOCOL_VERSION` of the server that recorded the trace
+    pub start: u64,            //When the recording started (seconds since UNIX epoch)
+    pub codec: Codec           //How chunks are compressed
+}
+
+impl Codec {
+    ///Returns false if the `compression` feature is required for this codec
+    #[inline]
+    pub fn is_supported(self) -> bool {
+        self == Codec::None || cfg!(feature = "compression")
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, TraceError> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Lz4  => Ok(lz4::block::compress(data, None, true)?),
+        Codec::Zstd => Ok(zstd::encode_all(data, 0)?)
+    }
+}
+
+//Never produces more than `MAX_CHUNK_SIZE` bytes, whatever the input claims: chunks may come
+//from the network (see `remote`), and a few bytes of zstd can expand to gigabytes
+#[cfg(feature = "compression")]
+fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, TraceError> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+
+        //The output is allocated from the size prefix, which must be checked first
+        Codec::Lz4 => {
+            let size = match data.get(0..4) {
+                Some(x) => u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as usize,
+                None    => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing lz4 size prefix").into())
+            };
+
+            if size > MAX_CHUNK_SIZE {
+                return Err(TraceError::ChunkTooLarge(size));
+            }
+
+            Ok(lz4::block::decompress(data, None)?)
+        },
+
+        //Frames may not tell their size, so stop reading one byte past the limit instead
+        Codec::Zstd => {
+            let mut ret = Vec::new();
+
+            zstd::stream::read::Decoder::with_buffer(data)?.take(MAX_CHUNK_SIZE as u64 + 1).read_to_end(&mut ret)?;
+
+            if ret.len() > MAX_CHUNK_SIZE {
+                return Err(TraceError::ChunkTooLarge(ret.len()));
+            }
+
+            Ok(ret)
+        }
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(codec: Codec, _data: &[u8]) -> Result<Vec<u8>, TraceError> {
+    Err(TraceError::UnsupportedCodec(codec))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(codec: Codec, _data: &[u8]) -> Result<Vec<u8>, TraceError> {
+    Err(TraceError::UnsupportedCodec(codec))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TraceChunk {
+    pub time: u64,                              //When the chunk was recorded (seconds since UNIX epoch)
+    pub lost: u64,                              //How many entries the client couldn't push since the previous chunk
+    pub frames: Vec<FrameData>,
+    #[serde(with = "crate::zone_codec::serde_zones")]
+    pub zones: Vec<ZoneData>,
+    pub heap: Vec<HeapData>,
+    pub plots: Vec<PlotData>,
+    pub plot_metas: Vec<PlotMetaData>,          //Plot colors set since the previous chunk, see `analysis::PlotColors`
+    pub zone_texts: Vec<ZoneTextData>,
+    pub zone_histograms: Vec<ZoneHistogramData>,
+    pub heap_backtraces: Vec<HeapBacktraceData>,
+    pub logs: Vec<(LogEntryHeader, String)>,
+    pub threads: Vec<ThreadNameData>            //Threads named (or renamed) since the previous chunk
+}
+
+pub enum TraceEvent<'a> {
+    Frame(&'a FrameData),
+    Zone(&'a ZoneData),
+    Heap(&'a HeapData),
+    Plot(&'a PlotData),
+    ZoneHistogram(&'a ZoneHistogramData),
+    Log(&'a LogEntryHeader, &'a str)
+}
+
+impl TraceEvent<'_> {
+    pub fn time(&self) -> Time {
+        match self {
+            TraceEvent::Frame(x)         => x.end,
+            TraceEvent::Zone(x)          => x.end,
+            TraceEvent::Heap(x)          => x.time,
+            TraceEvent::Plot(x)          => x.time,
+            TraceEvent::ZoneHistogram(x) => x.end,
+            TraceEvent::Log(x, _)        => x.time
+        }
+    }
+}
+
+impl TraceChunk {
+    ///Returns the entries of all payloads, ordered by time (zones and frames by their end).
+    ///Entries with the same time keep the order in which they were retrieved. Zone texts
+    ///are not part of the timeline; match them with their zone using `zone_texts`.
+    pub fn events(&self) -> Vec<TraceEvent<'_>> {
+        let mut ret: Vec<TraceEvent> = merged_events(self).collect();
+
+        ret.sort_by_key(|e| e.time()); //Stable
+        ret
+    }
+}
+
+const NUM_SOURCES: usize = 6;
+
+pub struct MergedEvents<'a> {
+    chunk: &'a TraceChunk,
+    next: [usize; NUM_SOURCES] //Index of the next entry of each payload
+}
+
+impl<'a> MergedEvents<'a> {
+    fn get(&self, source: usize) -> Option<TraceEvent<'a>> {
+        let i = self.next[source];
+        let c = self.chunk;
+
+        match source {
+            0 => c.frames.get(i).map(TraceEvent::Frame),
+            1 => c.zones.get(i).map(TraceEvent::Zone),
+            2 => c.heap.get(i).map(TraceEvent::Heap),
+            3 => c.plots.get(i).map(TraceEvent::Plot),
+            4 => c.zone_histograms.get(i).map(TraceEvent::ZoneHistogram),
+            _ => c.logs.get(i).map(|(header, text)| TraceEvent::Log(header, text.as_str()))
+        }
+    }
+}
+
+impl<'a> Iterator for MergedEvents<'a> {
+    type Item = TraceEvent<'a>;
+
+    fn next(&mut self) -> Option<TraceEvent<'a>> {
+        let mut best: Option<(usize, TraceEvent<'a>)> = None;
+
+        for source in 0..NUM_SOURCES {
+            if let Some(event) = self.get(source) {
+                if best.as_ref().map(|(_, b)| event.time() < b.time()).unwrap_or(true) {
+                    best = Some((source, event));
+                }
+            }
+        }
+
+        best.map(|(source, event)| {
+            self.next[source] += 1;
+            event
+        })
+    }
+}
+
+///K-way merge of the payloads of `chunk` into a single stream ordered by time, which is cheap
+///since each payload is already roughly ordered. The output is exactly ordered only if each
+///payload is; zones of different threads might not be (they are batched per thread), in which
+///case `TraceChunk::events()` sorts everything instead. Zone texts, plot metas and heap
+///backtraces are not part of the stream.
+pub fn merged_events(chunk: &TraceChunk) -> MergedEvents<'_> {
+    MergedEvents {
+        chunk,
+        next: [0; NUM_SOURCES]
+    }
+}
+
+fn drain<T: Copy, const N: usize>(payload: &mut Payload<T, N>, lost: &mut u64) -> Vec<T> {
+    let mut ret = Vec::with_capacity(N);
+
+    unsafe {
+        let (retrieved, payload_lost) = payload.retrieve_unchecked(ret.as_mut_ptr());
+        ret.set_len(retrieved);
+
+        *lost += payload_lost as u64;
+    }
+
+    ret
+}
+
+pub struct TraceRecorder<W: Write = BufWriter<File>> {
+    output: W,
+    codec: Codec,
+    buffer: Vec<u8>
+}
+
+impl TraceRecorder {
+    ///Creates (or truncates) the trace file at `path` and writes its header
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<TraceRecorder, TraceError> {
+        TraceRecorder::new(BufWriter::new(File::create(path)?))
+    }
+
+    ///Same as `create()`, but compresses chunks with `codec`
+    pub fn create_with_codec<P: AsRef<Path>>(path: P, codec: Codec) -> Result<TraceRecorder, TraceError> {
+        TraceRecorder::with_codec(BufWriter::new(File::create(path)?), codec)
+    }
+}
+
+impl<W: Write> TraceRecorder<W> {
+    ///Writes the header to `output`, which doesn't have to be a file
+    pub fn new(output: W) -> Result<TraceRecorder<W>, TraceError> {
+        TraceRecorder::with_codec(output, Codec::None)
+    }
+
+    ///Same as `new()`, but compresses chunks with `codec`
+    pub fn with_codec(mut output: W, codec: Codec) -> Result<TraceRecorder<W>, TraceError> {
+        if !codec.is_supported() {
+            return Err(TraceError::UnsupportedCodec(codec));
+        }
+
+        let header = TraceHeader {
+            version: TRACE_VERSION,
+            protocol_version: shmem::PROTOCOL_VERSION,
+            start: shmem::unix_time(),
+            codec
+        };
+
+        output.write_all(&TRACE_MAGIC)?;
+        bincode::serialize_into(&mut output, &header)?;
+
+        Ok(TraceRecorder {
+            output, codec,
+            buffer: Vec::new()
+        })
+    }
+
+    ///Retrieves all the payloads of `mem` and appends them to the trace. The chunk is
+    ///returned so that the server can also use the data live.
+    pub fn record(&mut self, mem: &mut SharedMemoryData) -> Result<TraceChunk, TraceError> {
+        let mut chunk = TraceChunk {
+            time: shmem::unix_time(),
+            ..Default::default()
+        };
+
+        chunk.threads = mem.thread_table.take_updates(); //First, as clients name threads before sending their zones
+        chunk.frames = drain(&mut mem.frame_data, &mut chunk.lost);
+        chunk.zones = drain(&mut mem.zone_data, &mut chunk.lost);
+        chunk.heap = drain(&mut mem.heap_data, &mut chunk.lost);
+        chunk.plots = drain(&mut mem.plot_data, &mut chunk.lost);
+        chunk.plot_metas = drain(&mut mem.plot_meta_data, &mut chunk.lost);
+        chunk.zone_texts = drain(&mut mem.zone_text_data, &mut chunk.lost);
+        chunk.zone_histograms = drain(&mut mem.zone_histogram_data, &mut chunk.lost);
+        chunk.heap_backtraces = drain(&mut mem.heap_backtrace_data, &mut chunk.lost);
+        chunk.logs = mem.retrieve_logs();
+
+        self.write_chunk(&chunk)?;
+        Ok(chunk)
+    }
+
+    ///Appends a chunk the server built itself
+    pub fn write_chunk(&mut self, chunk: &TraceChunk) -> Result<(), TraceError> {
+        self.buffer.clear();
+        bincode::serialize_into(&mut self.buffer, chunk)?;
+
+        if self.codec != Codec::None {
+            self.buffer = compress(self.codec, &self.buffer)?;
+        }
+
+        if self.buffer.len() > MAX_CHUNK_SIZE {
+            return Err(TraceError::ChunkTooLarge(self.buffer.len()));
+        }
+
+        self.output.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.output.write_all(&self.buffer)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), TraceError> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+pub struct TraceReader<R: Read = BufReader<File>> {
+    input: R,
+    header: TraceHeader,
+    buffer: Vec<u8>
+}
+
+impl TraceReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TraceReader, TraceError> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    ///Reads and checks the header from `input`, which doesn't have to be a file
+    pub fn new(mut input: R) -> Result<TraceReader<R>, TraceError> {
+        let mut magic = [0; 8];
+
+        input.read_exact(&mut magic)?;
+
+        if magic != TRACE_MAGIC {
+            return Err(TraceError::BadMagic);
+        }
+
+        let header: TraceHeader = bincode::deserialize_from(&mut input)?;
+
+        if header.version != TRACE_VERSION {
+            return Err(TraceError::VersionMismatch(header.version));
+        }
+
+        //Chunks hold raw shared memory entries, which only decode with the same layout
+        if header.protocol_version != shmem::PROTOCOL_VERSION {
+            return Err(TraceError::ProtocolMismatch(header.protocol_version));
+        }
+
+        Ok(TraceReader {
+            input, header,
+            buffer: Vec::new()
+        })
+    }
+
+    #[inline]
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    ///Reads the next chunk, in the order they were recorded. Returns `None` at the end of
+    ///the file. A chunk cut in the middle (e.g. the server crashed) is reported as an error.
+    pub fn next_chunk(&mut self) -> Result<Option<TraceChunk>, TraceError> {
+        let mut size = [0; 4];
+
+        if self.input.read(&mut size[0..1])? == 0 {
+            return Ok(None);
+        }
+
+        self.input.read_exact(&mut size[1..])?;
+        let size = u32::from_le_bytes(size) as usize;
+
+        if size > MAX_CHUNK_SIZE {
+            return Err(TraceError::ChunkTooLarge(size));
+        }
+
+        self.buffer.resize(size, 0);
+        self.input.read_exact(&mut self.buffer)?;
+
+        if self.header.codec != Codec::None {
+            self.buffer = decompress(self.header.codec, &self.buffer)?;
+        }
+
+        Ok(Some(bincode::deserialize(&self.buffer)?))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceChunk, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
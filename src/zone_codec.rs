@@ -0,0 +1,228 @@
+///Compact encoding of zones for traces and forwarding, where `ZoneData` (and its three
+///`SharedString`s) would otherwise be written as is. Each call to `encode_zones()` produces a
+///self-contained block, so losing one doesn't prevent decoding the others. In a block:
+/// - integers are LEB128 varints, and signed ones are zigzag encoded first
+/// - `end` is stored as a delta from the `end` of the previous zone of the block
+/// - `start` is stored as its difference with `end - duration`, which is usually 0
+/// - `(uid, color)` pairs and string keys go through dictionaries, so that each distinct one
+///   is written once per block and then referred to by its index
+/// - string contents are only written when there are some (i.e. the first time they're sent)
+///
+///This is only the on-wire/on-disk form; the shared memory keeps using `ZoneData`.
+
+use std::collections::HashMap;
+
+use crate::shmem::{Color, SharedString, ZoneData, SHARED_STRING_MAX_SIZE};
+
+///Smallest encoding of a zone: one byte for each of its 8 integers and for the dictionary
+///index and the length of its 3 strings, which is what a zone known to the dictionaries takes
+pub const MIN_ENCODED_ZONE_SIZE: usize = 14;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated,       //The block ends in the middle of a zone
+    BadIndex(u64),   //A dictionary index that doesn't refer to a previous entry
+    StringTooLong    //String contents longer than `SHARED_STRING_MAX_SIZE`
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+
+    out.push(x as u8);
+}
+
+#[inline]
+fn write_signed(out: &mut Vec<u8>, x: i64) {
+    write_varint(out, ((x << 1) ^ (x >> 63)) as u64);
+}
+
+//Index of `value` in the dictionary, which is written instead. New values get the next index,
+//which the decoder recognizes, and are written right after it.
+fn write_dict<T: Copy + Eq + std::hash::Hash, F: FnOnce(&mut Vec<u8>, T)>(out: &mut Vec<u8>, dict: &mut HashMap<T, u64>, value: T, f: F) {
+    match dict.get(&value) {
+        Some(&i) => write_varint(out, i),
+        None     => {
+            let i = dict.len() as u64;
+
+            dict.insert(value, i);
+            write_varint(out, i);
+            f(out, value);
+        }
+    }
+}
+
+//Key through the dictionary, then the length of the contents plus one (0 if none) and the contents
+fn write_string(out: &mut Vec<u8>, dict: &mut HashMap<usize, u64>, string: &SharedString) {
+    write_dict(out, dict, string.get_key(), |out, key| write_varint(out, key as u64));
+
+    match string.make_str() {
+        Some(x) => {
+            write_varint(out, x.len() as u64 + 1);
+            out.extend_from_slice(x.as_bytes());
+        },
+
+        None => out.push(0)
+    }
+}
+
+///Encodes `zones` into a block, see the module documentation
+pub fn encode_zones(zones: &[ZoneData]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(16 * zones.len() + 8);
+    let mut zone_dict: HashMap<(usize, u32), u64> = HashMap::new();
+    let mut string_dict: HashMap<usize, u64> = HashMap::new();
+    let mut prev_end = 0;
+
+    write_varint(&mut ret, zones.len() as u64);
+
+    for z in zones {
+        write_dict(&mut ret, &mut zone_dict, (z.uid, z.color.to_hex()), |out, (uid, color)| {
+            write_varint(out, uid as u64);
+            write_varint(out, color as u64);
+        });
+
+        write_signed(&mut ret, z.end.wrapping_sub(prev_end) as i64);
+        write_varint(&mut ret, z.duration);
+        write_signed(&mut ret, z.end.wrapping_sub(z.duration).wrapping_sub(z.start) as i64);
+        write_varint(&mut ret, z.cpu_duration);
+        write_varint(&mut ret, z.depth as u64);
+        write_varint(&mut ret, z.thread as u64);
+        write_varint(&mut ret, z.instance);
+        write_string(&mut ret, &mut string_dict, &z.name);
+        write_string(&mut ret, &mut string_dict, &z.loc);
+        write_string(&mut ret, &mut string_dict, &z.category);
+
+        prev_end = z.end;
+    }
+
+    ret
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let ret = *self.data.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+
+        Ok(ret)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut ret = 0;
+        let mut shift = 0;
+
+        loop {
+            let b = self.byte()?;
+
+            //Bits past 64 can only come from corrupted data; drop them rather than overflow
+            if shift < 64 {
+                ret |= ((b & 0x7F) as u64) << shift;
+            }
+
+            if b & 0x80 == 0 {
+                return Ok(ret);
+            }
+
+            shift += 7;
+        }
+    }
+
+    #[inline]
+    fn signed(&mut self) -> Result<i64, DecodeError> {
+        let x = self.varint()?;
+        Ok(((x >> 1) as i64) ^ -((x & 1) as i64))
+    }
+
+    fn dict<T: Copy, F: FnOnce(&mut Self) -> Result<T, DecodeError>>(&mut self, dict: &mut Vec<T>, f: F) -> Result<T, DecodeError> {
+        let i = self.varint()?;
+
+        if i == dict.len() as u64 {
+            let value = f(self)?;
+
+            dict.push(value);
+            Ok(value)
+        } else {
+            dict.get(i as usize).copied().ok_or(DecodeError::BadIndex(i))
+        }
+    }
+
+    fn string(&mut self, dict: &mut Vec<usize>, dst: &mut SharedString) -> Result<(), DecodeError> {
+        let key = self.dict(dict, |r| r.varint().map(|x| x as usize))?;
+        let len = self.varint()?;
+
+        if len == 0 {
+            dst.set_special(key, None);
+            return Ok(());
+        }
+
+        let len = (len - 1) as usize;
+
+        if len > SHARED_STRING_MAX_SIZE {
+            return Err(DecodeError::StringTooLong);
+        }
+
+        let raw = self.data.get(self.pos..self.pos + len).ok_or(DecodeError::Truncated)?;
+        self.pos += len;
+
+        dst.set_copy(key, &String::from_utf8_lossy(raw));
+        Ok(())
+    }
+}
+
+///Decodes a block produced by `encode_zones()`
+pub fn decode_zones(data: &[u8]) -> Result<Vec<ZoneData>, DecodeError> {
+    let mut r = Reader { data, pos: 0 };
+    let count = r.varint()?;
+
+    //Don't trust `count` more than the data
+    let mut ret = Vec::with_capacity((count as usize).min(data.len() / MIN_ENCODED_ZONE_SIZE));
+    let mut zone_dict: Vec<(usize, u32)> = Vec::new();
+    let mut string_dict: Vec<usize> = Vec::new();
+    let mut prev_end: u64 = 0;
+
+    for _ in 0..count {
+        //Plain data, so all zeroes is a valid starting point
+        let mut z: ZoneData = unsafe { std::mem::zeroed() };
+
+        let (uid, color) = r.dict(&mut zone_dict, |r| Ok((r.varint()? as usize, r.varint()? as u32)))?;
+        z.uid = uid;
+        z.color = Color::from_hex(color);
+
+        z.end = prev_end.wrapping_add(r.signed()? as u64);
+        z.duration = r.varint()?;
+        z.start = z.end.wrapping_sub(z.duration).wrapping_sub(r.signed()? as u64);
+        z.cpu_duration = r.varint()?;
+        z.depth = r.varint()? as u32;
+        z.thread = r.varint()? as usize;
+        z.instance = r.varint()?;
+        r.string(&mut string_dict, &mut z.name)?;
+        r.string(&mut string_dict, &mut z.loc)?;
+        r.string(&mut string_dict, &mut z.category)?;
+
+        prev_end = z.end;
+        ret.push(z);
+    }
+
+    Ok(ret)
+}
+
+//For `#[serde(with = "zone_codec::serde_zones")]`: zones are serialized as an encoded block
+pub(crate) mod serde_zones {
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    use crate::shmem::ZoneData;
+
+    pub fn serialize<S: Serializer>(zones: &[ZoneData], serializer: S) -> Result<S::Ok, S::Error> {
+        super::encode_zones(zones).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ZoneData>, D::Error> {
+        let data: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        super::decode_zones(&data).map_err(|err| serde::de::Error::custom(format!("bad zone block: {:?}", err)))
+    }
+}